@@ -0,0 +1,286 @@
+//! Threshold alerts driving an LED and a buzzer
+//!
+//! Each quantity has a threshold with hysteresis, an alert becomes active
+//! when the value reaches the limit, and clears when it drops below the
+//! limit less the hysteresis. After an alert triggers, the same alert won't
+//! sound the buzzer again until `alert_holdoff_sec` has passed. The LED is
+//! on while any alert is active, the buzzer beeps from when an alert
+//! triggers until the button is pressed, or the alerts clear.
+//!
+//! The alert task is the only one evaluating the alerts, the display shows
+//! the status it publishes for each measurement, so the screen always
+//! agrees with the LED and buzzer.
+
+use crate::{
+    measurement::{Measurement, MeasurementSubscriber},
+    parameter::Parameters,
+    rtc,
+    sensor::Quantity,
+};
+use defmt::{debug, info, Format};
+use embassy_futures::select::{select3, Either3};
+use embassy_stm32::{
+    exti::ExtiInput,
+    gpio::{AnyPin, Output},
+    peripherals,
+    timer::{simple_pwm::SimplePwm, Channel},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+/// The quantities that can raise an alert
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum AlertKind {
+    Pm2_5,
+    Pm10,
+    Iaq,
+    Humidity,
+    Temperature,
+}
+
+impl AlertKind {
+    pub const ALL: [AlertKind; 5] = [
+        AlertKind::Pm2_5,
+        AlertKind::Pm10,
+        AlertKind::Iaq,
+        AlertKind::Humidity,
+        AlertKind::Temperature,
+    ];
+
+    /// short name for the display
+    pub fn label(&self) -> &'static str {
+        match self {
+            AlertKind::Pm2_5 => "PM2.5",
+            AlertKind::Pm10 => "PM10",
+            AlertKind::Iaq => "IAQ",
+            AlertKind::Humidity => "RH",
+            AlertKind::Temperature => "TEMP",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+/// Limit for a quantity, the alert is active at or above `limit`, and
+/// clears below `limit - hysteresis`
+#[derive(Debug, Clone, Copy, Format)]
+pub struct Threshold {
+    pub limit: f32,
+    pub hysteresis: f32,
+}
+
+/// A set of alerts
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Format)]
+pub struct AlertSet(u8);
+
+impl AlertSet {
+    pub fn contains(&self, kind: AlertKind) -> bool {
+        self.0 & kind.bit() != 0
+    }
+
+    pub fn insert(&mut self, kind: AlertKind) {
+        self.0 |= kind.bit();
+    }
+
+    pub fn remove(&mut self, kind: AlertKind) {
+        self.0 &= !kind.bit();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// the alerts in the set
+    pub fn iter(&self) -> impl Iterator<Item = AlertKind> + '_ {
+        AlertKind::ALL.into_iter().filter(|k| self.contains(*k))
+    }
+}
+
+/// Result of evaluating the alerts for a cycle
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct AlertStatus {
+    /// alerts that are active
+    pub active: AlertSet,
+    /// alerts that became active this cycle, and should sound the buzzer
+    pub triggered: AlertSet,
+}
+
+/// Readings the alerts are evaluated against, None if not available
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct AlertInputs {
+    pub pm2_5: Option<f32>,
+    pub pm10: Option<f32>,
+    pub iaq: Option<f32>,
+    pub humidity: Option<f32>,
+    pub temperature: Option<f32>,
+}
+
+impl AlertInputs {
+    /// the readings from a measurement
+    pub fn from_measurement(m: &Measurement) -> Self {
+        AlertInputs {
+            pm2_5: m.readings.get(Quantity::Pm2_5),
+            pm10: m.readings.get(Quantity::Pm10),
+            iaq: m.readings.get(Quantity::Iaq),
+            humidity: m.readings.get(Quantity::Humidity),
+            temperature: m.readings.get(Quantity::Temperature),
+        }
+    }
+
+    fn value(&self, kind: AlertKind) -> Option<f32> {
+        match kind {
+            AlertKind::Pm2_5 => self.pm2_5,
+            AlertKind::Pm10 => self.pm10,
+            AlertKind::Iaq => self.iaq,
+            AlertKind::Humidity => self.humidity,
+            AlertKind::Temperature => self.temperature,
+        }
+    }
+}
+
+fn threshold(params: &Parameters, kind: AlertKind) -> Threshold {
+    match kind {
+        AlertKind::Pm2_5 => params.alert_pm25,
+        AlertKind::Pm10 => params.alert_pm10,
+        AlertKind::Iaq => params.alert_iaq,
+        AlertKind::Humidity => params.alert_humidity,
+        AlertKind::Temperature => params.alert_temperature,
+    }
+}
+
+/// Tracks alert state between cycles
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct AlertMonitor {
+    active: AlertSet,
+    /// RTC time each alert last sounded, in seconds
+    last_triggered: [Option<u64>; 5],
+}
+
+impl AlertMonitor {
+    /// evaluate the readings at time `now_sec`
+    ///
+    /// A missing reading leaves its alert unchanged.
+    pub fn evaluate(
+        &mut self,
+        params: &Parameters,
+        inputs: &AlertInputs,
+        now_sec: u64,
+    ) -> AlertStatus {
+        let mut status = AlertStatus::default();
+        if !params.alerts_enabled {
+            self.active = AlertSet::default();
+            return status;
+        }
+        for kind in AlertKind::ALL {
+            let Some(value) = inputs.value(kind) else {
+                continue;
+            };
+            let t = threshold(params, kind);
+            if self.active.contains(kind) {
+                if value < t.limit - t.hysteresis {
+                    debug!("alert {} cleared", kind);
+                    self.active.remove(kind);
+                }
+            } else if value >= t.limit {
+                info!("alert {}: {}", kind, value);
+                self.active.insert(kind);
+                let last = &mut self.last_triggered[kind as usize];
+                let held_off = last.map_or(false, |t| {
+                    now_sec.saturating_sub(t) < params.alert_holdoff_sec.into()
+                });
+                if !held_off {
+                    status.triggered.insert(kind);
+                    *last = Some(now_sec);
+                }
+            }
+        }
+        status.active = self.active;
+        status
+    }
+}
+
+/// The status of the latest measurement, with its uptime
+static ALERT_STATUS: Signal<CriticalSectionRawMutex, (u64, AlertStatus)> = Signal::new();
+
+/// wait for the alert task to evaluate a measurement, and return the status
+pub async fn status(m: &Measurement) -> AlertStatus {
+    loop {
+        let (uptime_ms, status) = ALERT_STATUS.wait().await;
+        if uptime_ms == m.uptime_ms {
+            return status;
+        }
+    }
+}
+
+/// time the buzzer is on, and off, while beeping
+const BEEP_PERIOD: Duration = Duration::from_millis(500);
+
+/// task to evaluate the alerts for each measurement, and drive the alert
+/// LED and buzzer
+#[embassy_executor::task]
+pub async fn alert_controller(
+    mut led: Output<'static, AnyPin>,
+    mut buzzer: SimplePwm<'static, peripherals::TIM3>,
+    mut button: ExtiInput<'static, peripherals::PC13>,
+    mut measurements: MeasurementSubscriber,
+    params: Parameters,
+) {
+    let mut monitor = AlertMonitor::default();
+    let max_duty = buzzer.get_max_duty();
+    buzzer.set_duty(Channel::Ch1, max_duty / 2);
+    let mut active = AlertSet::default();
+    let mut sounding = false;
+    let mut buzzer_on = false;
+    loop {
+        let beep = async {
+            if sounding {
+                Timer::after(BEEP_PERIOD).await
+            } else {
+                core::future::pending().await
+            }
+        };
+        match select3(
+            measurements.next_message_pure(),
+            button.wait_for_falling_edge(),
+            beep,
+        )
+        .await
+        {
+            Either3::First(m) => {
+                // uptime stops in STOP mode, the RTC doesn't
+                let status =
+                    monitor.evaluate(&params, &AlertInputs::from_measurement(&m), rtc::seconds());
+                ALERT_STATUS.signal((m.uptime_ms, status));
+                active = status.active;
+                if !status.triggered.is_empty() {
+                    sounding = true;
+                }
+            }
+            Either3::Second(_) => {
+                if sounding {
+                    info!("alert silenced");
+                }
+                sounding = false;
+            }
+            Either3::Third(_) => {
+                buzzer_on = !buzzer_on;
+            }
+        }
+        if active.is_empty() {
+            sounding = false;
+            led.set_low();
+        } else {
+            led.set_high();
+        }
+        if sounding && buzzer_on {
+            buzzer.enable(Channel::Ch1);
+        } else {
+            buzzer.disable(Channel::Ch1);
+        }
+        if !sounding {
+            buzzer_on = false;
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Battery voltage monitoring
+//!
+//! The battery is measured through a resistor divider on PA0. VREFINT is
+//! sampled at the same time to find the actual analog supply voltage, using
+//! the factory calibration value.
+
+use crate::parameter::Parameters;
+use core::cell::Cell;
+use defmt::{debug, info, warn, Format};
+use embassy_stm32::{adc, peripherals};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+
+/// VREFINT reading at 3.3V taken during production
+const VREFINT_CAL: *const u16 = 0x1FFF_F7BA as *const u16;
+
+/// Supply voltage the calibration value was taken at
+const VREFINT_CAL_MV: u32 = 3300;
+
+/// Battery chemistry, for estimating state of charge from voltage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Chemistry {
+    /// single Li-ion or LiPo cell
+    LiIon,
+    /// alkaline AA cells in series
+    Alkaline { cells: u8 },
+    /// NiMH AA cells in series
+    NiMh { cells: u8 },
+}
+
+/// discharge curves, cell millivolts against percent charge, highest first
+const LI_ION_CURVE: [(u16, u8); 6] = [
+    (4200, 100),
+    (4000, 85),
+    (3800, 60),
+    (3700, 40),
+    (3600, 15),
+    (3300, 0),
+];
+const ALKALINE_CURVE: [(u16, u8); 6] = [
+    (1550, 100),
+    (1400, 80),
+    (1300, 60),
+    (1200, 35),
+    (1100, 15),
+    (900, 0),
+];
+const NIMH_CURVE: [(u16, u8); 5] = [(1400, 100), (1300, 80), (1220, 50), (1150, 15), (1000, 0)];
+
+impl Chemistry {
+    /// estimate percent charge from the battery voltage
+    pub fn state_of_charge(&self, millivolts: u16) -> u8 {
+        let (curve, cells): (&[(u16, u8)], u8) = match *self {
+            Chemistry::LiIon => (&LI_ION_CURVE, 1),
+            Chemistry::Alkaline { cells } => (&ALKALINE_CURVE, cells),
+            Chemistry::NiMh { cells } => (&NIMH_CURVE, cells),
+        };
+        let cell_mv = millivolts / cells.max(1) as u16;
+        interpolate(curve, cell_mv)
+    }
+}
+
+/// piecewise linear interpolation on a curve sorted by descending voltage
+fn interpolate(curve: &[(u16, u8)], mv: u16) -> u8 {
+    let (first_mv, first_pct) = curve[0];
+    if mv >= first_mv {
+        return first_pct;
+    }
+    for pair in curve.windows(2) {
+        let (hi_mv, hi_pct) = pair[0];
+        let (lo_mv, lo_pct) = pair[1];
+        if mv >= lo_mv {
+            let span = (hi_mv - lo_mv) as u32;
+            let pos = (mv - lo_mv) as u32;
+            return lo_pct + ((hi_pct - lo_pct) as u32 * pos / span) as u8;
+        }
+    }
+    0
+}
+
+/// battery voltage from the voltage at the divider output, with the
+/// divider ratio in thousandths
+pub fn divided_millivolts(pin_mv: u32, ratio_milli: u32) -> u16 {
+    (pin_mv as u64 * ratio_milli as u64 / 1000).min(u16::MAX as u64) as u16
+}
+
+/// true if the battery is low
+///
+/// It's low at or below `low_percent`, and once low stays low until the
+/// charge is more than `hysteresis` percent above it, so the flag doesn't
+/// toggle with noise at the threshold.
+pub fn is_low_with_hysteresis(percent: u8, was_low: bool, low_percent: u8, hysteresis: u8) -> bool {
+    if was_low {
+        percent <= low_percent.saturating_add(hysteresis)
+    } else {
+        percent <= low_percent
+    }
+}
+
+/// Latest battery measurement
+#[derive(Debug, Clone, Copy, Format)]
+pub struct BatteryStatus {
+    pub millivolts: u16,
+    pub percent: u8,
+    pub low: bool,
+}
+
+static BATTERY_STATUS: Mutex<CriticalSectionRawMutex, Cell<Option<BatteryStatus>>> =
+    Mutex::new(Cell::new(None));
+
+/// the most recent battery status, None before the first measurement
+pub fn status() -> Option<BatteryStatus> {
+    BATTERY_STATUS.lock(|s| s.get())
+}
+
+/// true if the battery has been measured and is low
+pub fn is_low() -> bool {
+    status().map_or(false, |s| s.low)
+}
+
+/// task to measure the battery voltage
+#[embassy_executor::task]
+pub async fn battery_monitor(
+    mut adc: adc::Adc<'static, peripherals::ADC1>,
+    mut pin: peripherals::PA0,
+    params: Parameters,
+) {
+    let mut delay = embassy_time::Delay;
+    let mut vrefint = adc.enable_vref(&mut delay);
+    adc.set_sample_time(adc::SampleTime::Cycles601_5);
+    // SAFETY: the calibration value is in read-only system memory
+    let vrefint_cal = unsafe { VREFINT_CAL.read_volatile() } as u32;
+    info!("starting battery monitor");
+    loop {
+        let vrefint_sample = adc.read(&mut vrefint).await as u32;
+        let sample = adc.read(&mut pin).await as u32;
+        if vrefint_sample > 0 {
+            let vdda_mv = VREFINT_CAL_MV * vrefint_cal / vrefint_sample;
+            let pin_mv = vdda_mv * sample / 4095;
+            let millivolts = divided_millivolts(pin_mv, params.battery_divider_ratio_milli);
+            let percent = params.battery_chemistry.state_of_charge(millivolts);
+            let low = is_low_with_hysteresis(
+                percent,
+                is_low(),
+                params.battery_low_percent,
+                params.battery_low_hysteresis_percent,
+            );
+            let s = BatteryStatus {
+                millivolts,
+                percent,
+                low,
+            };
+            debug!("vdda: {}mV battery: {}", vdda_mv, s);
+            if low {
+                warn!("battery low: {}%", percent);
+            }
+            BATTERY_STATUS.lock(|b| b.set(Some(s)));
+        }
+        Timer::after(Duration::from_secs(
+            params.battery_sample_interval_sec.into(),
+        ))
+        .await;
+    }
+}
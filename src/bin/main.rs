@@ -4,27 +4,67 @@
 
 use atmo_monitor_stm32 as _; // global logger + panicking-behavior + memory layout
 use atmo_monitor_stm32::{
-    bme680_device::BmeDevice,
+    alert, battery, console,
+    coordinator::{Action, Coordinator, Event},
+    crash, diagnostics,
+    i2c_bus::{self, BusDevice},
+    low_power,
+    measurement::{self, Measurement, MeasurementSubscriber},
     parameter::Parameters,
-    pms7003_device::{self, PmCommand, PM25_SIGNAL},
+    pms7003_device::{PmCommand, PM25_SIGNAL},
+    rtc,
+    scd4x_device::{Scd4xCommand, SCD4X_SIGNAL},
     screen::Screen,
+    sensor::{self, Quantity, SensorId},
+    serializer,
+    stats::DailyStats,
+    watchdog::{self, TaskId},
     DisplayInfo,
 };
 use defmt::{debug, error, info, unwrap, Format};
 use embassy_executor::Spawner;
-use embassy_futures::{select, select::Either};
+use embassy_futures::{
+    select,
+    select::{Either, Either3},
+};
 use embassy_stm32::{
-    bind_interrupts, dma::NoDma, gpio::*, i2c, peripherals, rcc::AdcClockSource, spi, time::Hertz,
+    adc, bind_interrupts,
+    dma::NoDma,
+    exti::ExtiInput,
+    gpio::*,
+    i2c, peripherals,
+    rcc::AdcClockSource,
+    spi,
+    time::{khz, Hertz},
+    timer::simple_pwm::{PwmPin, SimplePwm},
     usart,
+    wdg::IndependentWatchdog,
 };
 use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
 use embassy_sync::channel::{Channel, Receiver, Sender};
 use embassy_sync::signal::Signal;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
 use il0373::{Builder, Dimensions, Display, GraphicDisplay, Interface, Rotation};
-use pms_7003::async_interface::Pms7003SensorAsync;
 use static_cell::{make_static, StaticCell};
 
+#[cfg(any(feature = "env-sensor", feature = "scd4x"))]
+use atmo_monitor_stm32::diagnostics::SensorFault;
+#[cfg(feature = "env-sensor")]
+use atmo_monitor_stm32::env_sensor::{self, EnvDevice};
+#[cfg(any(feature = "env-sensor", feature = "scd4x", feature = "sps30-i2c"))]
+use atmo_monitor_stm32::i2c_bus::I2cHandle;
+#[cfg(feature = "modbus")]
+use atmo_monitor_stm32::modbus;
+#[cfg(feature = "mqtt")]
+use atmo_monitor_stm32::mqtt;
+#[cfg(feature = "pm-sensor")]
+use atmo_monitor_stm32::pm_sensor::{self, PmDevice};
+#[cfg(feature = "pm-sensor")]
+use atmo_monitor_stm32::pms7003_device;
+#[cfg(feature = "scd4x")]
+use atmo_monitor_stm32::scd4x_device::{Scd4x, SCD4X_CALIBRATION};
+
 /// Display controller channel
 static DISPLAY_CHANNEL: StaticCell<Channel<NoopRawMutex, DisplayInfo, 2>> = StaticCell::new();
 
@@ -41,6 +81,20 @@ static mut RED_BUFFER: [u8; DISPLAY_BUFSIZE] = [0; DISPLAY_BUFSIZE];
 bind_interrupts!(struct Irqs {
     I2C1_EV => i2c::InterruptHandler<peripherals::I2C1>;
     USART1 => usart::BufferedInterruptHandler<peripherals::USART1>;
+    USART3 => usart::BufferedInterruptHandler<peripherals::USART3>;
+    ADC1_2 => adc::InterruptHandler<peripherals::ADC1>;
+});
+
+// the Modbus UART is only connected with its feature
+#[cfg(feature = "modbus")]
+bind_interrupts!(struct ModbusIrqs {
+    UART4 => usart::BufferedInterruptHandler<peripherals::UART4>;
+});
+
+// the WiFi coprocessor UART is only connected with the mqtt feature
+#[cfg(feature = "mqtt")]
+bind_interrupts!(struct MqttIrqs {
+    UART5 => usart::BufferedInterruptHandler<peripherals::UART5>;
 });
 
 /// Control enum
@@ -52,8 +106,21 @@ pub enum BmeCommand {
 
 pub static BME_SIGNAL: Signal<CriticalSectionRawMutex, BmeCommand> = Signal::new();
 
-#[embassy_executor::main]
-async fn main(spawner: Spawner) {
+/// Signalled when the screen shows the latest measurement
+static SCREEN_UPDATED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static EXECUTOR: StaticCell<low_power::Executor> = StaticCell::new();
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    EXECUTOR
+        .init(low_power::Executor::new())
+        .run(|spawner| unwrap!(spawner.spawn(start(spawner))));
+}
+
+/// set up the peripherals and spawn the tasks
+#[embassy_executor::task]
+async fn start(spawner: Spawner) {
     info!("atmo-monitor!");
 
     //defmt::trace!("trace");
@@ -69,10 +136,27 @@ async fn main(spawner: Spawner) {
     config.rcc.pclk2 = Some(Hertz(64_000_000));
     config.rcc.adc = Some(AdcClockSource::PllDiv1);
     let p = embassy_stm32::init(config);
+    rtc::enable();
+    if !rtc::is_set() {
+        info!("rtc not set, use the console 'time set' command");
+    }
 
     // create the parameters
     let parameters = Parameters::new(COLS, ROWS);
     info!("parameters: {}", parameters);
+    rtc::set_utc_offset(parameters.utc_offset_minutes);
+    serializer::set_format(parameters.output_format);
+
+    // report a crash before the last reset
+    let crash_record = crash::take();
+    if let Some(record) = &crash_record {
+        error!("crashed before reset: {}", record);
+    }
+
+    // report a task that stalled before the last reset
+    if let Some(task) = watchdog::take_stall_record() {
+        error!("watchdog reset, task {} stalled", task);
+    }
 
     // dc - PC7, rst - PB4, busy - PB5, ena - PB3
     // sck - PA5, mosi - PA7, miso - PA6
@@ -84,19 +168,47 @@ async fn main(spawner: Spawner) {
     let display_ena = Output::new(p.PB3, Level::High, Speed::Low);
 
     // usart1 rx = PA9, tx = PA10
+    #[cfg(feature = "pm-sensor")]
     info!("Initializing particulate sensor...");
-    let mut usart_config = usart::Config::default();
-    usart_config.baudrate = 9600;
-    let tx_buf = &mut make_static!([0u8; 32])[..];
-    let rx_buf = &mut make_static!([0u8; 64])[..];
-    let usart =
-        usart::BufferedUart::new(p.USART1, Irqs, p.PA10, p.PA9, tx_buf, rx_buf, usart_config);
-    let pm25dev = Pms7003SensorAsync::new(usart);
+    #[cfg(any(
+        feature = "pms7003",
+        feature = "pms5003",
+        feature = "pmsa003",
+        feature = "sps30"
+    ))]
+    let pm_uart = {
+        let mut usart_config = usart::Config::default();
+        usart_config.baudrate = pm_sensor::BAUD_RATE;
+        let tx_buf = &mut make_static!([0u8; 32])[..];
+        let rx_buf = &mut make_static!([0u8; 64])[..];
+        usart::BufferedUart::new(p.USART1, Irqs, p.PA10, p.PA9, tx_buf, rx_buf, usart_config)
+    };
+    #[cfg(feature = "pm-sensor")]
     let pm_set = Output::new(p.PA2, Level::High, Speed::Low);
+    #[cfg(feature = "pm-sensor")]
     let pm_reset = Output::new(p.PA3, Level::High, Speed::Low);
 
-    info!("Initializing bme680 sensor...");
+    // usart3 tx = PB10, rx = PB11
+    info!("Initializing console...");
+    let mut console_config = usart::Config::default();
+    console_config.baudrate = 115200;
+    // room for most of a measurement record
+    let console_tx_buf = &mut make_static!([0u8; 256])[..];
+    let console_rx_buf = &mut make_static!([0u8; 64])[..];
+    let console_uart = usart::BufferedUart::new(
+        p.USART3,
+        Irqs,
+        p.PB11,
+        p.PB10,
+        console_tx_buf,
+        console_rx_buf,
+        console_config,
+    );
+
+    #[cfg(feature = "env-sensor")]
+    info!("Initializing environmental sensor...");
     // initialize i2c
+    #[cfg(any(feature = "env-sensor", feature = "scd4x", feature = "sps30-i2c"))]
     let i2c = i2c::I2c::new(
         p.I2C1,
         p.PB8,
@@ -107,7 +219,44 @@ async fn main(spawner: Spawner) {
         Hertz(100_000),
         i2c::Config::default(),
     );
-    let bme_dev = BmeDevice::new(i2c);
+    #[cfg(any(feature = "env-sensor", feature = "scd4x", feature = "sps30-i2c"))]
+    let i2c_bus = i2c_bus::init(i2c);
+    #[cfg(any(feature = "bme680", feature = "bme688"))]
+    let bme_dev = EnvDevice::new(
+        I2cHandle::new(i2c_bus, BusDevice::Env),
+        I2cHandle::new(i2c_bus, BusDevice::Env),
+        parameters.env_heater_profile,
+    );
+    #[cfg(any(feature = "bme280", feature = "sht4x"))]
+    let bme_dev = EnvDevice::new(I2cHandle::new(i2c_bus, BusDevice::Env));
+    #[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+    let pm25dev = PmDevice::new(pm_uart, pm_sensor::PLANTOWER_MODEL);
+    #[cfg(feature = "sps30")]
+    let pm25dev = PmDevice::new(pm_uart);
+    #[cfg(feature = "sps30-i2c")]
+    let pm25dev = PmDevice::new(I2cHandle::new(i2c_bus, BusDevice::Sps30));
+
+    // alert outputs, led - PC8, buzzer - PC6 (TIM3_CH1), silence button - PC13
+    let alert_led = Output::new(p.PC8, Level::Low, Speed::Low);
+    let buzzer = SimplePwm::new(
+        p.TIM3,
+        Some(PwmPin::new_ch1(p.PC6)),
+        None,
+        None,
+        None,
+        khz(2),
+    );
+    let silence_button = ExtiInput::new(Input::new(p.PC13, Pull::None), p.EXTI13);
+
+    // battery voltage divider on PA0
+    let adc = adc::Adc::new(p.ADC1, Irqs, &mut embassy_time::Delay);
+    #[cfg(feature = "env-sensor")]
+    if bme_dev.is_err() {
+        error!(
+            "{} not found, continuing without it",
+            env_sensor::MODEL_NAME
+        );
+    }
 
     // spi
     let mut spi_config = spi::Config::default();
@@ -126,7 +275,7 @@ async fn main(spawner: Spawner) {
         .rotation(Rotation::Rotate90)
         .build()
         .unwrap();
-    let screen = Screen::new(
+    let mut screen = Screen::new(
         GraphicDisplay::new(
             Display::new(
                 Interface::new(spi, (display_cs, display_busy, display_dc, display_rst)),
@@ -142,22 +291,45 @@ async fn main(spawner: Spawner) {
 
     Timer::after(Duration::from_millis(800)).await;
 
+    if let Some(record) = &crash_record {
+        screen.power_on();
+        screen.show_crash(record);
+        screen.power_off();
+    }
+
     // data channels
     let dspctrl_channel = DISPLAY_CHANNEL.init(Channel::new());
 
     info!("Starting tasks...");
 
-    unwrap!(spawner.spawn(bme680_controller(
-        bme_dev,
+    let wdg = IndependentWatchdog::new(p.IWDG, parameters.watchdog_timeout_ms * 1000);
+    unwrap!(spawner.spawn(watchdog::supervisor(wdg, parameters)));
+
+    #[cfg(feature = "env-sensor")]
+    if let Ok(bme_dev) = bme_dev {
+        unwrap!(spawner.spawn(env_controller(
+            bme_dev,
+            dspctrl_channel.sender(),
+            parameters,
+        )));
+    }
+    #[cfg(feature = "scd4x")]
+    unwrap!(spawner.spawn(scd4x_controller(
+        Scd4x::new(
+            I2cHandle::new(i2c_bus, BusDevice::Scd4x),
+            parameters.scd4x_mode,
+        ),
         dspctrl_channel.sender(),
+        unwrap!(measurement::subscribe()),
         parameters,
     )));
     unwrap!(spawner.spawn(display_controller(
-        screen,
         display_ena.degrade(),
         dspctrl_channel.receiver(),
         parameters,
     )));
+    unwrap!(spawner.spawn(screen_controller(screen, unwrap!(measurement::subscribe()))));
+    #[cfg(feature = "pm-sensor")]
     unwrap!(spawner.spawn(pms7003_device::pm25_controller(
         pm25dev,
         pm_reset.degrade(),
@@ -165,97 +337,329 @@ async fn main(spawner: Spawner) {
         dspctrl_channel.sender(),
         parameters,
     )));
+    unwrap!(spawner.spawn(battery::battery_monitor(adc, p.PA0, parameters)));
+    unwrap!(spawner.spawn(console::console(
+        console_uart,
+        unwrap!(measurement::subscribe()),
+    )));
+    unwrap!(spawner.spawn(alert::alert_controller(
+        alert_led.degrade(),
+        buzzer,
+        silence_button,
+        unwrap!(measurement::subscribe()),
+        parameters,
+    )));
+    unwrap!(spawner.spawn(measurement_logger(unwrap!(measurement::subscribe()))));
+    #[cfg(feature = "modbus")]
+    {
+        // uart4 tx = PC10, rx = PC11, RS-485 DE and /RE = PB12
+        info!("Initializing modbus...");
+        let mut modbus_config = usart::Config::default();
+        modbus_config.baudrate = parameters.modbus_baud_rate;
+        // 8E1, the Modbus default
+        modbus_config.parity = usart::Parity::ParityEven;
+        let modbus_tx_buf = &mut make_static!([0u8; 256])[..];
+        let modbus_rx_buf = &mut make_static!([0u8; 256])[..];
+        let modbus_uart = usart::BufferedUart::new(
+            p.UART4,
+            ModbusIrqs,
+            p.PC11,
+            p.PC10,
+            modbus_tx_buf,
+            modbus_rx_buf,
+            modbus_config,
+        );
+        let modbus_de = Output::new(p.PB12, Level::Low, Speed::Low);
+        unwrap!(spawner.spawn(modbus::modbus(
+            modbus_uart,
+            modbus_de.degrade(),
+            unwrap!(measurement::subscribe()),
+            parameters,
+        )));
+    }
+    #[cfg(feature = "mqtt")]
+    if parameters.mqtt_wifi_ssid.is_empty() {
+        error!("mqtt not started, ATMO_WIFI_SSID wasn't set when building");
+    } else {
+        // uart5 tx = PC12, rx = PD2, to the WiFi coprocessor at the AT
+        // firmware's 115200 baud
+        info!("Initializing mqtt...");
+        let mut mqtt_config = usart::Config::default();
+        mqtt_config.baudrate = 115200;
+        let mqtt_tx_buf = &mut make_static!([0u8; 256])[..];
+        let mqtt_rx_buf = &mut make_static!([0u8; 256])[..];
+        let mqtt_uart = usart::BufferedUart::new(
+            p.UART5,
+            MqttIrqs,
+            p.PD2,
+            p.PC12,
+            mqtt_tx_buf,
+            mqtt_rx_buf,
+            mqtt_config,
+        );
+        unwrap!(spawner.spawn(mqtt::mqtt(
+            mqtt_uart,
+            unwrap!(measurement::subscribe()),
+            parameters,
+        )));
+    }
 }
 
 /// task to read sensor data
+#[cfg(feature = "env-sensor")]
 #[embassy_executor::task]
-async fn bme680_controller(
-    mut bme_dev: BmeDevice<i2c::I2c<'static, peripherals::I2C1>>,
+async fn env_controller(
+    mut bme_dev: EnvDevice,
     sender: Sender<'static, NoopRawMutex, DisplayInfo, 2>,
     params: Parameters,
 ) {
-    bme_dev.init();
+    let mut initialized = env_init(&mut bme_dev, &params).await;
+    loop {
+        match watchdog::supervised(TaskId::Env, BME_SIGNAL.wait()).await {
+            BmeCommand::On => {
+                // retry a failed initialization before each reading
+                if !initialized {
+                    initialized = env_init(&mut bme_dev, &params).await;
+                }
+                let info = match bme_dev.read() {
+                    Ok(data) => DisplayInfo::Data(
+                        SensorId::Env,
+                        data.readings(params.bme680_gas_baseline_ohm),
+                    ),
+                    Err(e) => DisplayInfo::Fault(SensorFault::Env(e)),
+                };
+                sender.send(info).await;
+            }
+            BmeCommand::Off => {}
+        }
+    }
+}
+
+/// initialize the environmental sensor, returns true if successful
+#[cfg(feature = "env-sensor")]
+async fn env_init(bme_dev: &mut EnvDevice, params: &Parameters) -> bool {
+    if bme_dev.init().is_err() {
+        return false;
+    }
     // throw away the first reading
-    bme_dev.read();
+    bme_dev.read().ok();
     Timer::after(Duration::from_millis(
         params.bme680_first_data_delay_ms.into(),
     ))
     .await;
+    true
+}
+
+/// task to read the CO2 sensor
+///
+/// Each measurement's pressure from the BME680 is passed on to the sensor
+/// for pressure compensation.
+#[cfg(feature = "scd4x")]
+#[embassy_executor::task]
+async fn scd4x_controller(
+    mut scd_dev: Scd4x<I2cHandle>,
+    sender: Sender<'static, NoopRawMutex, DisplayInfo, 2>,
+    mut measurements: MeasurementSubscriber,
+    params: Parameters,
+) {
+    let mut initialized = scd_dev.init(params.scd4x_auto_calibration).await.is_ok();
     loop {
-        match BME_SIGNAL.wait().await {
-            BmeCommand::On => {
-                let data = bme_dev.read();
-                sender.send(DisplayInfo::Bme680Data(data)).await;
+        match watchdog::supervised(
+            TaskId::Scd4x,
+            select::select3(
+                SCD4X_SIGNAL.wait(),
+                SCD4X_CALIBRATION.wait(),
+                measurements.next_message_pure(),
+            ),
+        )
+        .await
+        {
+            Either3::First(Scd4xCommand::On) => {
+                // retry a failed initialization before each reading
+                if !initialized {
+                    initialized = scd_dev.init(params.scd4x_auto_calibration).await.is_ok();
+                }
+                let info = match scd_dev.measure().await {
+                    Ok(data) => DisplayInfo::Data(SensorId::Scd4x, data.readings()),
+                    Err(e) => DisplayInfo::Fault(SensorFault::Scd4x(e)),
+                };
+                sender.send(info).await;
+            }
+            Either3::First(Scd4xCommand::Off) => {}
+            Either3::Second(calibration) => match scd_dev.calibrate(calibration).await {
+                Ok(correction) => info!("co2 {} done, correction {} ppm", calibration, correction),
+                Err(e) => error!("co2 {} failed: {}", calibration, e),
+            },
+            Either3::Third(m) => {
+                if let Some(hpa) = m.readings.get(Quantity::Pressure) {
+                    scd_dev.set_ambient_pressure(hpa as u16).ok();
+                }
             }
-            BmeCommand::Off => {}
         }
     }
 }
 
 /// task to control display
 ///
-/// signal both sensors to collect data
-/// when both have responded, then signal the sensors to suspend
-/// display the data
-/// wait for display interval and repeat
+/// drives the measurement coordinator, passing it messages from the sensor
+/// tasks and deadlines, and carrying out its actions: signalling the sensors,
+/// publishing the measurement and waiting for the screen to show it, and
+/// sleeping between cycles
 #[embassy_executor::task]
 async fn display_controller(
-    mut screen: Screen,
     mut ena_pin: Output<'static, AnyPin>,
     receiver: Receiver<'static, NoopRawMutex, DisplayInfo, 2>,
     params: Parameters,
 ) {
+    let mut coordinator = Coordinator::new(params);
+    let mut event = Event::Start {
+        battery_low: battery::is_low(),
+        unix_time: rtc::is_set().then(rtc::unix_time),
+    };
     loop {
-        ena_pin.set_high();
-        PM25_SIGNAL.signal(PmCommand::Wake);
-        BME_SIGNAL.signal(BmeCommand::On);
-        let mut current_data = None;
-        let mut current_pmdata = None;
-        loop {
-            debug!("Start sensor data cycle");
-            match select::select(
-                receiver.receive(),
-                Timer::after(Duration::from_secs(
-                    params.screen_controller_timeout_sec.into(),
-                )),
-            )
-            .await
-            {
-                Either::First(recv) => {
-                    debug!("display_controller got {}", recv);
-                    match recv {
-                        DisplayInfo::Bme680Data(data) => {
-                            current_data = Some(data);
-                            BME_SIGNAL.signal(BmeCommand::Off);
-                        }
-                        DisplayInfo::Pms7003Data(data) => {
-                            current_pmdata = Some(data);
-                            PM25_SIGNAL.signal(PmCommand::Sleep);
-                        }
-                    }
+        debug!("coordinator {}: {}", coordinator.state(), event);
+        let actions = coordinator.handle(event, Instant::now().as_millis());
+        let mut next = None;
+        for action in actions {
+            match action {
+                Action::StartCycle => {
+                    // discard anything sent after the previous cycle gave up on a sensor
+                    while receiver.try_receive().is_ok() {}
+                    ena_pin.set_high();
+                }
+                Action::StartSensor(id) => match id {
+                    SensorId::Env => BME_SIGNAL.signal(BmeCommand::On),
+                    SensorId::Pm => PM25_SIGNAL.signal(PmCommand::Wake),
+                    SensorId::Scd4x => SCD4X_SIGNAL.signal(Scd4xCommand::On),
+                },
+                Action::StopSensor(id) => match id {
+                    SensorId::Env => BME_SIGNAL.signal(BmeCommand::Off),
+                    SensorId::Pm => PM25_SIGNAL.signal(PmCommand::Sleep),
+                    SensorId::Scd4x => SCD4X_SIGNAL.signal(Scd4xCommand::Off),
+                },
+                Action::RecordFault(fault) => diagnostics::record(fault),
+                Action::Render(snapshot) => {
+                    SCREEN_UPDATED.reset();
+                    measurement::publish(Measurement::new(
+                        &snapshot,
+                        Instant::now().as_millis(),
+                        rtc::is_set().then(rtc::unix_time),
+                        battery::status(),
+                    ));
+                    watchdog::supervised(TaskId::Display, SCREEN_UPDATED.wait()).await;
+                    next = Some(Event::RenderDone);
+                }
+                Action::Sleep { seconds } => {
+                    sleep(&mut ena_pin, seconds, &params).await;
+                    next = Some(Event::Start {
+                        battery_low: battery::is_low(),
+                        unix_time: rtc::is_set().then(rtc::unix_time),
+                    });
                 }
-                Either::Second(_) => {
-                    error!("Timeout waiting for sensors");
+            }
+        }
+        event = match next {
+            Some(event) => event,
+            None => {
+                let deadline = coordinator
+                    .deadline()
+                    .map_or(Instant::MAX, Instant::from_millis);
+                match watchdog::supervised(
+                    TaskId::Display,
+                    select::select(receiver.receive(), Timer::at(deadline)),
+                )
+                .await
+                {
+                    Either::First(info) => Event::Sensor(info),
+                    Either::Second(_) => Event::Tick,
                 }
             }
-            if let (Some(d), Some(pd)) = (current_data, current_pmdata) {
-                screen.power_on();
-                screen.update(&d, &pd);
-                screen.power_off();
-                break;
+        };
+    }
+}
+
+/// task to show each measurement, with the alert status the alert task
+/// gives it
+///
+/// The task only checks in while waiting for a measurement, so a render
+/// that hangs is caught by the watchdog, while the display task waiting on
+/// it keeps checking in.
+#[embassy_executor::task]
+async fn screen_controller(mut screen: Screen, mut measurements: MeasurementSubscriber) {
+    loop {
+        let m = watchdog::supervised(TaskId::Screen, measurements.next_message_pure()).await;
+        let alert_status = alert::status(&m).await;
+        screen.power_on();
+        screen.update(
+            &m.readings,
+            m.pm_state,
+            m.battery.as_ref(),
+            m.local_time().as_ref(),
+            &alert_status,
+        );
+        screen.power_off();
+        watchdog::check_in(TaskId::Screen);
+        SCREEN_UPDATED.signal(());
+        debug!(
+            "Exit sensor data cycle, faults: {}, i2c env: {}, scd4x: {}, sps30: {}",
+            diagnostics::fault_log(),
+            i2c_bus::bus_stats(BusDevice::Env),
+            i2c_bus::bus_stats(BusDevice::Scd4x),
+            i2c_bus::bus_stats(BusDevice::Sps30)
+        );
+    }
+}
+
+/// task to log measurements, and the daily statistics at local midnight
+#[embassy_executor::task]
+async fn measurement_logger(mut measurements: MeasurementSubscriber) {
+    let mut daily_stats = DailyStats::default();
+    loop {
+        let m = measurements.next_message_pure().await;
+        for r in m.readings.iter() {
+            if let Some(spec) = sensor::spec(r.kind) {
+                let mut value: String<24> = String::new();
+                spec.write_value(&mut value, r.value).ok();
+                info!("{}: {}", spec.label, value.as_str());
             }
         }
-        debug!("Exit sensor data cycle");
-        Timer::after(Duration::from_secs(
-            params.screen_enable_shutdown_delay_sec.into(),
-        ))
-        .await;
-        ena_pin.set_low();
-        debug!("sleep cycle");
-        Timer::after(Duration::from_secs(
-            (params.screen_display_min_refresh_sec - params.screen_enable_shutdown_delay_sec)
-                .into(),
-        ))
+        if let Some(time) = m.local_time() {
+            if let Some(finished) = daily_stats.update(
+                &time,
+                m.readings.get(Quantity::Pm2_5).map(|v| v as u16),
+                m.readings.get(Quantity::Temperature),
+            ) {
+                info!("daily statistics: {}", finished);
+            }
+        }
+    }
+}
+
+/// keep the display powered for the shutdown delay, then power it down and
+/// sleep for the rest of the refresh interval
+async fn sleep(ena_pin: &mut Output<'static, AnyPin>, refresh_sec: u32, params: &Parameters) {
+    watchdog::sleep(
+        TaskId::Display,
+        Duration::from_secs(params.screen_enable_shutdown_delay_sec.into()),
+    )
+    .await;
+    ena_pin.set_low();
+    debug!("sleep cycle");
+    let sleep = Duration::from_secs(
+        refresh_sec
+            .saturating_sub(params.screen_enable_shutdown_delay_sec)
+            .into(),
+    );
+    if params.low_power_stop {
+        watchdog::supervised(
+            TaskId::Display,
+            low_power::stop_for(
+                sleep,
+                Duration::from_millis((params.watchdog_timeout_ms / 2).into()),
+            ),
+        )
         .await;
+    } else {
+        watchdog::sleep(TaskId::Display, sleep).await;
     }
 }
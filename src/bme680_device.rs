@@ -1,29 +1,63 @@
-//! Reading the BME680 sensor
+//! Reading the BME680 and BME688 sensors
+//!
+//! The sensors are read in forced mode, with a forced measurement for each
+//! step of the gas heater profile. The bme680 crate only knows the BME680,
+//! on a BME688 the gas measurement is enabled and its result read from the
+//! BME688 registers here.
 
-use bme680::{Bme680, I2CAddress, IIRFilterSize, OversamplingSetting, PowerMode, SettingsBuilder};
+use crate::env_sensor::{
+    bme688_gas_resistance, BmeFault, EnvData, HeaterProfile, HeaterStep, BME688_VARIANT_ID,
+};
+use bme680::{
+    Bme680, I2CAddress, IIRFilterSize, OversamplingSetting, PowerMode, Settings, SettingsBuilder,
+};
 use core::fmt;
-use defmt::{debug, Format};
+use defmt::{debug, error, Debug2Format};
 use embassy_time::{Delay, Duration};
 use embedded_hal::blocking::delay::DelayMs;
 use embedded_hal::blocking::i2c::{Read, Write};
 
-/// Data sensed by the BME680 device
-#[derive(Debug, Default, Clone, Copy, Format)]
-pub struct Bme680Data {
-    pub temperature: f32,
-    pub humidity: f32,
-    pub pressure: f32,
-    pub gas_resistance: u32,
-    pub gas_valid: bool,
-    pub heat_stable: bool,
+/// I2C address of the sensor, SDO pulled high as on the Adafruit breakout
+const ADDRESS: u8 = 0x77;
+
+/// Registers the bme680 crate doesn't handle for the BME688
+mod reg {
+    pub const GAS_R_MSB_H: u8 = 0x2c;
+    pub const CTRL_GAS_1: u8 = 0x71;
+    pub const VARIANT_ID: u8 = 0xf0;
 }
 
+/// run_gas in CTRL_GAS_1, the BME688 uses 0b10 where the BME680 uses 0b01
+const RUN_GAS_MASK: u8 = 0x30;
+const RUN_GAS_BME688: u8 = 0x20;
+
 /// Structure for BME680 device attached to I2C bus
 pub struct BmeDevice<I2C> {
     dev: Bme680<I2C, embassy_time::Delay>,
+    /// a second handle to the sensor, for the BME688 registers
+    regs: I2C,
+    /// true if the sensor is a BME688
+    bme688: bool,
+    profile: HeaterProfile,
     profile_duration: Duration,
 }
 
+/// the settings for a heater step
+fn step_settings(step: &HeaterStep) -> Settings {
+    SettingsBuilder::new()
+        .with_humidity_oversampling(OversamplingSetting::OS2x)
+        .with_pressure_oversampling(OversamplingSetting::OS4x)
+        .with_temperature_oversampling(OversamplingSetting::OS8x)
+        .with_temperature_filter(IIRFilterSize::Size3)
+        .with_gas_measurement(
+            Duration::from_millis(step.duration_ms.into()).into(),
+            step.temperature_c,
+            25,
+        )
+        .with_run_gas(true)
+        .build()
+}
+
 impl<I2C> BmeDevice<I2C>
 where
     I2C: Read + Write,
@@ -33,60 +67,153 @@ where
     /// Create a new BmeDevice, do not initialize it yet
     /// due to the bme680 module, there is some i2c traffic on the bus
     /// during this method
-    pub fn new(i2c: I2C) -> BmeDevice<I2C> {
+    ///
+    /// `regs` is a second handle to the sensor, used to find the variant,
+    /// and for the BME688 registers.
+    pub fn new(i2c: I2C, regs: I2C, profile: HeaterProfile) -> Result<BmeDevice<I2C>, BmeFault> {
         let mut delayer = Delay;
-        BmeDevice {
-            dev: Bme680::init(i2c, &mut delayer, I2CAddress::Secondary).unwrap(),
+        let dev = Bme680::init(i2c, &mut delayer, I2CAddress::Secondary).map_err(|e| {
+            error!("bme680 init: {}", Debug2Format(&e));
+            BmeFault::Init
+        })?;
+        let mut bme = BmeDevice {
+            dev,
+            regs,
+            bme688: false,
+            profile,
             profile_duration: Duration::from_secs(0),
-        }
+        };
+        let mut variant = [0u8; 1];
+        bme.read_registers(reg::VARIANT_ID, &mut variant, BmeFault::Init)?;
+        bme.bme688 = variant[0] == BME688_VARIANT_ID;
+        debug!("bme68x variant {=u8}", variant[0]);
+        Ok(bme)
     }
 
     /// Initialize the BmeDevice so it can read data
-    pub fn init(&mut self) {
-        let settings = SettingsBuilder::new()
-            .with_humidity_oversampling(OversamplingSetting::OS2x)
-            .with_pressure_oversampling(OversamplingSetting::OS4x)
-            .with_temperature_oversampling(OversamplingSetting::OS8x)
-            .with_temperature_filter(IIRFilterSize::Size3)
-            .with_gas_measurement(Duration::from_millis(1500).into(), 320, 25)
-            .with_run_gas(true)
-            .build();
-        let mut delayer = Delay;
-        self.dev
-            .set_sensor_settings(&mut delayer, settings)
-            .unwrap();
-        self.profile_duration =
-            Duration::try_from(self.dev.get_profile_dur(&settings.0).unwrap()).unwrap();
-        debug!("bme680 delay: {}ms", self.profile_duration);
+    pub fn init(&mut self) -> Result<(), BmeFault> {
+        let first = self.profile.steps()[0];
+        self.set_step(&first)?;
         debug!("bme680 initialized");
+        Ok(())
     }
 
-    /// Read data from the BmeDevice
-    pub fn read(&mut self) -> Bme680Data {
+    /// set the heater step for the next measurement
+    fn set_step(&mut self, step: &HeaterStep) -> Result<(), BmeFault> {
+        let settings = step_settings(step);
         let mut delayer = Delay;
-        // Read sensor data
         self.dev
-            .set_sensor_mode(&mut delayer, PowerMode::ForcedMode)
-            .unwrap();
-        delayer.delay_ms(self.profile_duration.as_millis() as u8);
-        let (data, _state) = self.dev.get_sensor_data(&mut delayer).unwrap();
+            .set_sensor_settings(&mut delayer, settings)
+            .map_err(|e| {
+                error!("bme680 settings: {}", Debug2Format(&e));
+                BmeFault::Init
+            })?;
+        let profile_dur = self.dev.get_profile_dur(&settings.0).map_err(|e| {
+            error!("bme680 profile duration: {}", Debug2Format(&e));
+            BmeFault::Init
+        })?;
+        self.profile_duration = Duration::try_from(profile_dur).map_err(|_| BmeFault::Init)?;
+        debug!("bme680 delay: {}ms", self.profile_duration);
+        if self.bme688 {
+            let mut ctrl = [0u8; 1];
+            self.read_registers(reg::CTRL_GAS_1, &mut ctrl, BmeFault::Init)?;
+            let ctrl = (ctrl[0] & !RUN_GAS_MASK) | RUN_GAS_BME688;
+            self.write_register(reg::CTRL_GAS_1, ctrl, BmeFault::Init)?;
+        }
+        Ok(())
+    }
 
-        let reading = Bme680Data {
-            temperature: data.temperature_celsius(),
-            humidity: data.humidity_percent(),
-            pressure: data.pressure_hpa(),
-            gas_resistance: data.gas_resistance_ohm(),
-            gas_valid: data.gas_valid(),
-            heat_stable: data.heat_stable(),
-        };
+    /// Read data from the BmeDevice, stepping through the heater profile
+    ///
+    /// With more than one step, the heater is set before each measurement,
+    /// and the gas resistance at the profile's `gas_step` is reported.
+    pub fn read(&mut self) -> Result<EnvData, BmeFault> {
+        let profile = self.profile;
+        let steps = profile.steps();
+        let mut reading = EnvData::default();
+        for (i, step) in steps.iter().enumerate() {
+            if steps.len() > 1 {
+                self.set_step(step).map_err(|_| BmeFault::Read)?;
+            }
+            let (env, gas) = self.measure()?;
+            debug!(
+                "heater {}C {}ms: gas resistance {}",
+                step.temperature_c, step.duration_ms, gas
+            );
+            // the gas step, the first step stands in if it's out of range
+            if i == profile.gas_step as usize || i == 0 {
+                reading = EnvData {
+                    gas_resistance: gas,
+                    ..env
+                };
+            }
+        }
         debug!("Temperature {}°C", reading.temperature);
         debug!("Pressure {}hPa", reading.pressure);
         debug!("Humidity {}%", reading.humidity);
         debug!("Gas Resistance {}Ω", reading.gas_resistance);
+        Ok(reading)
+    }
+
+    /// take a forced measurement, the gas resistance is None unless the
+    /// reading is valid and the heater was stable
+    fn measure(&mut self) -> Result<(EnvData, Option<u32>), BmeFault> {
+        let mut delayer = Delay;
+        self.dev
+            .set_sensor_mode(&mut delayer, PowerMode::ForcedMode)
+            .map_err(|e| {
+                error!("bme680 set mode: {}", Debug2Format(&e));
+                BmeFault::Read
+            })?;
+        // a heater step can be longer than the 255ms a u8 delay allows
+        delayer.delay_ms(self.profile_duration.as_millis() as u32);
+        let (data, _state) = self.dev.get_sensor_data(&mut delayer).map_err(|e| {
+            error!("bme680 get data: {}", Debug2Format(&e));
+            BmeFault::Read
+        })?;
         debug!(
             "gas valid: {} gas heater stable: {}",
-            reading.gas_valid, reading.heat_stable
+            data.gas_valid(),
+            data.heat_stable()
         );
-        reading
+        let gas = if self.bme688 {
+            let mut gas_regs = [0u8; 2];
+            self.read_registers(reg::GAS_R_MSB_H, &mut gas_regs, BmeFault::Read)?;
+            bme688_gas_resistance(&gas_regs)
+        } else {
+            (data.gas_valid() && data.heat_stable()).then(|| data.gas_resistance_ohm())
+        };
+        let env = EnvData {
+            temperature: data.temperature_celsius(),
+            humidity: data.humidity_percent(),
+            pressure: Some(data.pressure_hpa()),
+            gas_resistance: None,
+        };
+        Ok((env, gas))
+    }
+
+    fn write_register(&mut self, register: u8, value: u8, fault: BmeFault) -> Result<(), BmeFault> {
+        self.regs
+            .write(ADDRESS, &[register, value])
+            .map_err(|e| bus_error(e, register, fault))
     }
+
+    fn read_registers(
+        &mut self,
+        register: u8,
+        buf: &mut [u8],
+        fault: BmeFault,
+    ) -> Result<(), BmeFault> {
+        self.regs
+            .write(ADDRESS, &[register])
+            .map_err(|e| bus_error(e, register, fault))?;
+        self.regs
+            .read(ADDRESS, buf)
+            .map_err(|e| bus_error(e, register, fault))
+    }
+}
+
+fn bus_error<E: fmt::Debug>(e: E, register: u8, fault: BmeFault) -> BmeFault {
+    error!("bme68x register {=u8:#x}: {}", register, Debug2Format(&e));
+    fault
 }
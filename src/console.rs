@@ -0,0 +1,234 @@
+//! Serial console on USART3
+//!
+//! Line based commands, terminated by CR or LF, a line longer than 64
+//! characters is dropped with an error:
+//!
+//! - `time` show the local date and time
+//! - `time set YYYY-MM-DD HH:MM:SS` set the clock to a UTC date and time
+//! - `tz` show the local offset from UTC
+//! - `tz +HH:MM` set the local offset from UTC
+//! - `co2 asc on|off` enable or disable CO2 automatic self calibration
+//! - `co2 frc PPM` recalibrate the CO2 sensor to a known concentration
+//! - `output` show the measurement output format
+//! - `output off|csv|json|cbor` set the measurement output format
+//!
+//! Measurements are written to the console in the output format, see
+//! [`serializer`](crate::serializer).
+
+use crate::{
+    measurement::MeasurementSubscriber,
+    rtc::{self, DateTime},
+    scd4x_device::{Calibration, SCD4X_CALIBRATION},
+    sensor::{self, SensorId},
+    serializer::{self, OutputFormat, Serializer},
+};
+use core::fmt::Write as _;
+use defmt::{debug, error, Format};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{peripherals, usart};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+
+/// Length of the longest command line
+const LINE_LEN: usize = 64;
+
+/// A parsed console command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Command {
+    ShowTime,
+    SetTime(DateTime),
+    ShowOffset,
+    SetOffset(i16),
+    Co2Calibration(Calibration),
+    ShowOutput,
+    SetOutput(OutputFormat),
+}
+
+/// parse a command line
+pub fn parse(line: &str) -> Result<Command, &'static str> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("time"), None) => Ok(Command::ShowTime),
+        (Some("time"), Some("set")) => {
+            let date = words.next().ok_or("missing date")?;
+            let time = words.next().ok_or("missing time")?;
+            let dt = parse_date_time(date, time).ok_or("expected YYYY-MM-DD HH:MM:SS")?;
+            Ok(Command::SetTime(dt))
+        }
+        (Some("tz"), None) => Ok(Command::ShowOffset),
+        (Some("tz"), Some(offset)) => parse_offset(offset)
+            .map(Command::SetOffset)
+            .ok_or("expected +HH:MM or -HH:MM"),
+        (Some("co2"), Some("asc")) => match words.next() {
+            Some("on") => Ok(Command::Co2Calibration(Calibration::AutoSelfCalibration(
+                true,
+            ))),
+            Some("off") => Ok(Command::Co2Calibration(Calibration::AutoSelfCalibration(
+                false,
+            ))),
+            _ => Err("expected on or off"),
+        },
+        (Some("co2"), Some("frc")) => words
+            .next()
+            .and_then(|ppm| ppm.parse().ok())
+            .filter(|ppm| (400..=2000).contains(ppm))
+            .map(|ppm| Command::Co2Calibration(Calibration::Forced(ppm)))
+            .ok_or("expected ppm from 400 to 2000"),
+        (Some("output"), None) => Ok(Command::ShowOutput),
+        (Some("output"), Some(format)) => OutputFormat::from_name(format)
+            .map(Command::SetOutput)
+            .ok_or("expected off, csv, json or cbor"),
+        _ => Err("unknown command"),
+    }
+}
+
+fn parse_date_time(date: &str, time: &str) -> Option<DateTime> {
+    let mut d = date.split('-');
+    let mut t = time.split(':');
+    let dt = DateTime {
+        year: d.next()?.parse().ok()?,
+        month: d.next()?.parse().ok()?,
+        day: d.next()?.parse().ok()?,
+        hour: t.next()?.parse().ok()?,
+        minute: t.next()?.parse().ok()?,
+        second: t.next()?.parse().ok()?,
+    };
+    if d.next().is_some() || t.next().is_some() || !dt.is_valid() {
+        return None;
+    }
+    Some(dt)
+}
+
+fn parse_offset(offset: &str) -> Option<i16> {
+    let (sign, rest) = match offset.as_bytes().first()? {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':')?;
+    let hours: i16 = hours.parse().ok()?;
+    let minutes: i16 = minutes.parse().ok()?;
+    if !(0..=14).contains(&hours) || !(0..60).contains(&minutes) {
+        return None;
+    }
+    Some(sign * (hours * 60 + minutes))
+}
+
+/// write the UTC offset as +HH:MM
+fn write_offset(out: &mut String<LINE_LEN>, minutes: i16) {
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let minutes = minutes.unsigned_abs();
+    write!(out, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60).ok();
+}
+
+/// run a command line, returning the reply
+fn execute(line: &str) -> String<LINE_LEN> {
+    let mut out = String::new();
+    match parse(line) {
+        Ok(Command::ShowTime) => match rtc::local_now() {
+            Some(t) => {
+                write!(
+                    out,
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+                    t.year, t.month, t.day, t.hour, t.minute, t.second
+                )
+                .ok();
+                write_offset(&mut out, rtc::utc_offset());
+            }
+            None => {
+                write!(out, "time not set").ok();
+            }
+        },
+        Ok(Command::SetTime(dt)) => {
+            if rtc::set(&dt) {
+                write!(out, "ok").ok();
+            } else {
+                write!(out, "error: no clock crystal").ok();
+            }
+        }
+        Ok(Command::ShowOffset) => {
+            write!(out, "UTC").ok();
+            write_offset(&mut out, rtc::utc_offset());
+        }
+        Ok(Command::SetOffset(minutes)) => {
+            rtc::set_utc_offset(minutes);
+            write!(out, "ok").ok();
+        }
+        Ok(Command::Co2Calibration(calibration)) => {
+            if sensor::is_included(SensorId::Scd4x) {
+                // the result is logged by the sensor task
+                SCD4X_CALIBRATION.signal(calibration);
+                write!(out, "ok").ok();
+            } else {
+                write!(out, "error: no CO2 sensor").ok();
+            }
+        }
+        Ok(Command::ShowOutput) => {
+            write!(out, "{}", serializer::format().name()).ok();
+        }
+        Ok(Command::SetOutput(format)) => {
+            serializer::set_format(format);
+            write!(out, "ok").ok();
+        }
+        Err(e) => {
+            write!(out, "error: {}", e).ok();
+        }
+    }
+    out.push_str("\r\n").ok();
+    out
+}
+
+/// task to read commands from the serial console, and write measurements
+/// in the output format
+#[embassy_executor::task]
+pub async fn console(
+    mut uart: usart::BufferedUart<'static, peripherals::USART3>,
+    mut measurements: MeasurementSubscriber,
+) {
+    let mut line: String<LINE_LEN> = String::new();
+    // true while dropping the rest of a line that was too long
+    let mut discarding = false;
+    let mut buf = [0u8; 16];
+    let mut serializer = Serializer::new();
+    debug!("console started");
+    loop {
+        // bytes read stay buffered in the uart if a measurement arrives first
+        let n = match select(uart.read(&mut buf), measurements.next_message_pure()).await {
+            Either::First(Ok(n)) => n,
+            Either::First(Err(e)) => {
+                error!("console read: {}", e);
+                continue;
+            }
+            Either::Second(m) => {
+                if let Err(e) = serializer.write(serializer::format(), &m, &mut uart).await {
+                    error!("console output: {}", e);
+                }
+                continue;
+            }
+        };
+        for &b in &buf[..n] {
+            match b {
+                b'\r' | b'\n' if discarding => {
+                    discarding = false;
+                    uart.write_all(b"error: line too long\r\n").await.ok();
+                }
+                b'\r' | b'\n' => {
+                    if !line.is_empty() {
+                        debug!("console command: {}", line.as_str());
+                        let reply = execute(line.trim());
+                        uart.write_all(reply.as_bytes()).await.ok();
+                        line.clear();
+                    }
+                }
+                _ if discarding => {}
+                // discard lines that are too long, up to the line end
+                _ => {
+                    if line.push(b as char).is_err() {
+                        line.clear();
+                        discarding = true;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! Measurement cycle coordinator
+//!
+//! A state machine driven by typed events, deciding when sensors are
+//! started and stopped, when a cycle is rendered, and how long to sleep.
+//! It doesn't touch hardware or read the clock, the task driving it
+//! passes in the time, and carries out the returned actions, so it can be
+//! tested with fake sensors and time. The sensors in a cycle are those in
+//! the registry. The PM2.5 humidity correction is applied to the readings
+//! before they're rendered, and the PM2.5 NowCast and 24 hour average are
+//! added, from the RTC time the cycle started at.
+//!
+//! ```text
+//! Idle/Sleeping --Start--> Warming --pm2.5 settled--> Sampling
+//! Warming/Sampling --all sensors done--> Rendering, or Fault if none had data
+//! Rendering/Fault --RenderDone--> Sleeping
+//! ```
+
+use crate::{
+    diagnostics::SensorFault,
+    nowcast::PmHistory,
+    parameter::Parameters,
+    pm_correction,
+    pms7003_device::PmState,
+    scheduler::AdaptiveScheduler,
+    sensor::{self, Quantity, Readings, SensorId, SENSOR_COUNT},
+    DisplayInfo,
+};
+use defmt::{debug, info, Format};
+use heapless::Vec;
+
+/// Coordinator states
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum State {
+    /// no cycle has started yet
+    Idle,
+    /// sensors started, waiting for the pm2.5 sensor to settle
+    Warming,
+    /// waiting for sensor data
+    Sampling,
+    /// all sensors done, waiting for the display to be updated
+    Rendering,
+    /// no sensor returned data, waiting for the display to be updated
+    Fault,
+    /// waiting for the next cycle
+    Sleeping,
+}
+
+/// Events that drive the coordinator
+#[derive(Debug, Format)]
+pub enum Event {
+    /// start a measurement cycle, at `unix_time` in seconds since the Unix
+    /// epoch, None if the clock isn't set
+    Start {
+        battery_low: bool,
+        unix_time: Option<u64>,
+    },
+    /// message from a sensor task
+    Sensor(DisplayInfo),
+    /// time has passed, check the sensor deadlines
+    Tick,
+    /// the display has been updated
+    RenderDone,
+}
+
+/// Readings collected in a cycle
+#[derive(Debug, Clone, Format)]
+pub struct Snapshot {
+    pub readings: Readings,
+    pub pm_state: PmState,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot {
+            readings: Readings::new(),
+            pm_state: PmState::WarmingUp,
+        }
+    }
+}
+
+/// Actions for the driving task to carry out
+#[derive(Debug, Clone, Format)]
+pub enum Action {
+    /// power the display for a new cycle
+    StartCycle,
+    StartSensor(SensorId),
+    StopSensor(SensorId),
+    RecordFault(SensorFault),
+    /// show the readings, then send `RenderDone`
+    Render(Snapshot),
+    /// power down the display, wait, then send `Start`
+    Sleep {
+        seconds: u32,
+    },
+}
+
+/// Actions returned from handling one event
+pub type Actions = Vec<Action, { 2 * SENSOR_COUNT + 2 }>;
+
+/// A sensor taking part in the cycle
+struct Pending {
+    id: SensorId,
+    done: bool,
+    deadline_ms: u64,
+}
+
+/// The measurement cycle state machine
+pub struct Coordinator {
+    params: Parameters,
+    state: State,
+    scheduler: AdaptiveScheduler,
+    battery_low: bool,
+    sensors: Vec<Pending, SENSOR_COUNT>,
+    snapshot: Snapshot,
+    /// RTC time the cycle started at
+    unix_time: Option<u64>,
+    pm_history: PmHistory,
+}
+
+impl Coordinator {
+    pub fn new(params: Parameters) -> Self {
+        Coordinator {
+            scheduler: AdaptiveScheduler::new(&params),
+            params,
+            state: State::Idle,
+            battery_low: false,
+            sensors: Vec::new(),
+            snapshot: Snapshot::default(),
+            unix_time: None,
+            pm_history: PmHistory::new(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// time in ms at which a `Tick` is needed, None if only waiting for
+    /// other events
+    pub fn deadline(&self) -> Option<u64> {
+        match self.state {
+            State::Warming | State::Sampling => self
+                .sensors
+                .iter()
+                .filter(|s| !s.done)
+                .map(|s| s.deadline_ms)
+                .min(),
+            _ => None,
+        }
+    }
+
+    /// handle an event at time `now_ms`
+    pub fn handle(&mut self, event: Event, now_ms: u64) -> Actions {
+        let mut actions = Actions::new();
+        match (self.state, event) {
+            (
+                State::Idle | State::Sleeping,
+                Event::Start {
+                    battery_low,
+                    unix_time,
+                },
+            ) => self.start(battery_low, unix_time, now_ms, &mut actions),
+            (State::Warming | State::Sampling, Event::Sensor(info)) => {
+                self.sensor(info, &mut actions);
+                self.check_done(&mut actions);
+            }
+            (State::Warming | State::Sampling, Event::Tick) => {
+                self.tick(now_ms, &mut actions);
+                self.check_done(&mut actions);
+            }
+            (State::Rendering | State::Fault, Event::RenderDone) => {
+                self.sleep(&mut actions);
+            }
+            (state, event) => debug!("coordinator ignored {} in {}", event, state),
+        }
+        actions
+    }
+
+    fn start(
+        &mut self,
+        battery_low: bool,
+        unix_time: Option<u64>,
+        now_ms: u64,
+        actions: &mut Actions,
+    ) {
+        debug!("Start sensor data cycle");
+        self.battery_low = battery_low;
+        self.unix_time = unix_time;
+        self.snapshot = Snapshot::default();
+        self.sensors.clear();
+        actions.push(Action::StartCycle).ok();
+        for info in sensor::SENSORS {
+            // save the power the pm2.5 sensor fan uses when the battery is low
+            if info.id == SensorId::Pm && battery_low && self.params.battery_low_disables_pm25 {
+                info!("battery low, skipping pm2.5");
+                continue;
+            }
+            self.sensors
+                .push(Pending {
+                    id: info.id,
+                    done: false,
+                    deadline_ms: now_ms + info.id.timeout_sec(&self.params) as u64 * 1000,
+                })
+                .ok();
+            actions.push(Action::StartSensor(info.id)).ok();
+        }
+        self.state = if self.sensors.iter().any(|s| s.id == SensorId::Pm) {
+            State::Warming
+        } else {
+            State::Sampling
+        };
+    }
+
+    /// true if the sensor is in the cycle, and hasn't finished
+    fn is_pending(&self, id: SensorId) -> bool {
+        self.sensors.iter().any(|s| s.id == id && !s.done)
+    }
+
+    /// mark a sensor done, and stop it
+    fn finish(&mut self, id: SensorId, actions: &mut Actions) {
+        if let Some(s) = self.sensors.iter_mut().find(|s| s.id == id) {
+            s.done = true;
+            actions.push(Action::StopSensor(id)).ok();
+        }
+    }
+
+    fn sensor(&mut self, info: DisplayInfo, actions: &mut Actions) {
+        match info {
+            // ignore late data from a sensor that timed out
+            DisplayInfo::Data(id, readings) if self.is_pending(id) => {
+                self.snapshot.readings.merge(&readings);
+                self.finish(id, actions);
+            }
+            DisplayInfo::Fault(fault) if self.is_pending(fault.sensor()) => {
+                actions.push(Action::RecordFault(fault)).ok();
+                self.finish(fault.sensor(), actions);
+            }
+            DisplayInfo::Data(..) | DisplayInfo::Fault(_) => {
+                debug!("ignored data from a finished sensor")
+            }
+            DisplayInfo::PmState(pm_state) => {
+                self.snapshot.pm_state = pm_state;
+                if matches!(pm_state, PmState::Sampling | PmState::Unstable) {
+                    self.state = State::Sampling;
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, now_ms: u64, actions: &mut Actions) {
+        for s in self.sensors.iter_mut() {
+            if !s.done && now_ms >= s.deadline_ms {
+                s.done = true;
+                actions
+                    .push(Action::RecordFault(SensorFault::Timeout(s.id)))
+                    .ok();
+                actions.push(Action::StopSensor(s.id)).ok();
+            }
+        }
+    }
+
+    /// render once every sensor is done
+    fn check_done(&mut self, actions: &mut Actions) {
+        if self.sensors.iter().any(|s| !s.done) {
+            return;
+        }
+        self.state = if self.snapshot.readings.is_empty() {
+            State::Fault
+        } else {
+            self.derive();
+            State::Rendering
+        };
+        actions.push(Action::Render(self.snapshot.clone())).ok();
+    }
+
+    /// correct the PM2.5 for humidity, and add the averages
+    ///
+    /// The averages are over RTC hours, so they're left out while the clock
+    /// isn't set.
+    fn derive(&mut self) {
+        let readings = &mut self.snapshot.readings;
+        pm_correction::apply(readings, &self.params);
+        let Some(unix_time) = self.unix_time else {
+            return;
+        };
+        if let Some(pm2_5) = readings.get(Quantity::Pm2_5) {
+            self.pm_history.add(unix_time, pm2_5);
+        }
+        if let Some(nowcast) = self.pm_history.nowcast(unix_time) {
+            readings.set(Quantity::Pm2_5NowCast, nowcast);
+            readings.set(Quantity::AqiNowCast, pm_correction::aqi(nowcast) as f32);
+        }
+        if let Some(average) = self.pm_history.average_24h(unix_time) {
+            readings.set(Quantity::Pm2_5Avg24h, average);
+        }
+    }
+
+    fn sleep(&mut self, actions: &mut Actions) {
+        // after a fault, retry at the minimum interval
+        let mut seconds = if self.state == State::Fault {
+            self.params.screen_display_min_refresh_sec
+        } else {
+            let readings = &self.snapshot.readings;
+            self.scheduler.next_interval(
+                &self.params,
+                readings.get(Quantity::Pm2_5).map(|v| v as u16),
+                readings.get(Quantity::Iaq).map(|v| v as u16),
+            )
+        };
+        if self.battery_low {
+            seconds *= self.params.battery_low_refresh_multiplier;
+        }
+        self.state = State::Sleeping;
+        actions.push(Action::Sleep { seconds }).ok();
+    }
+}
@@ -0,0 +1,136 @@
+//! Crash records kept across a reset
+//!
+//! With the `production` feature, panics and hard faults don't halt the
+//! MCU waiting for a debugger. The panic message and location, or the
+//! faulting PC, LR and CFSR, are stored in RAM that isn't initialized at
+//! startup, and the MCU is reset. The record is read back on the next boot.
+
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use defmt::Format;
+use heapless::String;
+
+/// Length of the stored panic message
+pub const MESSAGE_LEN: usize = 96;
+
+/// What caused the last reset
+#[derive(Debug, Clone, Format)]
+pub enum CrashRecord {
+    /// panic message, including the location
+    Panic(String<MESSAGE_LEN>),
+    /// hard fault with registers from the exception frame
+    HardFault { pc: u32, lr: u32, cfsr: u32 },
+}
+
+const PANIC_MAGIC: u32 = 0xC4A5_0001;
+const FAULT_MAGIC: u32 = 0xC4A5_0002;
+
+#[repr(C)]
+struct RawRecord {
+    magic: u32,
+    pc: u32,
+    lr: u32,
+    cfsr: u32,
+    len: u32,
+    message: [u8; MESSAGE_LEN],
+}
+
+#[link_section = ".uninit.CRASH_RECORD"]
+static mut CRASH_RECORD: MaybeUninit<RawRecord> = MaybeUninit::uninit();
+
+/// read and clear the record of a crash before the last reset
+pub fn take() -> Option<CrashRecord> {
+    use core::ptr::{addr_of, addr_of_mut};
+    // SAFETY: only called once at startup. The memory isn't initialized at
+    // reset, so it's only read through raw pointers, never referenced.
+    // Every bit pattern is valid for the fields, and the magic number
+    // guards against random contents
+    let raw = unsafe { CRASH_RECORD.as_mut_ptr() };
+    let magic = unsafe {
+        let magic = addr_of!((*raw).magic).read_volatile();
+        addr_of_mut!((*raw).magic).write_volatile(0);
+        magic
+    };
+    if magic == FAULT_MAGIC {
+        // SAFETY: as above
+        return Some(unsafe {
+            CrashRecord::HardFault {
+                pc: addr_of!((*raw).pc).read_volatile(),
+                lr: addr_of!((*raw).lr).read_volatile(),
+                cfsr: addr_of!((*raw).cfsr).read_volatile(),
+            }
+        });
+    }
+    if magic != PANIC_MAGIC {
+        return None;
+    }
+    // SAFETY: as above
+    let (len, bytes) = unsafe {
+        (
+            addr_of!((*raw).len).read_volatile(),
+            addr_of!((*raw).message).read_volatile(),
+        )
+    };
+    let len = (len as usize).min(MESSAGE_LEN);
+    let mut message = String::new();
+    // the message was truncated on a byte boundary, drop anything invalid
+    let text = match core::str::from_utf8(&bytes[..len]) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    message.push_str(text).ok();
+    Some(CrashRecord::Panic(message))
+}
+
+/// formats into the record message, truncating what doesn't fit
+struct MessageWriter<'a> {
+    buf: &'a mut [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MESSAGE_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// store a panic message and reset
+pub fn record_panic(info: &core::panic::PanicInfo) -> ! {
+    use core::ptr::addr_of_mut;
+    cortex_m::interrupt::disable();
+    let mut buf = [0u8; MESSAGE_LEN];
+    let mut w = MessageWriter {
+        buf: &mut buf,
+        len: 0,
+    };
+    write!(w, "{}", info).ok();
+    let len = w.len as u32;
+    // SAFETY: interrupts are disabled, and we never return. The record is
+    // only written through raw pointers, as it may not be initialized
+    unsafe {
+        let raw = CRASH_RECORD.as_mut_ptr();
+        addr_of_mut!((*raw).message).write_volatile(buf);
+        addr_of_mut!((*raw).len).write_volatile(len);
+        addr_of_mut!((*raw).magic).write_volatile(PANIC_MAGIC);
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// store the fault registers and reset
+pub fn record_hard_fault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    use core::ptr::addr_of_mut;
+    // SAFETY: in the hard fault handler, nothing else is running. The
+    // record is only written through raw pointers, as it may not be
+    // initialized
+    unsafe {
+        let raw = CRASH_RECORD.as_mut_ptr();
+        addr_of_mut!((*raw).pc).write_volatile(frame.pc());
+        addr_of_mut!((*raw).lr).write_volatile(frame.lr());
+        addr_of_mut!((*raw).cfsr).write_volatile((*cortex_m::peripheral::SCB::PTR).cfsr.read());
+        addr_of_mut!((*raw).magic).write_volatile(FAULT_MAGIC);
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,63 @@
+//! Fault accounting for diagnostics
+
+use crate::{
+    env_sensor::BmeFault,
+    pms7003_device::PmFault,
+    scd4x_device::Scd4xFault,
+    sensor::{SensorId, SENSOR_COUNT},
+};
+use core::cell::Cell;
+use defmt::{warn, Format};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+/// Sensor faults seen during measurement cycles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SensorFault {
+    Env(BmeFault),
+    Pm(PmFault),
+    Scd4x(Scd4xFault),
+    /// no response from the sensor task within the sensor's timeout
+    Timeout(SensorId),
+}
+
+impl SensorFault {
+    /// the sensor that failed
+    pub fn sensor(&self) -> SensorId {
+        match self {
+            SensorFault::Env(_) => SensorId::Env,
+            SensorFault::Pm(_) => SensorId::Pm,
+            SensorFault::Scd4x(_) => SensorId::Scd4x,
+            SensorFault::Timeout(id) => *id,
+        }
+    }
+}
+
+/// Counts of faults since boot, by `SensorId`, and the most recent one
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct FaultLog {
+    pub faults: [u32; SENSOR_COUNT],
+    pub last: Option<SensorFault>,
+}
+
+static FAULT_LOG: Mutex<CriticalSectionRawMutex, Cell<FaultLog>> =
+    Mutex::new(Cell::new(FaultLog {
+        faults: [0; SENSOR_COUNT],
+        last: None,
+    }));
+
+/// record a sensor fault
+pub fn record(fault: SensorFault) {
+    warn!("sensor fault: {}", fault);
+    FAULT_LOG.lock(|log| {
+        let mut l = log.get();
+        let count = &mut l.faults[fault.sensor() as usize];
+        *count = count.wrapping_add(1);
+        l.last = Some(fault);
+        log.set(l);
+    });
+}
+
+/// get the faults recorded so far
+pub fn fault_log() -> FaultLog {
+    FAULT_LOG.lock(|log| log.get())
+}
@@ -0,0 +1,581 @@
+//! Environmental sensor models
+//!
+//! The environmental sensor on I2C1 is chosen by cargo feature:
+//!
+//! - `bme680` Bosch BME680, temperature, humidity, pressure and gas
+//! - `bme688` Bosch BME688, as the BME680, stepping the gas heater through
+//!   a profile of temperatures each cycle
+//! - `bme280` Bosch BME280, temperature, humidity and pressure
+//! - `sht4x` Sensirion SHT40/SHT41/SHT45, temperature and humidity
+//!
+//! Each model produces an [`EnvData`], with the values it doesn't measure
+//! marked absent, so they're left out of the readings rather than shown as
+//! zero.
+
+#[cfg(any(feature = "bme680", feature = "bme688"))]
+use crate::bme680_device::BmeDevice;
+#[cfg(feature = "sht4x")]
+use crate::scd4x_device::decode_words;
+use crate::sensor::{Quantity, QuantitySpec, Readings, SensorId, SensorInfo, Unit};
+#[cfg(any(feature = "bme280", feature = "sht4x"))]
+use core::fmt;
+use defmt::Format;
+#[cfg(any(feature = "bme280", feature = "sht4x"))]
+use defmt::{debug, error, Debug2Format};
+#[cfg(any(feature = "bme280", feature = "sht4x"))]
+use embassy_time::Delay;
+#[cfg(any(feature = "bme280", feature = "sht4x"))]
+use embedded_hal::blocking::delay::DelayMs;
+#[cfg(feature = "sht4x")]
+use embedded_hal::blocking::i2c::Read;
+#[cfg(any(feature = "bme280", feature = "sht4x"))]
+use embedded_hal::blocking::i2c::Write;
+#[cfg(feature = "bme280")]
+use embedded_hal::blocking::i2c::WriteRead;
+
+#[cfg(any(
+    all(feature = "bme680", feature = "bme688"),
+    all(feature = "bme680", feature = "bme280"),
+    all(feature = "bme680", feature = "sht4x"),
+    all(feature = "bme688", feature = "bme280"),
+    all(feature = "bme688", feature = "sht4x"),
+    all(feature = "bme280", feature = "sht4x"),
+))]
+compile_error!("only one environmental sensor feature can be enabled");
+
+/// The fitted sensor
+#[cfg(any(feature = "bme680", feature = "bme688"))]
+pub type EnvDevice = BmeDevice<crate::i2c_bus::I2cHandle>;
+#[cfg(feature = "bme280")]
+pub type EnvDevice = Bme280<crate::i2c_bus::I2cHandle>;
+#[cfg(feature = "sht4x")]
+pub type EnvDevice = Sht4x<crate::i2c_bus::I2cHandle>;
+
+/// Name of the fitted sensor
+#[cfg(not(any(feature = "bme688", feature = "bme280", feature = "sht4x")))]
+pub const MODEL_NAME: &str = "BME680";
+#[cfg(feature = "bme688")]
+pub const MODEL_NAME: &str = "BME688";
+#[cfg(feature = "bme280")]
+pub const MODEL_NAME: &str = "BME280";
+#[cfg(feature = "sht4x")]
+pub const MODEL_NAME: &str = "SHT4x";
+
+const TEMPERATURE: QuantitySpec = QuantitySpec {
+    kind: Quantity::Temperature,
+    unit: Unit::Celsius,
+    precision: 0,
+    output_precision: 1,
+    label: "Temp",
+    on_screen: false,
+};
+
+const HUMIDITY: QuantitySpec = QuantitySpec {
+    kind: Quantity::Humidity,
+    unit: Unit::Percent,
+    precision: 0,
+    output_precision: 1,
+    label: "Humidity",
+    on_screen: true,
+};
+
+const PRESSURE: QuantitySpec = QuantitySpec {
+    kind: Quantity::Pressure,
+    unit: Unit::HectoPascal,
+    precision: 0,
+    output_precision: 1,
+    label: "Pressure",
+    on_screen: true,
+};
+
+const GAS_RESISTANCE: QuantitySpec = QuantitySpec {
+    kind: Quantity::GasResistance,
+    unit: Unit::Ohm,
+    precision: 0,
+    output_precision: 0,
+    label: "Gas",
+    on_screen: true,
+};
+
+const IAQ: QuantitySpec = QuantitySpec {
+    kind: Quantity::Iaq,
+    unit: Unit::Index,
+    precision: 0,
+    output_precision: 0,
+    label: "IAQ",
+    on_screen: false,
+};
+
+/// The quantities the fitted sensor measures
+#[cfg(not(any(feature = "bme280", feature = "sht4x")))]
+pub static SENSOR: SensorInfo = SensorInfo {
+    id: SensorId::Env,
+    quantities: &[TEMPERATURE, HUMIDITY, PRESSURE, GAS_RESISTANCE, IAQ],
+};
+#[cfg(feature = "bme280")]
+pub static SENSOR: SensorInfo = SensorInfo {
+    id: SensorId::Env,
+    quantities: &[TEMPERATURE, HUMIDITY, PRESSURE],
+};
+#[cfg(feature = "sht4x")]
+pub static SENSOR: SensorInfo = SensorInfo {
+    id: SensorId::Env,
+    quantities: &[TEMPERATURE, HUMIDITY],
+};
+
+/// Errors from the environmental sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BmeFault {
+    /// device didn't respond, or couldn't be configured
+    Init,
+    /// reading data failed
+    Read,
+}
+
+/// Data sensed by the environmental sensor
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct EnvData {
+    pub temperature: f32,
+    pub humidity: f32,
+    /// pressure in hPa, None if the sensor doesn't measure it
+    pub pressure: Option<f32>,
+    /// gas resistance in ohms, None if the sensor has no gas sensor, or the
+    /// reading isn't valid
+    pub gas_resistance: Option<u32>,
+}
+
+impl EnvData {
+    /// the readings, leaving out the values that are absent
+    pub fn readings(&self, gas_baseline_ohm: u32) -> Readings {
+        let mut r = Readings::new();
+        r.set(Quantity::Temperature, self.temperature);
+        r.set(Quantity::Humidity, self.humidity);
+        if let Some(pressure) = self.pressure {
+            r.set(Quantity::Pressure, pressure);
+        }
+        if let Some(gas) = self.gas_resistance {
+            r.set(Quantity::GasResistance, gas as f32);
+        }
+        if let Some(iaq) = self.iaq(gas_baseline_ohm) {
+            r.set(Quantity::Iaq, iaq as f32);
+        }
+        r
+    }
+
+    /// Estimate an indoor air quality index from gas resistance and humidity
+    ///
+    /// 0 is excellent, 500 is extremely polluted. Humidity contributes 25%
+    /// of the score, based on distance from 40%RH, and gas resistance 75%,
+    /// relative to the clean air `gas_baseline_ohm`. Returns None if there
+    /// is no gas reading.
+    pub fn iaq(&self, gas_baseline_ohm: u32) -> Option<u16> {
+        let gas_resistance = self.gas_resistance?;
+        if gas_baseline_ohm == 0 {
+            return None;
+        }
+        const HUMIDITY_BASELINE: f32 = 40.0;
+        let humidity_score = if self.humidity < HUMIDITY_BASELINE {
+            self.humidity / HUMIDITY_BASELINE * 25.0
+        } else {
+            (100.0 - self.humidity) / (100.0 - HUMIDITY_BASELINE) * 25.0
+        }
+        .clamp(0.0, 25.0);
+        let gas_score = (gas_resistance as f32 / gas_baseline_ohm as f32 * 75.0).min(75.0);
+        Some(((100.0 - humidity_score - gas_score) * 5.0) as u16)
+    }
+}
+
+/// Most steps in a gas heater profile
+pub const MAX_HEATER_STEPS: usize = 4;
+
+/// A gas heater temperature, held for a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct HeaterStep {
+    pub temperature_c: u16,
+    pub duration_ms: u16,
+}
+
+/// The gas heater temperatures a BME680 or BME688 steps through each cycle
+///
+/// A forced measurement is taken at each step, the gas resistance at
+/// `gas_step` is the one reported, and used for the IAQ.
+#[derive(Debug, Clone, Copy, Format)]
+pub struct HeaterProfile {
+    pub steps: [HeaterStep; MAX_HEATER_STEPS],
+    /// number of steps used
+    pub len: u8,
+    pub gas_step: u8,
+}
+
+impl HeaterProfile {
+    /// the steps used, at least one
+    pub fn steps(&self) -> &[HeaterStep] {
+        &self.steps[..(self.len as usize).clamp(1, MAX_HEATER_STEPS)]
+    }
+}
+
+/// The BME680 heater setting, used for the single step
+const BME680_STEP: HeaterStep = HeaterStep {
+    temperature_c: 320,
+    duration_ms: 1500,
+};
+
+/// The default heater profile, a single step except for the BME688
+#[cfg(not(feature = "bme688"))]
+pub const DEFAULT_HEATER_PROFILE: HeaterProfile = HeaterProfile {
+    steps: [BME680_STEP; MAX_HEATER_STEPS],
+    len: 1,
+    gas_step: 0,
+};
+#[cfg(feature = "bme688")]
+pub const DEFAULT_HEATER_PROFILE: HeaterProfile = HeaterProfile {
+    steps: [
+        HeaterStep {
+            temperature_c: 200,
+            duration_ms: 150,
+        },
+        HeaterStep {
+            temperature_c: 250,
+            duration_ms: 150,
+        },
+        BME680_STEP,
+        HeaterStep {
+            temperature_c: 400,
+            duration_ms: 150,
+        },
+    ],
+    len: 4,
+    gas_step: 2,
+};
+
+/// Variant id of the BME688, in the variant id register, the BME680 is 0
+pub const BME688_VARIANT_ID: u8 = 0x01;
+
+/// decode the gas registers of a BME688, 0x2C and 0x2D, returning the gas
+/// resistance in ohms
+///
+/// The BME688 reports gas in different registers from the BME680, with its
+/// own valid and heater stable bits and resistance formula. Returns None
+/// unless the reading is valid and the heater was stable.
+pub fn bme688_gas_resistance(regs: &[u8; 2]) -> Option<u32> {
+    const GAS_VALID: u8 = 0x20;
+    const HEAT_STABLE: u8 = 0x10;
+    if regs[1] & GAS_VALID == 0 || regs[1] & HEAT_STABLE == 0 {
+        return None;
+    }
+    let adc = (regs[0] as i32) << 2 | (regs[1] >> 6) as i32;
+    let range = regs[1] & 0x0f;
+    let var1 = (262_144u32 >> range) as f32;
+    let var2 = (4096 + (adc - 512) * 3) as f32;
+    Some((1_000_000.0 * var1 / var2) as u32)
+}
+
+/// I2C address of the BME280, SDO pulled high as on the Adafruit breakout
+#[cfg(feature = "bme280")]
+const BME280_ADDRESS: u8 = 0x77;
+
+/// BME280 registers
+#[cfg(feature = "bme280")]
+mod reg {
+    pub const CHIP_ID: u8 = 0xd0;
+    pub const RESET: u8 = 0xe0;
+    pub const CALIB_00: u8 = 0x88;
+    pub const CALIB_26: u8 = 0xe1;
+    pub const CTRL_HUM: u8 = 0xf2;
+    pub const CTRL_MEAS: u8 = 0xf4;
+    pub const CONFIG: u8 = 0xf5;
+    pub const DATA: u8 = 0xf7;
+}
+
+#[cfg(feature = "bme280")]
+const BME280_CHIP_ID: u8 = 0x60;
+
+/// Compensation values stored in the BME280
+#[derive(Debug, Clone, Copy, Format)]
+pub struct Bme280Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+impl Bme280Calibration {
+    /// decode registers 0x88 to 0xa1, and 0xe1 to 0xe7
+    pub fn from_registers(calib: &[u8; 26], calib_h: &[u8; 7]) -> Self {
+        let u = |i: usize| u16::from_le_bytes([calib[i], calib[i + 1]]);
+        let s = |i: usize| u(i) as i16;
+        Bme280Calibration {
+            t1: u(0),
+            t2: s(2),
+            t3: s(4),
+            p1: u(6),
+            p2: s(8),
+            p3: s(10),
+            p4: s(12),
+            p5: s(14),
+            p6: s(16),
+            p7: s(18),
+            p8: s(20),
+            p9: s(22),
+            h1: calib[25],
+            h2: i16::from_le_bytes([calib_h[0], calib_h[1]]),
+            h3: calib_h[2],
+            // 12 bit values sharing 0xe5
+            h4: ((calib_h[3] as i8 as i16) << 4) | (calib_h[4] & 0x0f) as i16,
+            h5: ((calib_h[5] as i8 as i16) << 4) | (calib_h[4] >> 4) as i16,
+            h6: calib_h[6] as i8,
+        }
+    }
+
+    /// compensate the raw readings, returning the temperature in C, the
+    /// pressure in hPa and the humidity in %RH
+    ///
+    /// these are the floating point formulas from the datasheet
+    pub fn compensate(&self, adc_t: u32, adc_p: u32, adc_h: u16) -> (f32, f32, f32) {
+        let (adc_t, adc_p, adc_h) = (adc_t as f64, adc_p as f64, adc_h as f64);
+
+        let var1 = (adc_t / 16384.0 - self.t1 as f64 / 1024.0) * self.t2 as f64;
+        let var2 = adc_t / 131072.0 - self.t1 as f64 / 8192.0;
+        let t_fine = var1 + var2 * var2 * self.t3 as f64;
+        let temperature = t_fine / 5120.0;
+
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * self.p6 as f64 / 32768.0;
+        var2 += var1 * self.p5 as f64 * 2.0;
+        var2 = var2 / 4.0 + self.p4 as f64 * 65536.0;
+        var1 = (self.p3 as f64 * var1 * var1 / 524288.0 + self.p2 as f64 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * self.p1 as f64;
+        let pressure = if var1 == 0.0 {
+            0.0
+        } else {
+            let p = (1048576.0 - adc_p - var2 / 4096.0) * 6250.0 / var1;
+            let var1 = self.p9 as f64 * p * p / 2147483648.0;
+            let var2 = p * self.p8 as f64 / 32768.0;
+            p + (var1 + var2 + self.p7 as f64) / 16.0
+        };
+
+        let h = t_fine - 76800.0;
+        let h = (adc_h - (self.h4 as f64 * 64.0 + self.h5 as f64 / 16384.0 * h))
+            * (self.h2 as f64 / 65536.0
+                * (1.0
+                    + self.h6 as f64 / 67108864.0 * h * (1.0 + self.h3 as f64 / 67108864.0 * h)));
+        let humidity = (h * (1.0 - self.h1 as f64 * h / 524288.0)).clamp(0.0, 100.0);
+
+        (
+            temperature as f32,
+            (pressure / 100.0) as f32,
+            humidity as f32,
+        )
+    }
+}
+
+/// A BME280, measuring in forced mode
+#[cfg(feature = "bme280")]
+pub struct Bme280<I2C> {
+    i2c: I2C,
+    calibration: Option<Bme280Calibration>,
+}
+
+#[cfg(feature = "bme280")]
+impl<I2C, E> Bme280<I2C>
+where
+    I2C: WriteRead<Error = E> + Write<Error = E>,
+    E: fmt::Debug,
+{
+    /// Create a new Bme280, checking the chip id
+    pub fn new(i2c: I2C) -> Result<Self, BmeFault> {
+        let mut dev = Bme280 {
+            i2c,
+            calibration: None,
+        };
+        let mut id = [0u8; 1];
+        dev.read_registers(reg::CHIP_ID, &mut id, BmeFault::Init)?;
+        if id[0] != BME280_CHIP_ID {
+            error!("bme280 chip id {=u8:#x}", id[0]);
+            return Err(BmeFault::Init);
+        }
+        Ok(dev)
+    }
+
+    /// reset the sensor, and read its calibration
+    pub fn init(&mut self) -> Result<(), BmeFault> {
+        self.write_register(reg::RESET, 0xb6, BmeFault::Init)?;
+        Delay.delay_ms(3u8);
+        let mut calib = [0u8; 26];
+        let mut calib_h = [0u8; 7];
+        self.read_registers(reg::CALIB_00, &mut calib, BmeFault::Init)?;
+        self.read_registers(reg::CALIB_26, &mut calib_h, BmeFault::Init)?;
+        self.calibration = Some(Bme280Calibration::from_registers(&calib, &calib_h));
+        // IIR filter coefficient 2
+        self.write_register(reg::CONFIG, 0b001 << 2, BmeFault::Init)?;
+        debug!("bme280 initialized");
+        Ok(())
+    }
+
+    /// take a forced measurement
+    pub fn read(&mut self) -> Result<EnvData, BmeFault> {
+        let calibration = self.calibration.ok_or(BmeFault::Read)?;
+        // humidity 2x, temperature 8x, pressure 4x oversampling, as with
+        // the BME680, then forced mode
+        self.write_register(reg::CTRL_HUM, 0b010, BmeFault::Read)?;
+        self.write_register(
+            reg::CTRL_MEAS,
+            (0b100 << 5) | (0b011 << 2) | 0b01,
+            BmeFault::Read,
+        )?;
+        Delay.delay_ms(40u8);
+        let mut d = [0u8; 8];
+        self.read_registers(reg::DATA, &mut d, BmeFault::Read)?;
+        let adc_p = (d[0] as u32) << 12 | (d[1] as u32) << 4 | (d[2] as u32) >> 4;
+        let adc_t = (d[3] as u32) << 12 | (d[4] as u32) << 4 | (d[5] as u32) >> 4;
+        let adc_h = u16::from_be_bytes([d[6], d[7]]);
+        let (temperature, pressure, humidity) = calibration.compensate(adc_t, adc_p, adc_h);
+        let data = EnvData {
+            temperature,
+            humidity,
+            pressure: Some(pressure),
+            gas_resistance: None,
+        };
+        debug!("bme280: {}", data);
+        Ok(data)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8, fault: BmeFault) -> Result<(), BmeFault> {
+        self.i2c
+            .write(BME280_ADDRESS, &[register, value])
+            .map_err(|e| {
+                error!("bme280 write {=u8:#x}: {}", register, Debug2Format(&e));
+                fault
+            })
+    }
+
+    fn read_registers(
+        &mut self,
+        register: u8,
+        buf: &mut [u8],
+        fault: BmeFault,
+    ) -> Result<(), BmeFault> {
+        self.i2c
+            .write_read(BME280_ADDRESS, &[register], buf)
+            .map_err(|e| {
+                error!("bme280 read {=u8:#x}: {}", register, Debug2Format(&e));
+                fault
+            })
+    }
+}
+
+/// I2C address of the SHT40, SHT41 and SHT45
+#[cfg(feature = "sht4x")]
+const SHT4X_ADDRESS: u8 = 0x44;
+
+/// SHT4x commands
+#[cfg(feature = "sht4x")]
+mod sht_cmd {
+    pub const MEASURE_HIGH_PRECISION: u8 = 0xfd;
+    pub const READ_SERIAL_NUMBER: u8 = 0x89;
+    pub const SOFT_RESET: u8 = 0x94;
+}
+
+/// convert a SHT4x measurement, returning the temperature in C and the
+/// humidity in %RH
+pub fn sht4x_convert(words: &[u16; 2]) -> (f32, f32) {
+    let temperature = -45.0 + 175.0 * words[0] as f32 / 65535.0;
+    let humidity = (-6.0 + 125.0 * words[1] as f32 / 65535.0).clamp(0.0, 100.0);
+    (temperature, humidity)
+}
+
+/// A SHT4x, measuring temperature and humidity
+#[cfg(feature = "sht4x")]
+pub struct Sht4x<I2C> {
+    i2c: I2C,
+}
+
+#[cfg(feature = "sht4x")]
+impl<I2C, E> Sht4x<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+    E: fmt::Debug,
+{
+    /// Create a new Sht4x, reading its serial number
+    pub fn new(i2c: I2C) -> Result<Self, BmeFault> {
+        let mut dev = Sht4x { i2c };
+        let mut serial = [0u16; 2];
+        dev.command(sht_cmd::READ_SERIAL_NUMBER, 1, &mut serial, BmeFault::Init)?;
+        debug!(
+            "sht4x serial number {=u16:#x}{=u16:04x}",
+            serial[0], serial[1]
+        );
+        Ok(dev)
+    }
+
+    pub fn init(&mut self) -> Result<(), BmeFault> {
+        self.i2c
+            .write(SHT4X_ADDRESS, &[sht_cmd::SOFT_RESET])
+            .map_err(|e| bus_error(e, sht_cmd::SOFT_RESET, BmeFault::Init))?;
+        Delay.delay_ms(1u8);
+        debug!("sht4x initialized");
+        Ok(())
+    }
+
+    /// take a high precision measurement
+    pub fn read(&mut self) -> Result<EnvData, BmeFault> {
+        let mut words = [0u16; 2];
+        self.command(
+            sht_cmd::MEASURE_HIGH_PRECISION,
+            10,
+            &mut words,
+            BmeFault::Read,
+        )?;
+        let (temperature, humidity) = sht4x_convert(&words);
+        let data = EnvData {
+            temperature,
+            humidity,
+            pressure: None,
+            gas_resistance: None,
+        };
+        debug!("sht4x: {}", data);
+        Ok(data)
+    }
+
+    /// send a command, wait, and read the words of the reply
+    fn command(
+        &mut self,
+        command: u8,
+        wait_ms: u8,
+        words: &mut [u16; 2],
+        fault: BmeFault,
+    ) -> Result<(), BmeFault> {
+        self.i2c
+            .write(SHT4X_ADDRESS, &[command])
+            .map_err(|e| bus_error(e, command, fault))?;
+        Delay.delay_ms(wait_ms);
+        let mut buf = [0u8; 6];
+        self.i2c
+            .read(SHT4X_ADDRESS, &mut buf)
+            .map_err(|e| bus_error(e, command, fault))?;
+        decode_words(&buf, words).map_err(|_| {
+            error!("sht4x command {=u8:#x}: crc error", command);
+            fault
+        })
+    }
+}
+
+#[cfg(feature = "sht4x")]
+fn bus_error<E: fmt::Debug>(e: E, command: u8, fault: BmeFault) -> BmeFault {
+    error!("sht4x command {=u8:#x}: {}", command, Debug2Format(&e));
+    fault
+}
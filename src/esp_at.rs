@@ -0,0 +1,139 @@
+//! ESP8266/ESP32 WiFi coprocessor, running Espressif's AT firmware
+//!
+//! Commands are sent as lines, and the coprocessor's response lines are
+//! read until the final `OK`, or `ERROR` or `FAIL`. Other lines, such as
+//! the command echo and unsolicited reports, are logged and skipped. The
+//! client works over any `embedded_io_async` transport, and doesn't time
+//! out itself, callers wrap the exchanges in a timeout.
+
+use core::fmt::Write as _;
+use defmt::{debug, Format};
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+
+/// Longest response line kept, longer lines are truncated
+const LINE_LEN: usize = 128;
+
+/// Longest command
+pub const COMMAND_LEN: usize = 256;
+
+/// A command line, without the CR LF
+pub type Command = String<COMMAND_LEN>;
+
+/// Errors from the coprocessor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum AtError {
+    /// the command failed, with `ERROR` or `FAIL`
+    Failed,
+    /// the command didn't finish in time
+    Timeout,
+    /// the command is longer than `COMMAND_LEN`
+    Overflow,
+    /// the transport failed
+    Io,
+}
+
+/// write a string argument, quoted, escaping the characters AT firmware
+/// treats as special
+pub fn write_quoted(out: &mut Command, s: &str) -> Result<(), AtError> {
+    let mut write = || -> core::fmt::Result {
+        out.write_char('"')?;
+        for c in s.chars() {
+            if matches!(c, '"' | ',' | '\\') {
+                out.write_char('\\')?;
+            }
+            out.write_char(c)?;
+        }
+        out.write_char('"')
+    };
+    write().map_err(|_| AtError::Overflow)
+}
+
+/// An AT command client
+pub struct EspAt<U> {
+    uart: U,
+    line: Vec<u8, LINE_LEN>,
+}
+
+impl<U: Read + Write> EspAt<U> {
+    pub fn new(uart: U) -> Self {
+        EspAt {
+            uart,
+            line: Vec::new(),
+        }
+    }
+
+    /// send a command and wait for `OK`
+    pub async fn command(&mut self, command: &str) -> Result<(), AtError> {
+        self.send(command.as_bytes()).await?;
+        self.send(b"\r\n").await?;
+        self.wait_for("OK").await
+    }
+
+    /// publish an MQTT message on connection 0, at QoS 0
+    ///
+    /// The payload is sent raw after the `>` prompt, so it needn't be
+    /// escaped.
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<(), AtError> {
+        let mut command = Command::new();
+        command
+            .push_str("AT+MQTTPUBRAW=0,")
+            .map_err(|_| AtError::Overflow)?;
+        write_quoted(&mut command, topic)?;
+        write!(command, ",{},0,{}", payload.len(), retain as u8).map_err(|_| AtError::Overflow)?;
+        self.command(&command).await?;
+        self.wait_for(">").await?;
+        self.send(payload).await?;
+        self.wait_for("+MQTTPUB:OK").await
+    }
+
+    async fn send(&mut self, bytes: &[u8]) -> Result<(), AtError> {
+        self.uart.write_all(bytes).await.map_err(|_| AtError::Io)?;
+        self.uart.flush().await.map_err(|_| AtError::Io)
+    }
+
+    /// read response lines until `done`
+    async fn wait_for(&mut self, done: &str) -> Result<(), AtError> {
+        loop {
+            self.next_line().await?;
+            let line = core::str::from_utf8(&self.line).unwrap_or("");
+            if line == done {
+                return Ok(());
+            }
+            if line == "ERROR" || line == "FAIL" || line.ends_with(":FAIL") {
+                debug!("at failed: {}", line);
+                return Err(AtError::Failed);
+            }
+            if !line.is_empty() {
+                debug!("at: {}", line);
+            }
+        }
+    }
+
+    /// read the next line, or the `>` prompt, which isn't followed by a
+    /// line end
+    async fn next_line(&mut self) -> Result<(), AtError> {
+        self.line.clear();
+        let mut b = [0u8; 1];
+        loop {
+            if self.uart.read(&mut b).await.map_err(|_| AtError::Io)? == 0 {
+                return Err(AtError::Io);
+            }
+            match b[0] {
+                b'\n' => return Ok(()),
+                b'\r' => (),
+                b => {
+                    self.line.push(b).ok();
+                    if self.line[..] == b">"[..] {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Shared I2C1 bus
+//!
+//! Each sensor on I2C1 gets an [`I2cHandle`], a mutex protected handle to
+//! the bus, so several drivers can own a handle at once. The handle counts
+//! the transfers to its device, and the errors, for diagnostics.
+
+use core::cell::{Cell, RefCell};
+use defmt::{warn, Debug2Format, Format};
+use embassy_embedded_hal::shared_bus::{blocking::i2c::I2cDevice, I2cDeviceError};
+use embassy_stm32::{i2c, peripherals};
+use embassy_sync::blocking_mutex::{
+    raw::{CriticalSectionRawMutex, NoopRawMutex},
+    Mutex,
+};
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use static_cell::StaticCell;
+
+/// The devices on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BusDevice {
+    /// the environmental sensor
+    Env,
+    Scd4x,
+    Sps30,
+}
+
+const DEVICE_COUNT: usize = 3;
+
+/// The I2C1 peripheral
+pub type I2c1 = i2c::I2c<'static, peripherals::I2C1>;
+
+/// The bus, shared by the tasks on the thread executor
+pub type I2cBus = Mutex<NoopRawMutex, RefCell<I2c1>>;
+
+static I2C1_BUS: StaticCell<I2cBus> = StaticCell::new();
+
+/// share the I2C1 peripheral, only call once
+pub fn init(i2c: I2c1) -> &'static I2cBus {
+    I2C1_BUS.init(Mutex::new(RefCell::new(i2c)))
+}
+
+/// Transfer counts for a device
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct BusStats {
+    pub transfers: u32,
+    pub errors: u32,
+    /// errors since the last successful transfer
+    pub consecutive_errors: u32,
+}
+
+static BUS_STATS: Mutex<CriticalSectionRawMutex, Cell<[BusStats; DEVICE_COUNT]>> =
+    Mutex::new(Cell::new(
+        [BusStats {
+            transfers: 0,
+            errors: 0,
+            consecutive_errors: 0,
+        }; DEVICE_COUNT],
+    ));
+
+/// get the transfer counts for a device
+pub fn bus_stats(device: BusDevice) -> BusStats {
+    BUS_STATS.lock(|s| s.get()[device as usize])
+}
+
+/// Handle to a device on the shared bus
+pub struct I2cHandle {
+    bus: I2cDevice<'static, NoopRawMutex, I2c1>,
+    device: BusDevice,
+}
+
+impl I2cHandle {
+    pub fn new(bus: &'static I2cBus, device: BusDevice) -> Self {
+        I2cHandle {
+            bus: I2cDevice::new(bus),
+            device,
+        }
+    }
+
+    /// count a transfer, and its error if it failed
+    fn record<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        BUS_STATS.lock(|s| {
+            let mut stats = s.get();
+            let st = &mut stats[self.device as usize];
+            st.transfers = st.transfers.wrapping_add(1);
+            if result.is_ok() {
+                st.consecutive_errors = 0;
+            } else {
+                st.errors = st.errors.wrapping_add(1);
+                st.consecutive_errors = st.consecutive_errors.saturating_add(1);
+            }
+            s.set(stats);
+        });
+        if let Err(e) = &result {
+            warn!("i2c {}: {}", self.device, Debug2Format(e));
+        }
+        result
+    }
+}
+
+/// Errors from a transfer on the shared bus
+pub type Error = I2cDeviceError<i2c::Error>;
+
+impl Write for I2cHandle {
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        let result = self.bus.write(address, bytes);
+        self.record(result)
+    }
+}
+
+impl Read for I2cHandle {
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        let result = self.bus.read(address, buffer);
+        self.record(result)
+    }
+}
+
+impl WriteRead for I2cHandle {
+    type Error = Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        let result = self.bus.write_read(address, bytes, buffer);
+        self.record(result)
+    }
+}
@@ -10,28 +10,80 @@ use defmt_rtt as _; // global logger
 // our hal
 use embassy_stm32 as _;
 
+#[cfg(not(feature = "production"))]
 use panic_probe as _;
 
 // library modules
+pub mod alert;
+pub mod battery;
+#[cfg(any(feature = "bme680", feature = "bme688"))]
 pub mod bme680_device;
+pub mod console;
+pub mod coordinator;
+pub mod crash;
+pub mod diagnostics;
+pub mod env_sensor;
+#[cfg(feature = "mqtt")]
+pub mod esp_at;
+pub mod i2c_bus;
+pub mod low_power;
+pub mod measurement;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod nowcast;
 pub mod parameter;
+pub mod pm_correction;
+pub mod pm_sensor;
 pub mod pms7003_device;
+pub mod rtc;
+pub mod scd4x_device;
+pub mod scheduler;
 pub mod screen;
+pub mod sensor;
+pub mod serializer;
+pub mod stats;
+pub mod watchdog;
+
+// log timestamps are wall clock time from the RTC, or the epoch if it isn't set
+defmt::timestamp!("{=u64:iso8601s}", rtc::unix_time());
 
 /// Enumeration passed on channel to display controller
 #[derive(Debug, Format)]
 pub enum DisplayInfo {
-    Bme680Data(bme680_device::Bme680Data),
-    Pms7003Data(pms7003_device::PmSensorData),
+    /// readings from a sensor
+    Data(sensor::SensorId, sensor::Readings),
+    /// a sensor failed to return data
+    Fault(diagnostics::SensorFault),
+    /// progress of the particulate matter sensor's acquisition
+    PmState(pms7003_device::PmState),
 }
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(not(feature = "production"))]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
 }
 
+// route `defmt::panic` to the core panic handler, so it's recorded
+#[cfg(feature = "production")]
+#[defmt::panic_handler]
+fn panic() -> ! {
+    core::panic!("defmt panic")
+}
+
+/// Panic handler for production
+///
+/// Records the panic message and resets.
+#[cfg(feature = "production")]
+#[panic_handler]
+fn production_panic(info: &core::panic::PanicInfo) -> ! {
+    crash::record_panic(info)
+}
+
 /// Terminates the application and makes a semihosting-capable debug tool exit
 /// with status code 0.
 pub fn exit() -> ! {
@@ -45,6 +97,7 @@ pub fn exit() -> ! {
 /// Terminates the application and makes a semihosting-capable debug tool exit
 /// with an error. This seems better than the default, which is to spin in a
 /// loop.
+#[cfg(not(feature = "production"))]
 #[cortex_m_rt::exception]
 unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
     loop {
@@ -52,6 +105,15 @@ unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
     }
 }
 
+/// Hardfault handler for production
+///
+/// Records the fault registers and resets.
+#[cfg(feature = "production")]
+#[cortex_m_rt::exception]
+unsafe fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    crash::record_hard_fault(frame)
+}
+
 // defmt-test 0.3.0 has the limitation that this `#[tests]` attribute can only be used
 // once within a crate. the module can be in any file but there can only be at most
 // one `#[tests]` module in this library crate
@@ -0,0 +1,144 @@
+//! STOP mode between measurement cycles
+//!
+//! In STOP mode all the clocks except the LSE are stopped, and the RTC
+//! wakeup timer brings the MCU back. STOP mode is only entered by the
+//! [`Executor`] when every task is waiting, so a task asks for it with
+//! [`stop_for`] and the others keep running until they're done. The embassy
+//! time driver doesn't count while stopped, so `Instant::now()` doesn't
+//! include the time spent in STOP mode, and timers that fall in it fire
+//! late.
+
+use crate::rtc;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::debug;
+use embassy_executor::{raw, Spawner};
+use embassy_futures::select::select;
+use embassy_stm32::pac;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+/// RTC second to stay in STOP mode until, 0 if STOP mode isn't requested
+static STOP_UNTIL: AtomicU32 = AtomicU32::new(0);
+/// Longest single STOP, so the watchdog is pet in time
+static STOP_CHUNK_SEC: AtomicU32 = AtomicU32::new(1);
+/// Signalled when the requested time has passed
+static STOP_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Context the cortex-m pender treats as the thread executor, it wakes the
+/// executor with SEV
+const THREAD_PENDER: usize = usize::MAX;
+
+/// A thread mode executor that enters STOP mode when idle
+///
+/// Like the embassy thread executor, it waits for an event with WFE when
+/// no task is ready. While STOP mode is requested, and nothing is being
+/// transmitted, it enters STOP mode instead, until the RTC wakeup timer or
+/// any other event.
+pub struct Executor {
+    inner: raw::Executor,
+    not_send: PhantomData<*mut ()>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            inner: raw::Executor::new(THREAD_PENDER as *mut ()),
+            not_send: PhantomData,
+        }
+    }
+
+    /// spawn the first tasks with `init`, then run the executor forever
+    pub fn run(&'static mut self, init: impl FnOnce(Spawner)) -> ! {
+        init(self.inner.spawner());
+        loop {
+            // SAFETY: the executor is only polled from this thread
+            unsafe { self.inner.poll() };
+            idle();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stay in STOP mode for `duration`, whenever the executor is idle
+///
+/// The IWDG keeps running in STOP mode, so the MCU wakes every
+/// `watchdog_period` to pet it. Returns when `duration` has passed on the
+/// RTC, or the executor has been awake for `duration`.
+pub async fn stop_for(duration: Duration, watchdog_period: Duration) {
+    debug!("stop mode for {}s", duration.as_secs());
+    STOP_DONE.reset();
+    STOP_CHUNK_SEC.store(
+        watchdog_period.as_secs().clamp(1, u16::MAX as u64) as u32,
+        Ordering::Relaxed,
+    );
+    STOP_UNTIL.store(
+        (rtc::seconds() as u32).saturating_add(duration.as_secs() as u32),
+        Ordering::Relaxed,
+    );
+    select(STOP_DONE.wait(), Timer::after(duration)).await;
+    STOP_UNTIL.store(0, Ordering::Relaxed);
+    debug!("stop mode finished");
+}
+
+/// true while a USART is still sending, STOP mode would cut it short
+fn transmitting() -> bool {
+    !pac::USART1.isr().read().tc() || !pac::USART3.isr().read().tc()
+}
+
+/// wait for an event, in STOP mode if it's requested
+fn idle() {
+    let until = STOP_UNTIL.load(Ordering::Relaxed);
+    if until == 0 || transmitting() {
+        cortex_m::asm::wfe();
+        return;
+    }
+    let now = rtc::seconds() as u32;
+    if now >= until {
+        STOP_UNTIL.store(0, Ordering::Relaxed);
+        STOP_DONE.signal(());
+        return;
+    }
+    let secs = (until - now).min(STOP_CHUNK_SEC.load(Ordering::Relaxed));
+    stop_once(secs as u16);
+    pet_watchdog();
+}
+
+/// enter STOP mode until the RTC wakeup timer fires after `seconds`, or
+/// another event wakes the MCU
+fn stop_once(seconds: u16) {
+    // the clock configuration to restore after waking, STOP mode switches
+    // the system clock to HSI and turns off the PLL
+    let cfgr = pac::RCC.cfgr().read();
+    rtc::start_wakeup(seconds);
+    // low power regulator in STOP mode, PDDS is left clear for STOP rather
+    // than STANDBY
+    pac::PWR.cr().modify(|w| w.set_lpds(true));
+    // SAFETY: only called from the executor's idle loop, which owns SCR
+    let mut scb = unsafe { cortex_m::Peripherals::steal() }.SCB;
+    scb.set_sleepdeep();
+    cortex_m::asm::dsb();
+    cortex_m::asm::wfe();
+    scb.clear_sleepdeep();
+    rtc::stop_wakeup();
+    restore_clocks(cfgr);
+}
+
+/// restart the PLL and switch back to the clock configuration before STOP
+fn restore_clocks(cfgr: pac::rcc::regs::Cfgr) {
+    pac::RCC.cr().modify(|w| w.set_pllon(true));
+    while !pac::RCC.cr().read().pllrdy() {}
+    pac::RCC.cfgr().write_value(cfgr);
+    while pac::RCC.cfgr().read().sws().to_bits() != cfgr.sw().to_bits() {}
+}
+
+fn pet_watchdog() {
+    pac::IWDG
+        .kr()
+        .write(|w| w.set_key(pac::iwdg::vals::Key::RESET));
+}
@@ -0,0 +1,94 @@
+//! Measurement bus
+//!
+//! The readings from each cycle are published as a timestamped
+//! [`Measurement`] on a publish/subscribe channel. The display, alerts,
+//! logging and other outputs each subscribe independently, without the
+//! sensor tasks knowing about them.
+
+use crate::{
+    battery::BatteryStatus,
+    coordinator::Snapshot,
+    pms7003_device::PmState,
+    rtc::{self, DateTime},
+    sensor::Readings,
+};
+use defmt::Format;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+
+/// The readings from one measurement cycle
+#[derive(Debug, Clone, Format)]
+pub struct Measurement {
+    /// milliseconds since boot
+    pub uptime_ms: u64,
+    /// seconds since the Unix epoch, None if the clock isn't set
+    pub unix_time: Option<u64>,
+    pub readings: Readings,
+    pub pm_state: PmState,
+    pub battery: Option<BatteryStatus>,
+}
+
+impl Measurement {
+    pub fn new(
+        snapshot: &Snapshot,
+        uptime_ms: u64,
+        unix_time: Option<u64>,
+        battery: Option<BatteryStatus>,
+    ) -> Self {
+        Measurement {
+            uptime_ms,
+            unix_time,
+            readings: snapshot.readings.clone(),
+            pm_state: snapshot.pm_state,
+            battery,
+        }
+    }
+
+    /// the local date and time of the measurement, None if the clock wasn't
+    /// set
+    pub fn local_time(&self) -> Option<DateTime> {
+        self.unix_time.map(|t| {
+            let local = t as i64 + rtc::utc_offset() as i64 * 60;
+            DateTime::from_unix(local.max(0) as u64)
+        })
+    }
+}
+
+/// Measurements a slow subscriber can fall behind before missing some
+const CAPACITY: usize = 2;
+/// Maximum number of subscribers
+pub const MAX_SUBSCRIBERS: usize = 7;
+/// Maximum number of publishers, not counting immediate publishers
+const MAX_PUBLISHERS: usize = 1;
+
+pub type MeasurementSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    Measurement,
+    CAPACITY,
+    MAX_SUBSCRIBERS,
+    MAX_PUBLISHERS,
+>;
+
+static MEASUREMENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    Measurement,
+    CAPACITY,
+    MAX_SUBSCRIBERS,
+    MAX_PUBLISHERS,
+> = PubSubChannel::new();
+
+/// publish a measurement to all subscribers
+///
+/// Never waits, a subscriber that has fallen behind misses the oldest
+/// measurement.
+pub fn publish(measurement: Measurement) {
+    MEASUREMENTS
+        .immediate_publisher()
+        .publish_immediate(measurement);
+}
+
+/// subscribe to measurements, None if there are already `MAX_SUBSCRIBERS`
+pub fn subscribe() -> Option<MeasurementSubscriber> {
+    MEASUREMENTS.subscriber().ok()
+}
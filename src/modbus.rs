@@ -0,0 +1,406 @@
+//! Modbus RTU slave on UART4, over RS-485
+//!
+//! Measurements are read as input registers, function 0x04, and a
+//! selection of the parameters as holding registers, functions 0x03, 0x06
+//! and 0x10. The parameters are read at boot, so only those applied at
+//! runtime, the UTC offset and the measurement output format, can be
+//! written. The register map is in the Modbus section of the README. The
+//! transceiver's DE and /RE pins are driven together, high while
+//! transmitting.
+//!
+//! UART4 can't receive in STOP mode, so with this feature `low_power_stop`
+//! is off, and the MCU only sleeps between cycles.
+//!
+//! Writing a read only register returns the illegal data address exception,
+//! and a value out of range the illegal data value exception. Requests to
+//! the broadcast address 0 are carried out without a reply.
+
+use crate::{
+    measurement::{Measurement, MeasurementSubscriber},
+    parameter::Parameters,
+    pm_correction::HumidityCorrection,
+    rtc,
+    sensor::Quantity,
+    serializer::{self, OutputFormat},
+};
+use defmt::{debug, error, Format};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    gpio::{AnyPin, Output},
+    peripherals, usart,
+};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+
+/// Longest RTU frame
+pub const MAX_FRAME: usize = 256;
+
+/// An RTU frame, address, PDU and CRC
+pub type Frame = Vec<u8, MAX_FRAME>;
+
+/// Number of input registers
+pub const INPUT_REGISTERS: usize = 21;
+
+/// Number of holding registers
+pub const HOLDING_REGISTERS: usize = 15;
+
+/// Status flags in input register 0
+pub const STATUS_MEASURED: u16 = 1 << 0;
+pub const STATUS_ENV_SENSOR: u16 = 1 << 1;
+pub const STATUS_PM_SENSOR: u16 = 1 << 2;
+pub const STATUS_CO2_SENSOR: u16 = 1 << 3;
+pub const STATUS_BATTERY_LOW: u16 = 1 << 4;
+pub const STATUS_CLOCK_SET: u16 = 1 << 5;
+
+/// Register value without a reading
+const NO_VALUE: u16 = 0xffff;
+/// Signed register value without a reading
+const NO_SIGNED_VALUE: u16 = 0x8000;
+
+/// Function codes
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+/// Most registers in a read request
+const MAX_READ: u16 = 125;
+/// Most registers in a write request
+const MAX_WRITE: u16 = 123;
+
+/// Holding registers that can be written
+const UTC_OFFSET_REGISTER: u16 = 12;
+const OUTPUT_FORMAT_REGISTER: u16 = 13;
+
+/// Modbus exception codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+}
+
+/// the Modbus CRC-16 of a frame, sent low byte first
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for &b in bytes {
+        crc ^= b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// a reading times `scale`, rounded, NO_VALUE if absent
+fn unsigned(value: Option<f32>, scale: f32) -> u16 {
+    match value {
+        Some(v) => (v * scale + 0.5).clamp(0.0, (NO_VALUE - 1) as f32) as u16,
+        None => NO_VALUE,
+    }
+}
+
+/// a signed reading times `scale`, rounded, NO_SIGNED_VALUE if absent
+fn signed(value: Option<f32>, scale: f32) -> u16 {
+    match value {
+        Some(v) => {
+            let v = v * scale + if v < 0.0 { -0.5 } else { 0.5 };
+            v.clamp(-(i16::MAX as f32), i16::MAX as f32) as i16 as u16
+        }
+        None => NO_SIGNED_VALUE,
+    }
+}
+
+/// the input registers for a measurement
+fn input_registers(m: &Measurement, count: u16) -> [u16; INPUT_REGISTERS] {
+    let r = |kind| m.readings.get(kind);
+    let mut status = STATUS_MEASURED;
+    for (kind, flag) in [
+        (Quantity::Temperature, STATUS_ENV_SENSOR),
+        (Quantity::Pm2_5, STATUS_PM_SENSOR),
+        (Quantity::Co2, STATUS_CO2_SENSOR),
+    ] {
+        if r(kind).is_some() {
+            status |= flag;
+        }
+    }
+    if matches!(m.battery, Some(b) if b.low) {
+        status |= STATUS_BATTERY_LOW;
+    }
+    if m.unix_time.is_some() {
+        status |= STATUS_CLOCK_SET;
+    }
+    let uptime = (m.uptime_ms / 1000) as u32;
+    let gas = r(Quantity::GasResistance).map_or(u32::MAX, |v| v as u32);
+    [
+        status,
+        count,
+        (uptime >> 16) as u16,
+        uptime as u16,
+        signed(r(Quantity::Temperature), 100.0),
+        unsigned(r(Quantity::Humidity), 100.0),
+        unsigned(r(Quantity::Pressure), 10.0),
+        (gas >> 16) as u16,
+        gas as u16,
+        unsigned(r(Quantity::Iaq), 1.0),
+        unsigned(r(Quantity::Pm1_0), 10.0),
+        unsigned(r(Quantity::Pm2_5), 10.0),
+        unsigned(r(Quantity::Pm10), 10.0),
+        unsigned(r(Quantity::Pm2_5Raw), 10.0),
+        unsigned(r(Quantity::Aqi), 1.0),
+        unsigned(r(Quantity::Pm2_5NowCast), 10.0),
+        unsigned(r(Quantity::AqiNowCast), 1.0),
+        unsigned(r(Quantity::Pm2_5Avg24h), 10.0),
+        unsigned(r(Quantity::Co2), 1.0),
+        m.battery.as_ref().map_or(NO_VALUE, |b| b.millivolts),
+        m.battery.as_ref().map_or(NO_VALUE, |b| b.percent.into()),
+    ]
+}
+
+/// The register map, and the handling of requests
+pub struct ModbusSlave {
+    address: u8,
+    params: Parameters,
+    count: u16,
+    inputs: [u16; INPUT_REGISTERS],
+}
+
+impl ModbusSlave {
+    pub fn new(params: Parameters) -> Self {
+        let mut inputs = [NO_VALUE; INPUT_REGISTERS];
+        // no status flags, count or uptime, and no temperature
+        inputs[..4].fill(0);
+        inputs[4] = NO_SIGNED_VALUE;
+        ModbusSlave {
+            address: params.modbus_address,
+            params,
+            count: 0,
+            inputs,
+        }
+    }
+
+    /// update the input registers from a measurement
+    pub fn update(&mut self, m: &Measurement) {
+        self.count = self.count.wrapping_add(1);
+        self.inputs = input_registers(m, self.count);
+    }
+
+    /// the value of an input register
+    pub fn input(&self, register: u16) -> Option<u16> {
+        self.inputs.get(register as usize).copied()
+    }
+
+    /// the value of a holding register
+    pub fn holding(&self, register: u16) -> Option<u16> {
+        let p = &self.params;
+        let secs = |v: u32| u16::try_from(v).unwrap_or(u16::MAX);
+        let value = match register {
+            0 => secs(p.screen_display_min_refresh_sec),
+            1 => secs(p.screen_display_max_refresh_sec),
+            2 => secs(p.pm25_warmup_sec),
+            3 => p.pm25_sample_count.into(),
+            4 => p.battery_low_percent.into(),
+            5 => p.alerts_enabled.into(),
+            6 => unsigned(Some(p.alert_pm25.limit), 10.0),
+            7 => unsigned(Some(p.alert_pm10.limit), 10.0),
+            8 => unsigned(Some(p.alert_iaq.limit), 1.0),
+            9 => unsigned(Some(p.alert_humidity.limit), 10.0),
+            10 => signed(Some(p.alert_temperature.limit), 10.0),
+            11 => match p.pm25_humidity_correction {
+                HumidityCorrection::None => 0,
+                HumidityCorrection::Epa => 1,
+                HumidityCorrection::Kohler { .. } => 2,
+            },
+            UTC_OFFSET_REGISTER => rtc::utc_offset() as u16,
+            OUTPUT_FORMAT_REGISTER => serializer::format() as u16,
+            14 => self.address.into(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// check a value can be written to a holding register
+    fn check_write(register: u16, value: u16) -> Result<(), Exception> {
+        match register {
+            UTC_OFFSET_REGISTER if (-840..=840).contains(&(value as i16)) => Ok(()),
+            OUTPUT_FORMAT_REGISTER if value <= OutputFormat::Cbor as u16 => Ok(()),
+            UTC_OFFSET_REGISTER | OUTPUT_FORMAT_REGISTER => Err(Exception::IllegalDataValue),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    /// write a checked value to a holding register
+    fn write(register: u16, value: u16) {
+        debug!("modbus write register {} = {}", register, value);
+        match register {
+            UTC_OFFSET_REGISTER => rtc::set_utc_offset(value as i16),
+            OUTPUT_FORMAT_REGISTER => serializer::set_format(OutputFormat::from_u8(value as u8)),
+            _ => (),
+        }
+    }
+
+    /// handle a request frame, returning the response
+    ///
+    /// Returns None for frames with a bad CRC, frames for other slaves, and
+    /// broadcasts.
+    pub fn handle(&mut self, request: &[u8]) -> Option<Frame> {
+        if request.len() < 4 {
+            return None;
+        }
+        let (body, crc) = request.split_at(request.len() - 2);
+        if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+            debug!("modbus crc error");
+            return None;
+        }
+        let address = body[0];
+        if address != self.address && address != 0 {
+            return None;
+        }
+        let function = body[1];
+        let mut response = Frame::new();
+        response.extend_from_slice(&[address, function]).ok();
+        if let Err(e) = self.execute(function, &body[2..], &mut response) {
+            debug!("modbus function {} exception {}", function, e);
+            response.truncate(1);
+            response.extend_from_slice(&[function | 0x80, e as u8]).ok();
+        }
+        if address == 0 {
+            return None;
+        }
+        let crc = crc16(&response);
+        response.extend_from_slice(&crc.to_le_bytes()).ok();
+        Some(response)
+    }
+
+    /// carry out a function, adding its response data
+    fn execute(
+        &mut self,
+        function: u8,
+        data: &[u8],
+        response: &mut Frame,
+    ) -> Result<(), Exception> {
+        let word = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        match function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                if data.len() != 4 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let (start, count) = (word(0), word(2));
+                if !(1..=MAX_READ).contains(&count) {
+                    return Err(Exception::IllegalDataValue);
+                }
+                response.push(count as u8 * 2).ok();
+                for register in start..start.saturating_add(count) {
+                    let value = if function == READ_INPUT_REGISTERS {
+                        self.input(register)
+                    } else {
+                        self.holding(register)
+                    };
+                    let value = value.ok_or(Exception::IllegalDataAddress)?;
+                    response.extend_from_slice(&value.to_be_bytes()).ok();
+                }
+                Ok(())
+            }
+            WRITE_SINGLE_REGISTER => {
+                if data.len() != 4 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                Self::check_write(word(0), word(2))?;
+                Self::write(word(0), word(2));
+                response.extend_from_slice(data).ok();
+                Ok(())
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                if data.len() < 5 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let (start, count) = (word(0), word(2));
+                if !(1..=MAX_WRITE).contains(&count)
+                    || data[4] as u16 != count * 2
+                    || data.len() != 5 + count as usize * 2
+                {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let values =
+                    || (0..count).map(|i| (start.wrapping_add(i), word(5 + 2 * i as usize)));
+                // write all or none
+                for (register, value) in values() {
+                    Self::check_write(register, value)?;
+                }
+                for (register, value) in values() {
+                    Self::write(register, value);
+                }
+                response.extend_from_slice(&data[..4]).ok();
+                Ok(())
+            }
+            _ => Err(Exception::IllegalFunction),
+        }
+    }
+}
+
+/// the silent interval ending a frame, 3.5 characters, and 1.75ms above
+/// 19200 baud
+pub fn frame_gap(baud_rate: u32) -> Duration {
+    if baud_rate > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        // 11 bits a character
+        Duration::from_micros(3_500_000 * 11 / baud_rate as u64)
+    }
+}
+
+/// task to answer Modbus requests, keeping the input registers up to date
+/// with the measurements
+#[embassy_executor::task]
+pub async fn modbus(
+    mut uart: usart::BufferedUart<'static, peripherals::UART4>,
+    mut de: Output<'static, AnyPin>,
+    mut measurements: MeasurementSubscriber,
+    params: Parameters,
+) {
+    let mut slave = ModbusSlave::new(params);
+    let gap = frame_gap(params.modbus_baud_rate);
+    // the time to send a character, the transmitter is still busy when the
+    // buffer is flushed
+    let char_time = Duration::from_micros(11_000_000 / params.modbus_baud_rate as u64);
+    let mut frame = Frame::new();
+    let mut buf = [0u8; 32];
+    debug!("modbus slave {} started", slave.address);
+    loop {
+        match select(uart.read(&mut buf), measurements.next_message_pure()).await {
+            Either::First(Ok(n)) => {
+                frame.clear();
+                let mut overrun = frame.extend_from_slice(&buf[..n]).is_err();
+                // the rest of the frame, up to the silent interval
+                while let Ok(result) = with_timeout(gap, uart.read(&mut buf)).await {
+                    match result {
+                        Ok(n) => overrun |= frame.extend_from_slice(&buf[..n]).is_err(),
+                        Err(e) => {
+                            error!("modbus read: {}", e);
+                            overrun = true;
+                        }
+                    }
+                }
+                if overrun {
+                    continue;
+                }
+                if let Some(response) = slave.handle(&frame) {
+                    de.set_high();
+                    if let Err(e) = uart.write_all(&response).await {
+                        error!("modbus write: {}", e);
+                    }
+                    uart.flush().await.ok();
+                    Timer::after(char_time).await;
+                    de.set_low();
+                }
+            }
+            Either::First(Err(e)) => error!("modbus read: {}", e),
+            Either::Second(m) => slave.update(&m),
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! MQTT publishing through an ESP8266/ESP32 WiFi coprocessor on UART5
+//!
+//! The coprocessor runs Espressif's AT firmware, version 2 or later, which
+//! has the MQTT client. The task joins the WiFi network, connects to the
+//! broker, and publishes each measurement as a JSON object on
+//! `<prefix>/<device id>/state`, in the serializer's JSON format. The
+//! availability topic `<prefix>/<device id>/status` is `online` while
+//! connected, and the broker sets it `offline` when the connection is lost.
+//!
+//! After each connection a Home Assistant discovery config is published,
+//! retained, on `<discovery prefix>/sensor/<device id>/<key>/config` for
+//! each quantity of the included sensors, so the sensors appear in Home
+//! Assistant without configuration. If any step fails the coprocessor is
+//! reset and the task tries again after `mqtt_retry_sec`.
+//!
+//! UART5 can't receive in STOP mode, and the broker drops a connection idle
+//! for longer than the keep alive, so with this feature `low_power_stop` is
+//! off, and the MCU only sleeps between cycles. The task isn't started if
+//! `ATMO_WIFI_SSID` wasn't set when building.
+
+use crate::{
+    esp_at::{self, AtError, Command, EspAt},
+    measurement::{Measurement, MeasurementSubscriber},
+    parameter::Parameters,
+    sensor::{self, Quantity, QuantitySpec, Unit},
+    serializer::{OutputFormat, Record, Serializer},
+};
+use core::fmt::Write as _;
+use core::future::Future;
+use defmt::{debug, error, info};
+use embassy_stm32::{peripherals, usart};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+
+/// Longest topic
+pub const TOPIC_LEN: usize = 128;
+
+/// A topic
+pub type Topic = String<TOPIC_LEN>;
+
+/// Longest discovery config
+pub const CONFIG_LEN: usize = 640;
+
+/// A Home Assistant discovery config
+pub type Config = String<CONFIG_LEN>;
+
+/// Time allowed for a command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time allowed to join the WiFi network
+const JOIN_TIMEOUT: Duration = Duration::from_secs(20);
+/// Time allowed to connect to the broker
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time for the coprocessor to restart
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+/// MQTT keep alive, in seconds
+const KEEP_ALIVE_SEC: u16 = 120;
+
+/// the state topic, `<prefix>/<device id>/state`
+pub fn state_topic(params: &Parameters) -> Topic {
+    device_topic(params, "state")
+}
+
+/// the availability topic, `<prefix>/<device id>/status`
+pub fn status_topic(params: &Parameters) -> Topic {
+    device_topic(params, "status")
+}
+
+fn device_topic(params: &Parameters, name: &str) -> Topic {
+    let mut topic = Topic::new();
+    write!(
+        topic,
+        "{}/{}/{}",
+        params.mqtt_topic_prefix, params.mqtt_device_id, name
+    )
+    .ok();
+    topic
+}
+
+/// the Home Assistant discovery topic of a quantity
+pub fn discovery_topic(params: &Parameters, kind: Quantity) -> Topic {
+    let mut topic = Topic::new();
+    write!(
+        topic,
+        "{}/sensor/{}/{}/config",
+        params.mqtt_discovery_prefix,
+        params.mqtt_device_id,
+        kind.key()
+    )
+    .ok();
+    topic
+}
+
+/// the Home Assistant device class of a quantity, None if it has none
+fn device_class(kind: Quantity) -> Option<&'static str> {
+    match kind {
+        Quantity::Temperature => Some("temperature"),
+        Quantity::Humidity => Some("humidity"),
+        Quantity::Pressure => Some("atmospheric_pressure"),
+        Quantity::Pm1_0 => Some("pm1"),
+        Quantity::Pm2_5
+        | Quantity::Pm2_5Cf1
+        | Quantity::Pm2_5Raw
+        | Quantity::Pm2_5NowCast
+        | Quantity::Pm2_5Avg24h => Some("pm25"),
+        Quantity::Pm10 => Some("pm10"),
+        Quantity::Co2 => Some("carbon_dioxide"),
+        Quantity::Aqi | Quantity::AqiNowCast => Some("aqi"),
+        _ => None,
+    }
+}
+
+/// the unit of a quantity as Home Assistant writes it, None if it has none
+fn unit(unit: Unit) -> Option<&'static str> {
+    match unit {
+        Unit::Index => None,
+        Unit::Ohm => Some("\u{3A9}"),
+        Unit::MicrogramsPerCubicMetre => Some("\u{B5}g/m\u{B3}"),
+        _ => Some(unit.symbol()),
+    }
+}
+
+/// the Home Assistant discovery config of a quantity
+pub fn discovery_config(
+    params: &Parameters,
+    spec: &QuantitySpec,
+    json: &mut Config,
+) -> core::fmt::Result {
+    json.clear();
+    let id = params.mqtt_device_id;
+    let key = spec.kind.key();
+    write!(
+        json,
+        "{{\"name\":\"{}\",\"unique_id\":\"{}_{}\",\"object_id\":\"{}_{}\",\
+         \"state_topic\":\"{}\",\"value_template\":\"{{{{ value_json.{} }}}}\",\
+         \"availability_topic\":\"{}\",\"state_class\":\"measurement\"",
+        spec.label,
+        id,
+        key,
+        id,
+        key,
+        state_topic(params),
+        key,
+        status_topic(params),
+    )?;
+    if let Some(class) = device_class(spec.kind) {
+        write!(json, ",\"device_class\":\"{}\"", class)?;
+    }
+    if let Some(unit) = unit(spec.unit) {
+        write!(json, ",\"unit_of_measurement\":\"{}\"", unit)?;
+    }
+    write!(
+        json,
+        ",\"suggested_display_precision\":{},\"device\":{{\"identifiers\":[\"{}\"],\
+         \"name\":\"Atmo monitor\",\"model\":\"atmo-monitor-stm32\"}}}}",
+        spec.precision, id
+    )
+}
+
+/// the state payload of a measurement, a JSON object
+fn state(serializer: &mut Serializer, m: &Measurement, out: &mut Record) -> bool {
+    match serializer.encode(OutputFormat::JsonLines, m, out) {
+        Ok(()) => {
+            // without the line end
+            out.pop();
+            true
+        }
+        Err(e) => {
+            error!("mqtt state: {}", e);
+            false
+        }
+    }
+}
+
+fn overflow<E>(_: E) -> AtError {
+    AtError::Overflow
+}
+
+/// run an exchange with the coprocessor, failing after `timeout`
+async fn timed<F: Future<Output = Result<(), AtError>>>(
+    timeout: Duration,
+    exchange: F,
+) -> Result<(), AtError> {
+    with_timeout(timeout, exchange)
+        .await
+        .unwrap_or(Err(AtError::Timeout))
+}
+
+/// join the WiFi network, connect to the broker, and publish the
+/// availability and discovery configs
+pub async fn connect<U: Read + Write>(
+    at: &mut EspAt<U>,
+    params: &Parameters,
+) -> Result<(), AtError> {
+    // the firmware echoes commands by default
+    timed(COMMAND_TIMEOUT, at.command("ATE0")).await?;
+    timed(COMMAND_TIMEOUT, at.command("AT+CWMODE=1")).await?;
+
+    let mut command = Command::new();
+    command.push_str("AT+CWJAP=").map_err(overflow)?;
+    esp_at::write_quoted(&mut command, params.mqtt_wifi_ssid)?;
+    command.push(',').map_err(overflow)?;
+    esp_at::write_quoted(&mut command, params.mqtt_wifi_password.0)?;
+    timed(JOIN_TIMEOUT, at.command(&command)).await?;
+    info!("mqtt joined {}", params.mqtt_wifi_ssid);
+
+    // connection 0 over TCP, with the client id, user name and password
+    command.clear();
+    command.push_str("AT+MQTTUSERCFG=0,1,").map_err(overflow)?;
+    esp_at::write_quoted(&mut command, params.mqtt_device_id)?;
+    command.push(',').map_err(overflow)?;
+    esp_at::write_quoted(&mut command, params.mqtt_username)?;
+    command.push(',').map_err(overflow)?;
+    esp_at::write_quoted(&mut command, params.mqtt_password.0)?;
+    command.push_str(",0,0,\"\"").map_err(overflow)?;
+    timed(COMMAND_TIMEOUT, at.command(&command)).await?;
+
+    // the broker publishes the will, offline, if the connection is lost
+    let status = status_topic(params);
+    command.clear();
+    write!(command, "AT+MQTTCONNCFG=0,{},0,", KEEP_ALIVE_SEC).map_err(overflow)?;
+    esp_at::write_quoted(&mut command, &status)?;
+    command.push_str(",\"offline\",0,1").map_err(overflow)?;
+    timed(COMMAND_TIMEOUT, at.command(&command)).await?;
+
+    command.clear();
+    command.push_str("AT+MQTTCONN=0,").map_err(overflow)?;
+    esp_at::write_quoted(&mut command, params.mqtt_broker)?;
+    write!(command, ",{},0", params.mqtt_port).map_err(overflow)?;
+    timed(CONNECT_TIMEOUT, at.command(&command)).await?;
+    info!("mqtt connected to {}", params.mqtt_broker);
+
+    timed(COMMAND_TIMEOUT, at.publish(&status, b"online", true)).await?;
+    let mut config = Config::new();
+    for spec in sensor::quantities() {
+        discovery_config(params, spec, &mut config).map_err(overflow)?;
+        let topic = discovery_topic(params, spec.kind);
+        timed(COMMAND_TIMEOUT, at.publish(&topic, config.as_bytes(), true)).await?;
+    }
+    debug!("mqtt discovery published");
+    Ok(())
+}
+
+/// task to publish measurements over MQTT, through the WiFi coprocessor
+#[embassy_executor::task]
+pub async fn mqtt(
+    uart: usart::BufferedUart<'static, peripherals::UART5>,
+    mut measurements: MeasurementSubscriber,
+    params: Parameters,
+) {
+    let mut at = EspAt::new(uart);
+    let mut serializer = Serializer::new();
+    let state_topic = state_topic(&params);
+    let mut payload = Record::new();
+    let retry = Duration::from_secs(params.mqtt_retry_sec.into());
+    loop {
+        // start from a known state, the restart may already have failed
+        // the connection
+        if timed(COMMAND_TIMEOUT, at.command("AT+RST")).await.is_ok() {
+            Timer::after(RESTART_DELAY).await;
+        }
+        if let Err(e) = connect(&mut at, &params).await {
+            error!("mqtt connect: {}", e);
+            Timer::after(retry).await;
+            continue;
+        }
+        loop {
+            let m = measurements.next_message_pure().await;
+            if !state(&mut serializer, &m, &mut payload) {
+                continue;
+            }
+            if let Err(e) = timed(COMMAND_TIMEOUT, at.publish(&state_topic, &payload, false)).await
+            {
+                error!("mqtt publish: {}", e);
+                break;
+            }
+            debug!("mqtt published {} bytes", payload.len());
+        }
+        Timer::after(retry).await;
+    }
+}
@@ -0,0 +1,130 @@
+//! NowCast and 24 hour average of PM2.5
+//!
+//! The AQI is defined on 24 hour averages, and public sites show the EPA
+//! NowCast, a weighted average of the last 12 hourly averages that follows
+//! changes faster. The corrected PM2.5 of each cycle is added to an hourly
+//! average, the current hour included while it's still filling. Hours
+//! without data, while the PM sensor was skipped or had failed, or before
+//! boot, are left out rather than counted as zero.
+//!
+//! The hours are those of the RTC, in seconds since the Unix epoch, as the
+//! uptime doesn't count the time spent in STOP mode. Nothing is added while
+//! the clock isn't set.
+
+use defmt::Format;
+
+/// Length of an hour in seconds
+pub const HOUR_SEC: u64 = 3600;
+
+/// Number of hourly averages kept
+const HOURS: usize = 24;
+
+/// Hours in the NowCast
+const NOWCAST_HOURS: u64 = 12;
+
+/// Fewest hours with data in a 24 hour average, 75% as for the EPA daily
+/// average
+const MIN_DAY_HOURS: usize = 18;
+
+/// The sum of the values in an hour
+#[derive(Debug, Default, Clone, Copy, Format)]
+struct Hour {
+    /// hours since the Unix epoch
+    hour: u64,
+    sum: f32,
+    /// number of values, 0 if the hour has no data
+    count: u16,
+}
+
+/// The hourly averages of PM2.5 over the last 24 hours
+#[derive(Debug, Clone, Format)]
+pub struct PmHistory {
+    hours: [Hour; HOURS],
+}
+
+impl Default for PmHistory {
+    fn default() -> Self {
+        PmHistory::new()
+    }
+}
+
+impl PmHistory {
+    pub fn new() -> Self {
+        PmHistory {
+            hours: [Hour::default(); HOURS],
+        }
+    }
+
+    /// add a PM2.5 value measured at `unix_time`, in seconds since the Unix
+    /// epoch
+    pub fn add(&mut self, unix_time: u64, pm2_5: f32) {
+        let hour = unix_time / HOUR_SEC;
+        let h = &mut self.hours[(hour % HOURS as u64) as usize];
+        // the slot last held the hour a day earlier
+        if h.count == 0 || h.hour != hour {
+            *h = Hour {
+                hour,
+                sum: 0.0,
+                count: 0,
+            };
+        }
+        h.sum += pm2_5;
+        h.count = h.count.saturating_add(1);
+    }
+
+    /// the average of the hour `age` hours before the hour of `unix_time`,
+    /// None if it has no data
+    pub fn hourly(&self, unix_time: u64, age: u64) -> Option<f32> {
+        let hour = (unix_time / HOUR_SEC).checked_sub(age)?;
+        let h = &self.hours[(hour % HOURS as u64) as usize];
+        (h.count > 0 && h.hour == hour).then(|| h.sum / h.count as f32)
+    }
+
+    /// the EPA NowCast of PM2.5 at `unix_time`
+    ///
+    /// Returns None unless two of the three most recent hours have data.
+    /// The weight factor is the ratio of the lowest to the highest hourly
+    /// average, at least 0.5, and each older hour is weighted by another
+    /// factor. The result is truncated to 0.1 ug/m3.
+    pub fn nowcast(&self, unix_time: u64) -> Option<f32> {
+        let recent = (0..3).filter_map(|age| self.hourly(unix_time, age)).count();
+        if recent < 2 {
+            return None;
+        }
+        let hours = (0..NOWCAST_HOURS).map(|age| self.hourly(unix_time, age));
+        let (min, max) = hours
+            .clone()
+            .flatten()
+            .fold((f32::MAX, 0.0f32), |(min, max), c| (min.min(c), max.max(c)));
+        let weight = if max > 0.0 { (min / max).max(0.5) } else { 1.0 };
+        let mut factor = 1.0;
+        let mut sum = 0.0;
+        let mut weights = 0.0;
+        for c in hours {
+            if let Some(c) = c {
+                sum += factor * c;
+                weights += factor;
+            }
+            factor *= weight;
+        }
+        Some(truncate(sum / weights))
+    }
+
+    /// the average of the hourly averages over the last 24 hours
+    ///
+    /// Returns None unless 18 of the hours have data.
+    pub fn average_24h(&self, unix_time: u64) -> Option<f32> {
+        let (sum, count) = (0..HOURS as u64)
+            .filter_map(|age| self.hourly(unix_time, age))
+            .fold((0.0, 0), |(sum, count), c| (sum + c, count + 1));
+        if count < MIN_DAY_HOURS {
+            return None;
+        }
+        Some(truncate(sum / count as f32))
+    }
+}
+
+/// truncate a concentration to 0.1 ug/m3
+fn truncate(c: f32) -> f32 {
+    (c.max(0.0) * 10.0) as u32 as f32 / 10.0
+}
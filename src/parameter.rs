@@ -1,14 +1,94 @@
+use crate::{
+    alert::Threshold,
+    battery::Chemistry,
+    env_sensor::{HeaterProfile, DEFAULT_HEATER_PROFILE},
+    pm_correction::{HumidityCorrection, DEFAULT_HUMIDITY_CORRECTION},
+    pms7003_device::AveragingStrategy,
+    scd4x_device::Scd4xMode,
+    serializer::OutputFormat,
+};
 use defmt::Format;
 
+/// A password or other secret parameter, not shown in the logs
+#[derive(Debug, Clone, Copy)]
+pub struct Secret(pub &'static str);
+
+impl Format for Secret {
+    fn format(&self, f: defmt::Formatter) {
+        if self.0.is_empty() {
+            defmt::write!(f, "\"\"")
+        } else {
+            defmt::write!(f, "<hidden>")
+        }
+    }
+}
+
 #[derive(Format, Clone, Copy)]
 pub struct Parameters {
     pub screen_columns: u16,
     pub screen_rows: u16,
     pub screen_margin: u16,
-    pub screen_controller_timeout_sec: u32,
     pub screen_display_min_refresh_sec: u32,
+    pub screen_display_max_refresh_sec: u32,
     pub screen_enable_shutdown_delay_sec: u32,
     pub bme680_first_data_delay_ms: u32,
+    pub pm25_sample_interval_ms: u32,
+    pub pm25_warmup_sec: u32,
+    pub pm25_stability_check: bool,
+    pub pm25_stability_tolerance: u16,
+    pub pm25_stability_frames: u8,
+    pub pm25_stability_max_frames: u8,
+    pub pm25_sample_count: u8,
+    pub pm25_averaging: AveragingStrategy,
+    pub pm25_outlier_rejection: bool,
+    pub pm25_outlier_min_deviation: u16,
+    pub pm25_outlier_percent: u16,
+    pub pm25_max_errors: u8,
+    pub pm25_acquisition_timeout_sec: u32,
+    pub bme680_timeout_sec: u32,
+    pub pm25_timeout_sec: u32,
+    pub watchdog_timeout_ms: u32,
+    pub watchdog_task_timeout_sec: u32,
+    pub low_power_stop: bool,
+    pub battery_chemistry: Chemistry,
+    pub battery_divider_ratio_milli: u32,
+    pub battery_sample_interval_sec: u32,
+    pub battery_low_percent: u8,
+    pub battery_low_hysteresis_percent: u8,
+    pub battery_low_refresh_multiplier: u32,
+    pub battery_low_disables_pm25: bool,
+    pub bme680_gas_baseline_ohm: u32,
+    pub adaptive_sampling: bool,
+    pub adaptive_pm25_threshold: u16,
+    pub adaptive_pm25_rise: u16,
+    pub adaptive_iaq_threshold: u16,
+    pub adaptive_iaq_rise: u16,
+    pub utc_offset_minutes: i16,
+    pub alerts_enabled: bool,
+    pub alert_pm25: Threshold,
+    pub alert_pm10: Threshold,
+    pub alert_iaq: Threshold,
+    pub alert_humidity: Threshold,
+    pub alert_temperature: Threshold,
+    pub alert_holdoff_sec: u32,
+    pub scd4x_mode: Scd4xMode,
+    pub scd4x_timeout_sec: u32,
+    pub scd4x_auto_calibration: bool,
+    pub env_heater_profile: HeaterProfile,
+    pub pm25_humidity_correction: HumidityCorrection,
+    pub output_format: OutputFormat,
+    pub modbus_address: u8,
+    pub modbus_baud_rate: u32,
+    pub mqtt_wifi_ssid: &'static str,
+    pub mqtt_wifi_password: Secret,
+    pub mqtt_broker: &'static str,
+    pub mqtt_port: u16,
+    pub mqtt_username: &'static str,
+    pub mqtt_password: Secret,
+    pub mqtt_device_id: &'static str,
+    pub mqtt_topic_prefix: &'static str,
+    pub mqtt_discovery_prefix: &'static str,
+    pub mqtt_retry_sec: u32,
 }
 
 impl Parameters {
@@ -18,9 +98,86 @@ impl Parameters {
             screen_rows,
             screen_margin: 5,
             bme680_first_data_delay_ms: 100,
-            screen_controller_timeout_sec: 20,
             screen_display_min_refresh_sec: 180,
+            screen_display_max_refresh_sec: 1440,
             screen_enable_shutdown_delay_sec: 30,
+            pm25_sample_interval_ms: 1000,
+            pm25_warmup_sec: 30,
+            pm25_stability_check: true,
+            pm25_stability_tolerance: 3,
+            pm25_stability_frames: 3,
+            pm25_stability_max_frames: 20,
+            pm25_sample_count: 5,
+            pm25_averaging: AveragingStrategy::Median,
+            pm25_outlier_rejection: true,
+            pm25_outlier_min_deviation: 10,
+            pm25_outlier_percent: 50,
+            pm25_max_errors: 10,
+            pm25_acquisition_timeout_sec: 90,
+            bme680_timeout_sec: 20,
+            pm25_timeout_sec: 110,
+            watchdog_timeout_ms: 25_000,
+            watchdog_task_timeout_sec: 30,
+            // the UARTs can't receive in STOP mode, so a Modbus slave or an
+            // MQTT connection only sleeps between cycles, drawing more
+            // current, to answer requests and keep the connection alive
+            low_power_stop: !cfg!(any(feature = "modbus", feature = "mqtt")),
+            battery_chemistry: Chemistry::LiIon,
+            battery_divider_ratio_milli: 2000,
+            battery_sample_interval_sec: 300,
+            battery_low_percent: 15,
+            battery_low_hysteresis_percent: 5,
+            battery_low_refresh_multiplier: 4,
+            battery_low_disables_pm25: true,
+            bme680_gas_baseline_ohm: 250_000,
+            adaptive_sampling: true,
+            adaptive_pm25_threshold: 12,
+            adaptive_pm25_rise: 5,
+            adaptive_iaq_threshold: 100,
+            adaptive_iaq_rise: 25,
+            utc_offset_minutes: 0,
+            alerts_enabled: true,
+            alert_pm25: Threshold {
+                limit: 35.0,
+                hysteresis: 5.0,
+            },
+            alert_pm10: Threshold {
+                limit: 150.0,
+                hysteresis: 10.0,
+            },
+            alert_iaq: Threshold {
+                limit: 200.0,
+                hysteresis: 20.0,
+            },
+            alert_humidity: Threshold {
+                limit: 70.0,
+                hysteresis: 5.0,
+            },
+            alert_temperature: Threshold {
+                limit: 30.0,
+                hysteresis: 1.0,
+            },
+            alert_holdoff_sec: 1800,
+            scd4x_mode: Scd4xMode::Periodic,
+            scd4x_timeout_sec: 20,
+            scd4x_auto_calibration: true,
+            env_heater_profile: DEFAULT_HEATER_PROFILE,
+            pm25_humidity_correction: DEFAULT_HUMIDITY_CORRECTION,
+            output_format: OutputFormat::Off,
+            modbus_address: 1,
+            modbus_baud_rate: 9600,
+            // the network settings are set in the environment at build time,
+            // so they aren't in the source
+            mqtt_wifi_ssid: option_env!("ATMO_WIFI_SSID").unwrap_or(""),
+            mqtt_wifi_password: Secret(option_env!("ATMO_WIFI_PASSWORD").unwrap_or("")),
+            mqtt_broker: option_env!("ATMO_MQTT_BROKER").unwrap_or(""),
+            mqtt_port: 1883,
+            mqtt_username: option_env!("ATMO_MQTT_USERNAME").unwrap_or(""),
+            mqtt_password: Secret(option_env!("ATMO_MQTT_PASSWORD").unwrap_or("")),
+            mqtt_device_id: "atmo_monitor",
+            mqtt_topic_prefix: "atmo",
+            mqtt_discovery_prefix: "homeassistant",
+            mqtt_retry_sec: 60,
         }
     }
 }
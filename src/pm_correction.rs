@@ -0,0 +1,117 @@
+//! Humidity correction of PM2.5, and the air quality index
+//!
+//! Optical sensors count water taken up by particles as particle mass, so
+//! they read high in humid air. The correction uses the humidity from the
+//! environmental sensor, and is applied to a cycle's readings before they
+//! are displayed or published. The uncorrected value is kept as
+//! [`Quantity::Pm2_5Raw`], and the AQI is calculated from the corrected
+//! value.
+
+use crate::{
+    parameter::Parameters,
+    sensor::{Quantity, Readings},
+};
+use defmt::{debug, Format};
+
+/// How PM2.5 is corrected for humidity
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum HumidityCorrection {
+    /// use the sensor's value
+    None,
+    /// the EPA US-wide correction for Plantower sensors, from the CF=1
+    /// values, including the extension for high concentrations
+    Epa,
+    /// remove the water uptake of particles with hygroscopicity `kappa`,
+    /// using the kappa-Kohler growth model
+    Kohler { kappa: f32 },
+}
+
+/// The default correction, the EPA correction for the Plantower sensors it
+/// was fitted for, none for the SPS30
+#[cfg(not(any(feature = "sps30", feature = "sps30-i2c")))]
+pub const DEFAULT_HUMIDITY_CORRECTION: HumidityCorrection = HumidityCorrection::Epa;
+#[cfg(any(feature = "sps30", feature = "sps30-i2c"))]
+pub const DEFAULT_HUMIDITY_CORRECTION: HumidityCorrection = HumidityCorrection::None;
+
+/// the EPA US-wide correction of a Plantower CF=1 PM2.5 value
+pub fn epa(pm2_5_cf1: f32, humidity: f32) -> f32 {
+    let x = pm2_5_cf1;
+    let rh = humidity;
+    let corrected = if x < 30.0 {
+        0.524 * x - 0.0862 * rh + 5.75
+    } else if x < 50.0 {
+        let w = x / 20.0 - 1.5;
+        (0.786 * w + 0.524 * (1.0 - w)) * x - 0.0862 * rh + 5.75
+    } else if x < 210.0 {
+        0.786 * x - 0.0862 * rh + 5.75
+    } else if x < 260.0 {
+        let w = x / 50.0 - 4.2;
+        (0.69 * w + 0.786 * (1.0 - w)) * x - 0.0862 * rh * (1.0 - w)
+            + 2.966 * w
+            + 5.75 * (1.0 - w)
+            + 8.84e-4 * x * x * w
+    } else {
+        2.966 + 0.69 * x + 8.84e-4 * x * x
+    };
+    corrected.max(0.0)
+}
+
+/// Highest humidity used in the growth model, the growth factor rises
+/// steeply towards saturation
+const KOHLER_MAX_HUMIDITY: f32 = 95.0;
+
+/// the dry PM2.5 from a value measured at `humidity`, using the
+/// kappa-Kohler mass growth factor `1 + kappa * aw / (1 - aw)`
+pub fn kohler(pm2_5: f32, humidity: f32, kappa: f32) -> f32 {
+    let aw = humidity.clamp(0.0, KOHLER_MAX_HUMIDITY) / 100.0;
+    pm2_5 / (1.0 + kappa * aw / (1.0 - aw))
+}
+
+/// US EPA PM2.5 breakpoints, concentration and index, from the 2024 revision
+const AQI_BREAKPOINTS: [(f32, f32, u16, u16); 6] = [
+    (0.0, 9.0, 0, 50),
+    (9.1, 35.4, 51, 100),
+    (35.5, 55.4, 101, 150),
+    (55.5, 125.4, 151, 200),
+    (125.5, 225.4, 201, 300),
+    (225.5, 325.4, 301, 500),
+];
+
+/// the US EPA air quality index of a PM2.5 concentration, 500 above the
+/// top breakpoint
+pub fn aqi(pm2_5: f32) -> u16 {
+    // concentrations are truncated to 0.1 ug/m3
+    let c = (pm2_5.max(0.0) * 10.0) as u32 as f32 / 10.0;
+    for (c_low, c_high, i_low, i_high) in AQI_BREAKPOINTS {
+        if c <= c_high {
+            let i = (i_high - i_low) as f32 / (c_high - c_low) * (c - c_low) + i_low as f32;
+            return (i + 0.5) as u16;
+        }
+    }
+    500
+}
+
+/// correct the PM2.5 reading, keeping the raw value, and add the AQI
+///
+/// The PM2.5 is left uncorrected if there is no humidity reading.
+pub fn apply(readings: &mut Readings, params: &Parameters) {
+    let raw = match readings.get(Quantity::Pm2_5) {
+        Some(v) => v,
+        None => return,
+    };
+    readings.set(Quantity::Pm2_5Raw, raw);
+    let humidity = readings.get(Quantity::Humidity);
+    let corrected = match (params.pm25_humidity_correction, humidity) {
+        (HumidityCorrection::Epa, Some(rh)) => {
+            epa(readings.get(Quantity::Pm2_5Cf1).unwrap_or(raw), rh)
+        }
+        (HumidityCorrection::Kohler { kappa }, Some(rh)) => kohler(raw, rh, kappa),
+        _ => raw,
+    };
+    debug!(
+        "pm2.5 {} corrected to {} at humidity {}",
+        raw, corrected, humidity
+    );
+    readings.set(Quantity::Pm2_5, corrected);
+    readings.set(Quantity::Aqi, aqi(corrected) as f32);
+}
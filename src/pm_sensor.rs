@@ -0,0 +1,656 @@
+//! Particulate matter sensor models
+//!
+//! The acquisition in `pms7003_device` works with any [`PmDevice`], chosen
+//! by cargo feature:
+//!
+//! - `pms7003`, `pms5003`, `pmsa003` Plantower sensors on USART1 at 9600
+//!   baud. The models share the frame format, the PMS5003 and PMSA003 can
+//!   return to active mode after waking, so passive mode is set again.
+//! - `sps30` Sensirion SPS30 on USART1 at 115200 baud, using SHDLC framing
+//! - `sps30-i2c` Sensirion SPS30 on the shared I2C1 bus
+//!
+//! Each model produces a [`PmSensorData`], so averaging, the display and
+//! the outputs don't depend on the model.
+
+use crate::pms7003_device::PmSensorData;
+#[cfg(feature = "sps30-i2c")]
+use crate::scd4x_device::{crc8, decode_words};
+#[cfg(feature = "sps30-i2c")]
+use core::fmt;
+#[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+use defmt::info;
+#[cfg(feature = "sps30-i2c")]
+use defmt::Debug2Format;
+use defmt::Format;
+#[cfg(feature = "pm-sensor")]
+use defmt::{debug, error};
+use embassy_stm32::{peripherals, usart};
+#[cfg(any(
+    feature = "pms7003",
+    feature = "pms5003",
+    feature = "pmsa003",
+    feature = "sps30"
+))]
+use embassy_time::with_timeout;
+#[cfg(feature = "pm-sensor")]
+use embassy_time::Duration;
+#[cfg(any(feature = "sps30", feature = "sps30-i2c"))]
+use embassy_time::Timer;
+#[cfg(feature = "sps30-i2c")]
+use embedded_hal::blocking::i2c::{Read as I2cRead, Write as I2cWrite};
+#[cfg(feature = "sps30")]
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+#[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+use pms_7003::async_interface::Pms7003SensorAsync;
+
+#[cfg(any(
+    all(feature = "pms7003", feature = "pms5003"),
+    all(feature = "pms7003", feature = "pmsa003"),
+    all(feature = "pms5003", feature = "pmsa003"),
+    all(
+        any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"),
+        any(feature = "sps30", feature = "sps30-i2c")
+    ),
+    all(feature = "sps30", feature = "sps30-i2c"),
+))]
+compile_error!("only one particulate matter sensor feature can be enabled");
+
+/// The UART the sensor is connected to
+pub type PmUart = usart::BufferedUart<'static, peripherals::USART1>;
+
+/// The fitted sensor
+#[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+pub type PmDevice = Plantower;
+#[cfg(feature = "sps30")]
+pub type PmDevice = Sps30Shdlc;
+#[cfg(feature = "sps30-i2c")]
+pub type PmDevice = Sps30I2c<crate::i2c_bus::I2cHandle>;
+
+/// Baud rate of the UART sensors
+#[cfg(not(feature = "sps30"))]
+pub const BAUD_RATE: u32 = 9600;
+#[cfg(feature = "sps30")]
+pub const BAUD_RATE: u32 = 115_200;
+
+/// The fitted Plantower model
+#[cfg(feature = "pms5003")]
+pub const PLANTOWER_MODEL: PlantowerModel = PlantowerModel::Pms5003;
+#[cfg(feature = "pmsa003")]
+pub const PLANTOWER_MODEL: PlantowerModel = PlantowerModel::Pmsa003;
+#[cfg(not(any(feature = "pms5003", feature = "pmsa003")))]
+pub const PLANTOWER_MODEL: PlantowerModel = PlantowerModel::Pms7003;
+
+/// Name of the fitted sensor
+#[cfg(not(any(feature = "sps30", feature = "sps30-i2c")))]
+pub const MODEL_NAME: &str = PLANTOWER_MODEL.name();
+#[cfg(any(feature = "sps30", feature = "sps30-i2c"))]
+pub const MODEL_NAME: &str = "SPS30";
+
+/// Errors from a sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PmError {
+    /// the request couldn't be sent
+    Send,
+    /// the reply couldn't be read
+    Read,
+    /// the reply failed its checksum
+    Checksum,
+    /// the reply wasn't the one expected
+    IncorrectResponse,
+    /// there was no reply
+    NoResponse,
+    /// no new measurement was ready
+    NotReady,
+    /// the sensor reported an error, with its state byte
+    Device(u8),
+    /// the I2C transfer failed
+    Bus,
+}
+
+#[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+impl From<pms_7003::Error> for PmError {
+    fn from(e: pms_7003::Error) -> Self {
+        match e {
+            pms_7003::Error::SendFailed => PmError::Send,
+            pms_7003::Error::ReadFailed => PmError::Read,
+            pms_7003::Error::ChecksumError => PmError::Checksum,
+            pms_7003::Error::IncorrectResponse => PmError::IncorrectResponse,
+            pms_7003::Error::NoResponse => PmError::NoResponse,
+        }
+    }
+}
+
+/// Plantower sensors sharing the PMS7003 protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PlantowerModel {
+    Pms7003,
+    Pms5003,
+    Pmsa003,
+}
+
+impl PlantowerModel {
+    pub const fn name(self) -> &'static str {
+        match self {
+            PlantowerModel::Pms7003 => "PMS7003",
+            PlantowerModel::Pms5003 => "PMS5003",
+            PlantowerModel::Pmsa003 => "PMSA003",
+        }
+    }
+
+    /// true if the sensor can be streaming frames again after waking
+    #[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+    fn active_after_wake(self) -> bool {
+        !matches!(self, PlantowerModel::Pms7003)
+    }
+}
+
+/// A Plantower sensor in passive mode, frames are only sent when requested
+#[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+pub struct Plantower {
+    dev: Pms7003SensorAsync<PmUart>,
+    model: PlantowerModel,
+    asleep: bool,
+}
+
+#[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+impl Plantower {
+    pub fn new(uart: PmUart, model: PlantowerModel) -> Self {
+        Plantower {
+            dev: Pms7003SensorAsync::new(uart),
+            model,
+            asleep: false,
+        }
+    }
+
+    /// prepare the sensor after power up
+    pub async fn init(&mut self) {
+        if let Err(e) = self.set_passive().await {
+            error!("{} init: {}", self.model.name(), e);
+        }
+    }
+
+    /// wake the sensor if it's asleep, starting the fan
+    pub async fn wake(&mut self) -> Result<(), PmError> {
+        if !self.asleep {
+            return Ok(());
+        }
+        self.dev.wake().await?;
+        self.asleep = false;
+        if self.model.active_after_wake() {
+            self.set_passive().await?;
+        }
+        Ok(())
+    }
+
+    /// put the sensor to sleep, stopping the fan
+    ///
+    /// in passive mode the sensor doesn't send frames unless requested, so
+    /// the sleep response can't be interleaved with a data frame
+    pub async fn sleep(&mut self) -> Result<(), PmError> {
+        self.dev.sleep().await?;
+        self.asleep = true;
+        Ok(())
+    }
+
+    /// request a frame, and read it
+    pub async fn read(&mut self) -> Result<PmSensorData, PmError> {
+        self.dev.request().await?;
+        let frame = self.dev.read().await?;
+        Ok(PmSensorData::copy_from_frame(&frame))
+    }
+
+    /// put the sensor into passive mode
+    ///
+    /// in active mode the sensor is streaming frames, so the response to
+    /// the passive command may be preceded by a data frame. Retry up to
+    /// `PASSIVE_ATTEMPTS` times until the sensor acknowledges the mode
+    /// change, each attempt limited to `PASSIVE_TIMEOUT`.
+    async fn set_passive(&mut self) -> Result<(), PmError> {
+        let mut error = PmError::NoResponse;
+        for _ in 0..PASSIVE_ATTEMPTS {
+            error = match with_timeout(PASSIVE_TIMEOUT, self.dev.passive()).await {
+                Ok(Ok(_)) => {
+                    info!("{} in passive mode", self.model.name());
+                    return Ok(());
+                }
+                Ok(Err(e)) => PmError::from(e),
+                Err(_) => PmError::NoResponse,
+            };
+            match error {
+                PmError::IncorrectResponse | PmError::NoResponse => {
+                    debug!("passive: {}", error);
+                }
+                _ => break,
+            }
+        }
+        error!("{} passive: {}", self.model.name(), error);
+        Err(error)
+    }
+}
+
+/// Attempts to put a Plantower sensor into passive mode
+#[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+const PASSIVE_ATTEMPTS: u8 = 5;
+/// Longest wait for a Plantower sensor to acknowledge passive mode
+#[cfg(any(feature = "pms7003", feature = "pms5003", feature = "pmsa003"))]
+const PASSIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// SPS30 commands, on I2C and over SHDLC
+#[cfg(any(feature = "sps30", feature = "sps30-i2c"))]
+mod cmd {
+    #[cfg(feature = "sps30-i2c")]
+    pub const START_MEASUREMENT: u16 = 0x0010;
+    #[cfg(feature = "sps30-i2c")]
+    pub const STOP_MEASUREMENT: u16 = 0x0104;
+    #[cfg(feature = "sps30-i2c")]
+    pub const READ_DATA_READY: u16 = 0x0202;
+    #[cfg(feature = "sps30-i2c")]
+    pub const READ_MEASURED_VALUES: u16 = 0x0300;
+    #[cfg(feature = "sps30-i2c")]
+    pub const SLEEP: u16 = 0x1001;
+    #[cfg(feature = "sps30-i2c")]
+    pub const WAKE_UP: u16 = 0x1103;
+
+    #[cfg(feature = "sps30")]
+    pub const SHDLC_START_MEASUREMENT: u8 = 0x00;
+    #[cfg(feature = "sps30")]
+    pub const SHDLC_STOP_MEASUREMENT: u8 = 0x01;
+    #[cfg(feature = "sps30")]
+    pub const SHDLC_READ_MEASURED_VALUES: u8 = 0x03;
+    #[cfg(feature = "sps30")]
+    pub const SHDLC_SLEEP: u8 = 0x10;
+    #[cfg(feature = "sps30")]
+    pub const SHDLC_WAKE_UP: u8 = 0x11;
+}
+
+/// Measurement output format, big endian unsigned 16 bit integers
+#[cfg(any(feature = "sps30", feature = "sps30-i2c"))]
+const OUTPUT_FORMAT_U16: u8 = 0x05;
+
+/// Number of values in a SPS30 measurement
+pub const SPS30_VALUES: usize = 10;
+
+/// convert a SPS30 measurement, in unsigned 16 bit format
+///
+/// The values are mass concentrations of PM1.0, PM2.5, PM4.0 and PM10 in
+/// ug/m3, number concentrations from 0.3um up to 0.5, 1.0, 2.5, 4.0 and
+/// 10um in particles/cm3, and the typical particle size in nm. The sensor
+/// gives one mass concentration, used for both the standard and
+/// atmospheric values. The particle counts beyond each size are derived
+/// from the number concentrations, using 4.0um for 5.0um, there is no count
+/// beyond 10um.
+pub fn sps30_data(values: &[u16; SPS30_VALUES]) -> PmSensorData {
+    let [pm1_0, pm2_5, _pm4_0, pm10, nc0_5, nc1_0, nc2_5, nc4_0, nc10, _size] = *values;
+    // particles/cm3 to particles in 0.1L
+    let beyond = |nc: u16| (nc10.saturating_sub(nc) as u32 * 100).min(u16::MAX as u32) as u16;
+    PmSensorData {
+        pm1_0,
+        pm2_5,
+        pm10,
+        pm1_0_atm: pm1_0,
+        pm2_5_atm: pm2_5,
+        pm10_atm: pm10,
+        count_0_3: beyond(0),
+        count_0_5: beyond(nc0_5),
+        count_1_0: beyond(nc1_0),
+        count_2_5: beyond(nc2_5),
+        count_5_0: beyond(nc4_0),
+        count_10: 0,
+    }
+}
+
+/// Start and stop byte of a SHDLC frame
+const SHDLC_FLAG: u8 = 0x7e;
+/// Escape byte of a SHDLC frame
+const SHDLC_ESCAPE: u8 = 0x7d;
+/// Address of the sensor
+const SHDLC_ADDRESS: u8 = 0x00;
+
+/// Longest SHDLC frame, with every byte stuffed
+pub const SHDLC_MAX_FRAME: usize = 2 + 2 * (5 + SPS30_VALUES * 2);
+
+/// Longest SHDLC data
+pub const SHDLC_MAX_DATA: usize = SPS30_VALUES * 2;
+
+/// A reply from the sensor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShdlcResponse {
+    pub command: u8,
+    pub data: Vec<u8, SHDLC_MAX_DATA>,
+}
+
+impl Format for ShdlcResponse {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "command {=u8:#x} data {=[u8]:#x}",
+            self.command,
+            self.data.as_slice()
+        )
+    }
+}
+
+/// the complement of the low byte of the sum of the bytes
+fn shdlc_checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn shdlc_push_stuffed(frame: &mut Vec<u8, SHDLC_MAX_FRAME>, b: u8) {
+    match b {
+        SHDLC_FLAG | SHDLC_ESCAPE | 0x11 | 0x13 => {
+            frame.push(SHDLC_ESCAPE).ok();
+            frame.push(b ^ 0x20).ok();
+        }
+        _ => {
+            frame.push(b).ok();
+        }
+    }
+}
+
+/// build a request frame, with the start and stop bytes
+pub fn shdlc_encode(command: u8, data: &[u8]) -> Vec<u8, SHDLC_MAX_FRAME> {
+    let mut frame = Vec::new();
+    // address, command, length, data, checksum
+    let mut bytes: Vec<u8, { SHDLC_MAX_DATA + 4 }> = Vec::new();
+    bytes
+        .extend_from_slice(&[SHDLC_ADDRESS, command, data.len() as u8])
+        .ok();
+    bytes.extend_from_slice(data).ok();
+    bytes.push(shdlc_checksum(&bytes)).ok();
+    frame.push(SHDLC_FLAG).ok();
+    for &b in bytes.iter() {
+        shdlc_push_stuffed(&mut frame, b);
+    }
+    frame.push(SHDLC_FLAG).ok();
+    frame
+}
+
+/// decode a reply, the bytes between the start and stop bytes
+pub fn shdlc_decode(stuffed: &[u8]) -> Result<ShdlcResponse, PmError> {
+    let mut bytes: Vec<u8, { SHDLC_MAX_DATA + 5 }> = Vec::new();
+    let mut escaped = false;
+    for &b in stuffed {
+        if escaped {
+            bytes
+                .push(b ^ 0x20)
+                .map_err(|_| PmError::IncorrectResponse)?;
+            escaped = false;
+        } else if b == SHDLC_ESCAPE {
+            escaped = true;
+        } else {
+            bytes.push(b).map_err(|_| PmError::IncorrectResponse)?;
+        }
+    }
+    // address, command, state, length, data, checksum
+    let (&checksum, body) = bytes.split_last().ok_or(PmError::IncorrectResponse)?;
+    if body.len() < 4 || body[3] as usize != body.len() - 4 {
+        return Err(PmError::IncorrectResponse);
+    }
+    if shdlc_checksum(body) != checksum {
+        return Err(PmError::Checksum);
+    }
+    // the low 7 bits are the error code, the top bit flags a device error
+    if body[2] != 0 {
+        return Err(PmError::Device(body[2]));
+    }
+    Ok(ShdlcResponse {
+        command: body[1],
+        data: Vec::from_slice(&body[4..]).map_err(|_| PmError::IncorrectResponse)?,
+    })
+}
+
+/// Longest wait for the sensor to have a new measurement, one a second
+#[cfg(any(feature = "sps30", feature = "sps30-i2c"))]
+const SPS30_READY_POLLS: u8 = 15;
+#[cfg(any(feature = "sps30", feature = "sps30-i2c"))]
+const SPS30_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest wait for a reply over SHDLC, the sensor replies within 20ms
+#[cfg(feature = "sps30")]
+const SHDLC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// decode the values of a measurement
+#[cfg(feature = "sps30")]
+fn sps30_values(bytes: &[u8]) -> Option<[u16; SPS30_VALUES]> {
+    if bytes.len() != SPS30_VALUES * 2 {
+        return None;
+    }
+    let mut values = [0u16; SPS30_VALUES];
+    for (v, b) in values.iter_mut().zip(bytes.chunks_exact(2)) {
+        *v = u16::from_be_bytes([b[0], b[1]]);
+    }
+    Some(values)
+}
+
+/// A SPS30 on USART1, using SHDLC framing
+#[cfg(feature = "sps30")]
+pub struct Sps30Shdlc {
+    uart: PmUart,
+    asleep: bool,
+    measuring: bool,
+}
+
+#[cfg(feature = "sps30")]
+impl Sps30Shdlc {
+    pub fn new(uart: PmUart) -> Self {
+        Sps30Shdlc {
+            uart,
+            asleep: false,
+            measuring: false,
+        }
+    }
+
+    /// prepare the sensor after power up, or after a reset of the MCU
+    /// while the sensor was asleep
+    pub async fn init(&mut self) {
+        self.asleep = true;
+        if let Err(e) = self.wake().await {
+            error!("sps30 init: {}", e);
+        }
+    }
+
+    /// wake the sensor if it's asleep, and start measuring
+    pub async fn wake(&mut self) -> Result<(), PmError> {
+        if self.asleep {
+            // a low pulse on RX wakes the interface, then the command
+            // wakes the sensor, an idle sensor rejects it
+            self.uart
+                .write_all(&[0xff])
+                .await
+                .map_err(|_| PmError::Send)?;
+            match self.transact(cmd::SHDLC_WAKE_UP, &[]).await {
+                Ok(_) | Err(PmError::Device(_)) => {}
+                Err(e) => return Err(e),
+            }
+            self.asleep = false;
+        }
+        if !self.measuring {
+            self.transact(cmd::SHDLC_START_MEASUREMENT, &[0x01, OUTPUT_FORMAT_U16])
+                .await?;
+            self.measuring = true;
+            debug!("sps30 measurement started");
+        }
+        Ok(())
+    }
+
+    /// stop measuring, and put the sensor to sleep
+    pub async fn sleep(&mut self) -> Result<(), PmError> {
+        self.transact(cmd::SHDLC_STOP_MEASUREMENT, &[]).await?;
+        self.measuring = false;
+        self.transact(cmd::SHDLC_SLEEP, &[]).await?;
+        self.asleep = true;
+        Ok(())
+    }
+
+    /// read the next measurement
+    pub async fn read(&mut self) -> Result<PmSensorData, PmError> {
+        for _ in 0..SPS30_READY_POLLS {
+            let response = self.transact(cmd::SHDLC_READ_MEASURED_VALUES, &[]).await?;
+            // no data if there is no new measurement
+            if !response.data.is_empty() {
+                let values = sps30_values(&response.data).ok_or(PmError::IncorrectResponse)?;
+                return Ok(sps30_data(&values));
+            }
+            Timer::after(SPS30_POLL_INTERVAL).await;
+        }
+        Err(PmError::NotReady)
+    }
+
+    /// send a request, and read the reply
+    ///
+    /// A sensor that doesn't reply within `SHDLC_TIMEOUT` gives
+    /// `PmError::NoResponse`.
+    async fn transact(&mut self, command: u8, data: &[u8]) -> Result<ShdlcResponse, PmError> {
+        with_timeout(SHDLC_TIMEOUT, self.exchange(command, data))
+            .await
+            .unwrap_or(Err(PmError::NoResponse))
+    }
+
+    /// send a request, and wait for the reply
+    async fn exchange(&mut self, command: u8, data: &[u8]) -> Result<ShdlcResponse, PmError> {
+        let frame = shdlc_encode(command, data);
+        self.uart
+            .write_all(&frame)
+            .await
+            .map_err(|_| PmError::Send)?;
+        let response = shdlc_decode(&self.read_frame().await?)?;
+        if response.command != command {
+            return Err(PmError::IncorrectResponse);
+        }
+        Ok(response)
+    }
+
+    /// read the bytes of a frame between the start and stop bytes
+    async fn read_frame(&mut self) -> Result<Vec<u8, SHDLC_MAX_FRAME>, PmError> {
+        let mut frame = Vec::new();
+        let mut started = false;
+        let mut b = [0u8; 1];
+        loop {
+            self.uart
+                .read_exact(&mut b)
+                .await
+                .map_err(|_| PmError::Read)?;
+            match (started, b[0]) {
+                (false, SHDLC_FLAG) => started = true,
+                (false, _) => {}
+                // two flags in a row, the first ended an earlier frame
+                (true, SHDLC_FLAG) if frame.is_empty() => {}
+                (true, SHDLC_FLAG) => return Ok(frame),
+                (true, byte) => frame.push(byte).map_err(|_| PmError::IncorrectResponse)?,
+            }
+        }
+    }
+}
+
+/// I2C address of the SPS30
+#[cfg(feature = "sps30-i2c")]
+const SPS30_ADDRESS: u8 = 0x69;
+
+/// A SPS30 on the shared I2C1 bus, it needs a bus clock of 100kHz or less
+#[cfg(feature = "sps30-i2c")]
+pub struct Sps30I2c<I2C> {
+    i2c: I2C,
+    asleep: bool,
+    measuring: bool,
+}
+
+#[cfg(feature = "sps30-i2c")]
+impl<I2C, E> Sps30I2c<I2C>
+where
+    I2C: I2cRead<Error = E> + I2cWrite<Error = E>,
+    E: fmt::Debug,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Sps30I2c {
+            i2c,
+            asleep: false,
+            measuring: false,
+        }
+    }
+
+    /// prepare the sensor after power up, or after a reset of the MCU
+    /// while the sensor was asleep
+    pub async fn init(&mut self) {
+        self.asleep = true;
+        if let Err(e) = self.wake().await {
+            error!("sps30 init: {}", e);
+        }
+    }
+
+    /// wake the sensor if it's asleep, and start measuring
+    pub async fn wake(&mut self) -> Result<(), PmError> {
+        if self.asleep {
+            // the first command wakes the interface and isn't acknowledged
+            self.send(cmd::WAKE_UP).ok();
+            self.send(cmd::WAKE_UP).ok();
+            Timer::after(Duration::from_millis(5)).await;
+            self.asleep = false;
+        }
+        if !self.measuring {
+            self.send_word(cmd::START_MEASUREMENT, (OUTPUT_FORMAT_U16 as u16) << 8)?;
+            Timer::after(Duration::from_millis(20)).await;
+            self.measuring = true;
+            debug!("sps30 measurement started");
+        }
+        Ok(())
+    }
+
+    /// stop measuring, and put the sensor to sleep
+    pub async fn sleep(&mut self) -> Result<(), PmError> {
+        self.send(cmd::STOP_MEASUREMENT)?;
+        self.measuring = false;
+        Timer::after(Duration::from_millis(20)).await;
+        self.send(cmd::SLEEP)?;
+        Timer::after(Duration::from_millis(5)).await;
+        self.asleep = true;
+        Ok(())
+    }
+
+    /// read the next measurement
+    pub async fn read(&mut self) -> Result<PmSensorData, PmError> {
+        for _ in 0..SPS30_READY_POLLS {
+            let mut ready = [0u16; 1];
+            self.read_words(cmd::READ_DATA_READY, &mut ready)?;
+            if ready[0] & 0x01 != 0 {
+                let mut values = [0u16; SPS30_VALUES];
+                self.read_words(cmd::READ_MEASURED_VALUES, &mut values)?;
+                return Ok(sps30_data(&values));
+            }
+            Timer::after(SPS30_POLL_INTERVAL).await;
+        }
+        Err(PmError::NotReady)
+    }
+
+    fn send(&mut self, command: u16) -> Result<(), PmError> {
+        self.i2c
+            .write(SPS30_ADDRESS, &command.to_be_bytes())
+            .map_err(|e| bus_error(e, command))
+    }
+
+    fn send_word(&mut self, command: u16, word: u16) -> Result<(), PmError> {
+        let [c0, c1] = command.to_be_bytes();
+        let [w0, w1] = word.to_be_bytes();
+        let crc = crc8(&[w0, w1]);
+        self.i2c
+            .write(SPS30_ADDRESS, &[c0, c1, w0, w1, crc])
+            .map_err(|e| bus_error(e, command))
+    }
+
+    /// set the register pointer to `command`, and read its words
+    fn read_words(&mut self, command: u16, words: &mut [u16]) -> Result<(), PmError> {
+        self.send(command)?;
+        let mut buf = [0u8; SPS30_VALUES * 3];
+        let buf = &mut buf[..words.len() * 3];
+        self.i2c
+            .read(SPS30_ADDRESS, buf)
+            .map_err(|e| bus_error(e, command))?;
+        decode_words(buf, words).map_err(|_| PmError::Checksum)
+    }
+}
+
+#[cfg(feature = "sps30-i2c")]
+fn bus_error<E: fmt::Debug>(e: E, command: u16) -> PmError {
+    error!("sps30 command {=u16:#x}: {}", command, Debug2Format(&e));
+    PmError::Bus
+}
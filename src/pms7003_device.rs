@@ -1,15 +1,31 @@
-//! Reading the Plantower PMS7003 sensor
+//! Reading the particulate matter sensor
+//!
+//! The sensor is a Plantower PMS7003 by default, the models that can be
+//! fitted instead are in `pm_sensor`.
 
-use crate::{parameter::Parameters, DisplayInfo};
-use defmt::{debug, error, info, Format};
-use embassy_stm32::{
-    gpio::{AnyPin, Output},
-    peripherals, usart,
+use crate::sensor::{Quantity, QuantitySpec, Readings, SensorId, SensorInfo, Unit};
+#[cfg(feature = "pm-sensor")]
+use crate::{
+    diagnostics::SensorFault,
+    parameter::Parameters,
+    pm_sensor::{PmDevice, PmError},
+    watchdog::{self, TaskId},
+    DisplayInfo,
 };
-use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use defmt::Format;
+#[cfg(feature = "pm-sensor")]
+use defmt::{debug, error, info, warn};
+#[cfg(feature = "pm-sensor")]
+use embassy_stm32::gpio::{AnyPin, Output};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(feature = "pm-sensor")]
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+#[cfg(feature = "pm-sensor")]
 use embassy_sync::channel::Sender;
 use embassy_sync::signal::Signal;
-use pms_7003::{async_interface::Pms7003SensorAsync, Error};
+#[cfg(feature = "pm-sensor")]
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use heapless::Vec;
 
 /// Control enum
 #[derive(Debug, Clone, Copy, Format)]
@@ -18,9 +34,169 @@ pub enum PmCommand {
     Sleep,
 }
 
+/// State of the sensor during an acquisition, reported to the display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PmState {
+    /// fan is spinning up after wake, readings not valid yet
+    WarmingUp,
+    /// waiting for consecutive frames to agree
+    Stabilizing,
+    /// readings are stable, collecting frames to average
+    Sampling,
+    /// stability was not reached, collecting frames anyway
+    Unstable,
+}
+
 /// The wake/sleep signal
 pub static PM25_SIGNAL: Signal<CriticalSectionRawMutex, PmCommand> = Signal::new();
 
+/// The quantities the sensor measures, at atmospheric conditions, the
+/// PM2.5 humidity correction, AQI and averages derived from them, and the
+/// quality of the frame average
+pub static SENSOR: SensorInfo = SensorInfo {
+    id: SensorId::Pm,
+    quantities: &[
+        QuantitySpec {
+            kind: Quantity::Pm1_0,
+            unit: Unit::MicrogramsPerCubicMetre,
+            precision: 0,
+            output_precision: 0,
+            label: "PM1.0",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Pm2_5,
+            unit: Unit::MicrogramsPerCubicMetre,
+            precision: 0,
+            output_precision: 1,
+            label: "PM2.5",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Pm10,
+            unit: Unit::MicrogramsPerCubicMetre,
+            precision: 0,
+            output_precision: 0,
+            label: "PM10",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Pm2_5Cf1,
+            unit: Unit::MicrogramsPerCubicMetre,
+            precision: 0,
+            output_precision: 0,
+            label: "PM2.5 CF1",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Pm2_5Raw,
+            unit: Unit::MicrogramsPerCubicMetre,
+            precision: 0,
+            output_precision: 1,
+            label: "PM2.5 raw",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Aqi,
+            unit: Unit::Index,
+            precision: 0,
+            output_precision: 0,
+            label: "AQI",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Pm2_5NowCast,
+            unit: Unit::MicrogramsPerCubicMetre,
+            precision: 1,
+            output_precision: 1,
+            label: "PM2.5 NowCast",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Pm2_5Avg24h,
+            unit: Unit::MicrogramsPerCubicMetre,
+            precision: 1,
+            output_precision: 1,
+            label: "PM2.5 24h",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::AqiNowCast,
+            unit: Unit::Index,
+            precision: 0,
+            output_precision: 0,
+            label: "AQI NowCast",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::PmQuality,
+            unit: Unit::Percent,
+            precision: 0,
+            output_precision: 0,
+            label: "PM quality",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Count0_3,
+            unit: Unit::PerDeciLitre,
+            precision: 0,
+            output_precision: 0,
+            label: ">0.3um",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Count0_5,
+            unit: Unit::PerDeciLitre,
+            precision: 0,
+            output_precision: 0,
+            label: ">0.5um",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Count1_0,
+            unit: Unit::PerDeciLitre,
+            precision: 0,
+            output_precision: 0,
+            label: ">1.0um",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Count2_5,
+            unit: Unit::PerDeciLitre,
+            precision: 0,
+            output_precision: 0,
+            label: ">2.5um",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Count5_0,
+            unit: Unit::PerDeciLitre,
+            precision: 0,
+            output_precision: 0,
+            label: ">5.0um",
+            on_screen: false,
+        },
+        QuantitySpec {
+            kind: Quantity::Count10,
+            unit: Unit::PerDeciLitre,
+            precision: 0,
+            output_precision: 0,
+            label: ">10um",
+            on_screen: false,
+        },
+    ],
+};
+
+/// The particle count quantities, smallest size first
+pub const COUNTS: [Quantity; 6] = [
+    Quantity::Count0_3,
+    Quantity::Count0_5,
+    Quantity::Count1_0,
+    Quantity::Count2_5,
+    Quantity::Count5_0,
+    Quantity::Count10,
+];
+
 /// Data from the sensor
 #[derive(Debug, Default, Clone, Copy, Format)]
 pub struct PmSensorData {
@@ -30,6 +206,18 @@ pub struct PmSensorData {
     pub pm1_0_atm: u16,
     pub pm2_5_atm: u16,
     pub pm10_atm: u16,
+    /// number of particles beyond 0.3um in 0.1L of air
+    pub count_0_3: u16,
+    /// number of particles beyond 0.5um in 0.1L of air
+    pub count_0_5: u16,
+    /// number of particles beyond 1.0um in 0.1L of air
+    pub count_1_0: u16,
+    /// number of particles beyond 2.5um in 0.1L of air
+    pub count_2_5: u16,
+    /// number of particles beyond 5.0um in 0.1L of air
+    pub count_5_0: u16,
+    /// number of particles beyond 10um in 0.1L of air
+    pub count_10: u16,
 }
 
 impl PmSensorData {
@@ -42,104 +230,403 @@ impl PmSensorData {
             pm1_0_atm: frame.pm1_0_atm,
             pm2_5_atm: frame.pm2_5_atm,
             pm10_atm: frame.pm10_atm,
+            count_0_3: frame.beyond_0_3,
+            count_0_5: frame.beyond_0_5,
+            count_1_0: frame.beyond_1_0,
+            count_2_5: frame.beyond_2_5,
+            count_5_0: frame.beyond_5_0,
+            count_10: frame.beyond_10_0,
         }
     }
 
-    /// calculate moving average from array of PmSensorData
-    pub fn average(data: &[PmSensorData]) -> Self {
-        let mut pm1_0_avg: u32 = 0;
-        let mut pm2_5_avg: u32 = 0;
-        let mut pm10_avg: u32 = 0;
-        let mut pm1_0_atm_avg: u32 = 0;
-        let mut pm2_5_atm_avg: u32 = 0;
-        let mut pm10_atm_avg: u32 = 0;
-        for d in data {
-            pm1_0_avg += d.pm1_0 as u32;
-            pm2_5_avg += d.pm2_5 as u32;
-            pm10_avg += d.pm10 as u32;
-            pm1_0_atm_avg += d.pm1_0_atm as u32;
-            pm2_5_atm_avg += d.pm2_5_atm as u32;
-            pm10_atm_avg += d.pm10_atm as u32;
+    /// the readings, mass concentrations at atmospheric conditions, and
+    /// the CF=1 PM2.5 for the humidity correction
+    pub fn readings(&self) -> Readings {
+        let mut r = Readings::new();
+        r.set(Quantity::Pm1_0, self.pm1_0_atm as f32);
+        r.set(Quantity::Pm2_5, self.pm2_5_atm as f32);
+        r.set(Quantity::Pm2_5Cf1, self.pm2_5 as f32);
+        r.set(Quantity::Pm10, self.pm10_atm as f32);
+        for (kind, count) in COUNTS.iter().zip(self.counts()) {
+            r.set(*kind, count as f32);
         }
+        r
+    }
+
+    /// the particle counts as an array, smallest size first
+    pub fn counts(&self) -> [u16; 6] {
+        [
+            self.count_0_3,
+            self.count_0_5,
+            self.count_1_0,
+            self.count_2_5,
+            self.count_5_0,
+            self.count_10,
+        ]
+    }
+
+    /// all the values as an array, in declaration order
+    fn to_fields(self) -> [u16; 12] {
+        [
+            self.pm1_0,
+            self.pm2_5,
+            self.pm10,
+            self.pm1_0_atm,
+            self.pm2_5_atm,
+            self.pm10_atm,
+            self.count_0_3,
+            self.count_0_5,
+            self.count_1_0,
+            self.count_2_5,
+            self.count_5_0,
+            self.count_10,
+        ]
+    }
+
+    /// build from an array of values, in declaration order
+    fn from_fields(f: [u16; 12]) -> Self {
         Self {
-            pm1_0: (pm1_0_avg / data.len() as u32) as u16,
-            pm2_5: (pm2_5_avg / data.len() as u32) as u16,
-            pm10: (pm10_avg / data.len() as u32) as u16,
-            pm1_0_atm: (pm1_0_atm_avg / data.len() as u32) as u16,
-            pm2_5_atm: (pm2_5_atm_avg / data.len() as u32) as u16,
-            pm10_atm: (pm10_atm_avg / data.len() as u32) as u16,
+            pm1_0: f[0],
+            pm2_5: f[1],
+            pm10: f[2],
+            pm1_0_atm: f[3],
+            pm2_5_atm: f[4],
+            pm10_atm: f[5],
+            count_0_3: f[6],
+            count_0_5: f[7],
+            count_1_0: f[8],
+            count_2_5: f[9],
+            count_5_0: f[10],
+            count_10: f[11],
         }
     }
+
+    /// calculate the average of an array of PmSensorData
+    ///
+    /// Each value is reduced independently using `strategy`. If `outlier`
+    /// is given, frames whose pm2.5 deviates from the median pm2.5 by more
+    /// than the limit are rejected before averaging. With an even count the
+    /// median is between two frames, so every frame can be rejected, then
+    /// none are. At most `MAX_PM_SAMPLES` frames are used. Returns None if
+    /// there are no frames.
+    pub fn average(
+        data: &[PmSensorData],
+        strategy: AveragingStrategy,
+        outlier: Option<OutlierLimit>,
+    ) -> Option<PmAverage> {
+        let data = &data[..data.len().min(MAX_PM_SAMPLES)];
+        if data.is_empty() {
+            return None;
+        }
+        let mut kept: Vec<[u16; 12], MAX_PM_SAMPLES> = Vec::new();
+        match outlier {
+            Some(limit) => {
+                let mut pm2_5: Vec<u16, MAX_PM_SAMPLES> =
+                    data.iter().map(|d| d.pm2_5_atm).collect();
+                let median = reduce(&mut pm2_5, AveragingStrategy::Median);
+                let max_dev = limit.max_deviation(median);
+                for d in data
+                    .iter()
+                    .filter(|d| d.pm2_5_atm.abs_diff(median) <= max_dev)
+                {
+                    kept.push(d.to_fields()).ok();
+                }
+            }
+            None => {
+                for d in data {
+                    kept.push(d.to_fields()).ok();
+                }
+            }
+        }
+        if kept.is_empty() {
+            for d in data {
+                kept.push(d.to_fields()).ok();
+            }
+        }
+        let mut avg = [0u16; 12];
+        let mut column: Vec<u16, MAX_PM_SAMPLES> = Vec::new();
+        for (i, v) in avg.iter_mut().enumerate() {
+            column.clear();
+            for f in &kept {
+                column.push(f[i]).ok();
+            }
+            *v = reduce(&mut column, strategy);
+        }
+        Some(PmAverage {
+            data: Self::from_fields(avg),
+            samples: kept.len() as u8,
+            rejected: (data.len() - kept.len()) as u8,
+        })
+    }
+}
+
+/// Maximum number of frames that can be averaged in one acquisition
+pub const MAX_PM_SAMPLES: usize = 16;
+
+/// How frames are combined into a single reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum AveragingStrategy {
+    /// arithmetic mean of all frames
+    Mean,
+    /// middle value, mean of the two middle values for an even count
+    Median,
+    /// mean after discarding the lowest and highest 20% of values
+    TrimmedMean,
+}
+
+/// Limit on how far a frame's pm2.5 can be from the median before the
+/// frame is rejected
+///
+/// The allowed deviation is the larger of `min_deviation` and `percent` of
+/// the median, so low concentrations aren't rejected for normal noise.
+#[derive(Debug, Clone, Copy, Format)]
+pub struct OutlierLimit {
+    pub min_deviation: u16,
+    pub percent: u16,
 }
 
-fn print_error(ctx: &str, e: Error) {
-    match e {
-        Error::SendFailed => error!("{}: Send failed", ctx),
-        Error::ReadFailed => error!("{}: Read failed", ctx),
-        Error::ChecksumError => error!("{}: checksum error", ctx),
-        Error::IncorrectResponse => error!("{}: incorrect response", ctx),
-        Error::NoResponse => error!("{}: no response", ctx),
+impl OutlierLimit {
+    fn max_deviation(&self, median: u16) -> u16 {
+        let relative = (median as u32 * self.percent as u32 / 100).min(u16::MAX as u32) as u16;
+        relative.max(self.min_deviation)
     }
 }
 
+/// Result of averaging frames
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct PmAverage {
+    pub data: PmSensorData,
+    /// number of frames used
+    pub samples: u8,
+    /// number of frames rejected as outliers
+    pub rejected: u8,
+}
+
+impl PmAverage {
+    /// the averaged data as readings, with the quality
+    pub fn readings(&self) -> Readings {
+        let mut r = self.data.readings();
+        r.set(Quantity::PmQuality, self.quality().into());
+        r
+    }
+
+    /// percentage of the frames that were used
+    pub fn quality(&self) -> u8 {
+        let total = self.samples as u16 + self.rejected as u16;
+        if total == 0 {
+            0
+        } else {
+            (self.samples as u16 * 100 / total) as u8
+        }
+    }
+}
+
+/// reduce a non-empty set of values to one using the strategy, the values
+/// are sorted in place
+fn reduce(values: &mut [u16], strategy: AveragingStrategy) -> u16 {
+    let n = values.len();
+    match strategy {
+        AveragingStrategy::Mean => mean(values),
+        AveragingStrategy::Median => {
+            values.sort_unstable();
+            if n % 2 == 1 {
+                values[n / 2]
+            } else {
+                mean(&values[n / 2 - 1..=n / 2])
+            }
+        }
+        AveragingStrategy::TrimmedMean => {
+            values.sort_unstable();
+            let trim = if n >= 3 { (n / 5).max(1) } else { 0 };
+            mean(&values[trim..n - trim])
+        }
+    }
+}
+
+/// mean of a non-empty set of values, summed in u32 so it can't overflow
+fn mean(values: &[u16]) -> u16 {
+    let sum: u32 = values.iter().map(|v| *v as u32).sum();
+    (sum / values.len() as u32) as u16
+}
+
 /// task to read pm2.5 sensor data
+#[cfg(feature = "pm-sensor")]
 #[embassy_executor::task]
 pub async fn pm25_controller(
-    mut dev: Pms7003SensorAsync<usart::BufferedUart<'static, peripherals::USART1>>,
+    mut dev: PmDevice,
     _reset_pin: Output<'static, AnyPin>,
     _set_pin: Output<'static, AnyPin>,
     sender: Sender<'static, NoopRawMutex, DisplayInfo, 2>,
-    _params: Parameters,
+    params: Parameters,
 ) {
-    let mut first = true;
     info!("starting pm2.5 loop");
+    dev.init().await;
     loop {
         // wait for start signal
-        match PM25_SIGNAL.wait().await {
+        match watchdog::supervised(TaskId::Pm25, PM25_SIGNAL.wait()).await {
             PmCommand::Wake => {
                 info!("Start collecting pm2.5");
-                if !first {
-                    if let Err(e) = dev.wake().await {
-                        print_error("pm25dev.wake", e);
+                if let Err(e) = dev.wake().await {
+                    error!("pm2.5 wake: {}", e);
+                }
+                let mut budget = Budget::new(&params);
+                let result = match pm25_warmup(&mut dev, &sender, &params, &mut budget).await {
+                    Ok(_) => pm25_get_data(&mut dev, &params, &mut budget).await,
+                    Err(fault) => Err(fault),
+                };
+                match result {
+                    Ok(avg) => {
+                        sender
+                            .send(DisplayInfo::Data(SensorId::Pm, avg.readings()))
+                            .await
+                    }
+                    Err(fault) => {
+                        error!("pm2.5 acquisition failed: {}", fault);
+                        sender
+                            .send(DisplayInfo::Fault(SensorFault::Pm(fault)))
+                            .await
                     }
-                } else {
-                    first = false;
                 }
-                let avg = pm25_get_data(&mut dev).await;
-                sender.send(DisplayInfo::Pms7003Data(avg)).await;
             }
             PmCommand::Sleep => {
                 info!("Stop collecting pm2.5");
-                // put the device to sleep, due to race conditions, may
-                // receive a data packet instead of the sleep response
-                // if this happens, retry the sleep
-                loop {
-                    match dev.sleep().await {
-                        Ok(_) => break,
-                        Err(Error::IncorrectResponse) => {
-                            debug!("sleep incorrect response");
-                            continue;
-                        }
-                        Err(e) => {
-                            print_error("pm25dev.sleep", e);
-                            break;
-                        }
-                    }
+                if let Err(e) = dev.sleep().await {
+                    error!("pm2.5 sleep: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Reason an acquisition was abandoned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PmFault {
+    /// the sensor returned `pm25_max_errors` errors
+    TooManyErrors,
+    /// `pm25_acquisition_timeout_sec` elapsed
+    Timeout,
+}
+
+/// error and time budget for one acquisition
+#[cfg(feature = "pm-sensor")]
+struct Budget {
+    deadline: Instant,
+    errors: u8,
+    max_errors: u8,
+}
+
+#[cfg(feature = "pm-sensor")]
+impl Budget {
+    fn new(params: &Parameters) -> Self {
+        Self {
+            deadline: Instant::now()
+                + Duration::from_secs(params.pm25_acquisition_timeout_sec.into()),
+            errors: 0,
+            max_errors: params.pm25_max_errors,
+        }
+    }
+
+    /// read a frame, giving up at the deadline
+    ///
+    /// returns None if the sensor gave an error, or didn't respond within
+    /// `FRAME_TIMEOUT`, but the error budget isn't used up yet
+    async fn read_frame(&mut self, dev: &mut PmDevice) -> Result<Option<PmSensorData>, PmFault> {
+        watchdog::check_in(TaskId::Pm25);
+        let remaining = self
+            .deadline
+            .checked_duration_since(Instant::now())
+            .ok_or(PmFault::Timeout)?;
+        let e = match with_timeout(remaining.min(FRAME_TIMEOUT), dev.read()).await {
+            Ok(Ok(frame)) => return Ok(Some(frame)),
+            Ok(Err(e)) => e,
+            Err(_) if remaining <= FRAME_TIMEOUT => return Err(PmFault::Timeout),
+            Err(_) => PmError::NoResponse,
+        };
+        error!("pm2.5 read: {}", e);
+        self.errors += 1;
+        if self.errors >= self.max_errors {
+            Err(PmFault::TooManyErrors)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Longest wait for the sensor to answer a request
+#[cfg(feature = "pm-sensor")]
+const FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// wait for the sensor fan to spin up, then optionally wait for the
+/// readings to settle
+///
+/// The datasheet calls for 30s after wake before readings are valid. If the
+/// stability check is enabled, frames are then read until
+/// `pm25_stability_frames` consecutive pm2.5 readings are within
+/// `pm25_stability_tolerance` of each other, or `pm25_stability_max_frames`
+/// frames have been read.
+#[cfg(feature = "pm-sensor")]
+async fn pm25_warmup(
+    dev: &mut PmDevice,
+    sender: &Sender<'static, NoopRawMutex, DisplayInfo, 2>,
+    params: &Parameters,
+    budget: &mut Budget,
+) -> Result<(), PmFault> {
+    info!("pm2.5 warm up {}s", params.pm25_warmup_sec);
+    sender.send(DisplayInfo::PmState(PmState::WarmingUp)).await;
+    watchdog::sleep(
+        TaskId::Pm25,
+        Duration::from_secs(params.pm25_warmup_sec.into()),
+    )
+    .await;
+    if !params.pm25_stability_check {
+        sender.send(DisplayInfo::PmState(PmState::Sampling)).await;
+        return Ok(());
+    }
+    sender
+        .send(DisplayInfo::PmState(PmState::Stabilizing))
+        .await;
+    let mut last: Option<u16> = None;
+    // frames in the current run, each within tolerance of the one before
+    let mut stable_frames = 0;
+    let mut state = PmState::Unstable;
+    for _ in 0..params.pm25_stability_max_frames {
+        if let Some(frame) = budget.read_frame(dev).await? {
+            stable_frames = match last {
+                Some(prev) if frame.pm2_5_atm.abs_diff(prev) <= params.pm25_stability_tolerance => {
+                    stable_frames + 1
                 }
+                _ => 1,
+            };
+            last = Some(frame.pm2_5_atm);
+            if stable_frames >= params.pm25_stability_frames {
+                state = PmState::Sampling;
+                break;
             }
         }
+        Timer::after(Duration::from_millis(params.pm25_sample_interval_ms.into())).await;
     }
+    info!("pm2.5 warm up finished: {}", state);
+    sender.send(DisplayInfo::PmState(state)).await;
+    Ok(())
 }
 
+/// collect frames and average them
+///
+/// If the budget runs out after some frames were collected, the average
+/// of those frames is returned. The quality of the average is passed on
+/// with the readings.
+#[cfg(feature = "pm-sensor")]
 async fn pm25_get_data(
-    dev: &mut Pms7003SensorAsync<usart::BufferedUart<'static, peripherals::USART1>>,
-) -> PmSensorData {
+    dev: &mut PmDevice,
+    params: &Parameters,
+    budget: &mut Budget,
+) -> Result<PmAverage, PmFault> {
     debug!("pm2.5 get data loop");
-    let mut data = [PmSensorData::default(); 5];
-    let mut offset = 0;
-    loop {
-        match dev.read().await {
-            Ok(frame) => {
+    let count = (params.pm25_sample_count as usize).clamp(1, MAX_PM_SAMPLES);
+    let mut data: Vec<PmSensorData, MAX_PM_SAMPLES> = Vec::new();
+    while data.len() < count {
+        match budget.read_frame(dev).await {
+            Ok(Some(frame)) => {
                 debug!(
                     "PM1_0: {} PM2_5: {} PM10: {} PM1_0_atm: {} PM2_5_atm {}, PM10_atm {}",
                     frame.pm1_0,
@@ -149,18 +636,44 @@ async fn pm25_get_data(
                     frame.pm2_5_atm,
                     frame.pm10_atm,
                 );
-                if offset < data.len() {
-                    data[offset] = PmSensorData::copy_from_frame(&frame);
-                    offset += 1;
-                }
-                if offset == data.len() {
+                debug!(
+                    "counts >0.3: {} >0.5: {} >1.0: {} >2.5: {} >5.0: {} >10: {}",
+                    frame.count_0_3,
+                    frame.count_0_5,
+                    frame.count_1_0,
+                    frame.count_2_5,
+                    frame.count_5_0,
+                    frame.count_10,
+                );
+                data.push(frame).ok();
+                if data.len() == count {
                     break;
                 }
             }
-            Err(e) => {
-                print_error("pm25_get_data", e);
+            Ok(None) => {}
+            Err(fault) if data.is_empty() => return Err(fault),
+            Err(fault) => {
+                warn!("pm2.5 using {} of {} frames: {}", data.len(), count, fault);
+                break;
             }
         }
+        Timer::after(Duration::from_millis(params.pm25_sample_interval_ms.into())).await;
     }
-    PmSensorData::average(&data)
+    let outlier = if params.pm25_outlier_rejection {
+        Some(OutlierLimit {
+            min_deviation: params.pm25_outlier_min_deviation,
+            percent: params.pm25_outlier_percent,
+        })
+    } else {
+        None
+    };
+    // data is never empty here, so there is always an average
+    let avg = PmSensorData::average(&data, params.pm25_averaging, outlier).unwrap_or_default();
+    info!(
+        "pm2.5 averaged {} frames, rejected {}, quality {}%",
+        avg.samples,
+        avg.rejected,
+        avg.quality()
+    );
+    Ok(avg)
 }
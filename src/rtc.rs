@@ -0,0 +1,337 @@
+//! Real time clock running from the LSE crystal
+//!
+//! The RTC calendar holds UTC, local time is found by adding the UTC offset.
+//! The RTC keeps running in STOP mode, its wakeup timer is used to wake the
+//! MCU at the end of a sleep interval. If the LSE crystal doesn't start,
+//! the RTC runs from the LSI, only for the wakeups, and the calendar is
+//! never set.
+
+use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use defmt::{debug, error, Format};
+use embassy_stm32::pac;
+use embassy_stm32::pac::rcc::vals::Rtcsel;
+use embassy_time::{Duration, Instant};
+
+/// A calendar date and time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert seconds since the Unix epoch to a date and time
+    pub fn from_unix(secs: u64) -> DateTime {
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// Convert to seconds since the Unix epoch
+    pub fn to_unix(&self) -> u64 {
+        // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let month = self.month.clamp(1, 12) as i64;
+        let y = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = y.div_euclid(400);
+        let yoe = y.rem_euclid(400);
+        let mp = (month + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day.max(1) as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        (days.max(0) as u64) * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// true if the date is valid, and within the range the RTC can hold
+    pub fn is_valid(&self) -> bool {
+        (2000..2100).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+/// local time offset from UTC in minutes
+static UTC_OFFSET_MINUTES: AtomicI32 = AtomicI32::new(0);
+
+/// Set the local time offset from UTC
+pub fn set_utc_offset(minutes: i16) {
+    UTC_OFFSET_MINUTES.store(minutes.into(), Ordering::Relaxed);
+}
+
+/// The local time offset from UTC in minutes
+pub fn utc_offset() -> i16 {
+    UTC_OFFSET_MINUTES.load(Ordering::Relaxed) as i16
+}
+
+/// the value of two BCD digits
+fn from_bcd(tens: u8, units: u8) -> u8 {
+    tens * 10 + units
+}
+
+/// true once `enable` has run, the RTC registers aren't read before
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Backup register holding `CALENDAR_SET` once the calendar is set
+const CALENDAR_SET_REGISTER: usize = 0;
+const CALENDAR_SET: u32 = 0xCA1E_0001;
+
+/// true if the calendar has been set since the backup domain was powered
+///
+/// INITS can't be used, as it's clear in the year 2000, so `set` leaves a
+/// flag in a backup register, which is reset with the backup domain.
+pub fn is_set() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+        && !NO_CALENDAR.load(Ordering::Relaxed)
+        && pac::RTC.bkpr(CALENDAR_SET_REGISTER).read().bkp() == CALENDAR_SET
+}
+
+/// The current UTC date and time
+pub fn now() -> DateTime {
+    // the shadow registers are bypassed, so read until two reads agree, in
+    // case the time changed between reading TR and DR
+    let (tr, dr) = loop {
+        let tr = pac::RTC.tr().read();
+        let dr = pac::RTC.dr().read();
+        if tr.0 == pac::RTC.tr().read().0 && dr.0 == pac::RTC.dr().read().0 {
+            break (tr, dr);
+        }
+    };
+    DateTime {
+        year: 2000 + from_bcd(dr.yt(), dr.yu()) as u16,
+        month: from_bcd(dr.mt() as u8, dr.mu()),
+        day: from_bcd(dr.dt(), dr.du()),
+        hour: from_bcd(tr.ht(), tr.hu()),
+        minute: from_bcd(tr.mnt(), tr.mnu()),
+        second: from_bcd(tr.st(), tr.su()),
+    }
+}
+
+/// Seconds counted by the RTC, whether or not the calendar is set
+///
+/// Unlike `Instant::now()`, this includes the time spent in STOP mode.
+pub fn seconds() -> u64 {
+    now().to_unix()
+}
+
+/// Seconds since the Unix epoch, 0 if the calendar isn't set or the RTC
+/// isn't enabled yet
+pub fn unix_time() -> u64 {
+    if is_set() {
+        now().to_unix()
+    } else {
+        0
+    }
+}
+
+/// The current local date and time, None if the calendar isn't set
+pub fn local_now() -> Option<DateTime> {
+    if !is_set() {
+        return None;
+    }
+    let local = now().to_unix() as i64 + utc_offset() as i64 * 60;
+    Some(DateTime::from_unix(local.max(0) as u64))
+}
+
+/// Set the calendar to a UTC date and time, false if there's no calendar
+/// as the RTC runs from the LSI
+pub fn set(dt: &DateTime) -> bool {
+    if NO_CALENDAR.load(Ordering::Relaxed) {
+        error!("rtc: no calendar without the LSE crystal");
+        return false;
+    }
+    unlocked(|| {
+        pac::RTC.isr().modify(|w| w.set_init(true));
+        while !pac::RTC.isr().read().initf() {}
+        let year = (dt.year - 2000) as u8;
+        pac::RTC.tr().write(|w| {
+            w.set_ht(dt.hour / 10);
+            w.set_hu(dt.hour % 10);
+            w.set_mnt(dt.minute / 10);
+            w.set_mnu(dt.minute % 10);
+            w.set_st(dt.second / 10);
+            w.set_su(dt.second % 10);
+        });
+        pac::RTC.dr().write(|w| {
+            w.set_yt(year / 10);
+            w.set_yu(year % 10);
+            w.set_mt(dt.month >= 10);
+            w.set_mu(dt.month % 10);
+            w.set_dt(dt.day / 10);
+            w.set_du(dt.day % 10);
+            // weekday isn't used, but 0 is forbidden
+            w.set_wdu(1);
+        });
+        pac::RTC.isr().modify(|w| w.set_init(false));
+        pac::RTC
+            .bkpr(CALENDAR_SET_REGISTER)
+            .write(|w| w.set_bkp(CALENDAR_SET));
+    });
+    debug!("rtc set to {}", dt);
+    true
+}
+
+/// Time allowed for the LSE crystal to start, it typically takes 2s
+const LSE_STARTUP: Duration = Duration::from_secs(5);
+
+/// LSI prescalers, 40kHz / 128 / 312
+const LSI_PREDIV_A: u8 = 127;
+const LSI_PREDIV_S: u16 = 311;
+
+/// true if the RTC runs from the LSI, as the LSE crystal failed
+static NO_CALENDAR: AtomicBool = AtomicBool::new(false);
+
+/// EXTI line connected to the RTC wakeup timer
+const RTC_WAKEUP_EXTI_LINE: usize = 20;
+
+/// Start the LSE and clock the RTC from it
+///
+/// The backup domain isn't reset with the MCU, so if the RTC is already
+/// running it's left alone.
+pub fn enable() {
+    pac::RCC.apb1enr().modify(|w| w.set_pwren(true));
+    // allow writes to the backup domain
+    pac::PWR.cr().modify(|w| w.set_dbp(true));
+    let bdcr = pac::RCC.bdcr().read();
+    if bdcr.rtcen() && bdcr.rtcsel() == Rtcsel::LSE {
+        debug!("rtc already running");
+    } else {
+        if bdcr.rtcen() {
+            // running from the LSI after the crystal failed, the clock can
+            // only be changed by resetting the backup domain
+            pac::RCC.bdcr().modify(|w| w.set_bdrst(true));
+            pac::RCC.bdcr().modify(|w| w.set_bdrst(false));
+        }
+        pac::RCC.bdcr().modify(|w| w.set_lseon(true));
+        let start = Instant::now();
+        while !pac::RCC.bdcr().read().lserdy() && start.elapsed() < LSE_STARTUP {}
+        if pac::RCC.bdcr().read().lserdy() {
+            pac::RCC.bdcr().modify(|w| {
+                w.set_rtcsel(Rtcsel::LSE);
+                w.set_rtcen(true);
+            });
+            debug!("rtc enabled");
+        } else {
+            error!("rtc: LSE didn't start, running from the LSI without a calendar");
+            enable_lsi();
+        }
+    }
+    // read the calendar directly, the shadow registers aren't updated in
+    // STOP mode
+    unlocked(|| pac::RTC.cr().modify(|w| w.set_bypshad(true)));
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Run the RTC from the LSI, for the STOP mode wakeups only
+///
+/// The LSI is too inaccurate for a calendar, so the calendar isn't set
+/// while the RTC runs from it.
+fn enable_lsi() {
+    pac::RCC.bdcr().modify(|w| w.set_lseon(false));
+    pac::RCC.csr().modify(|w| w.set_lsion(true));
+    while !pac::RCC.csr().read().lsirdy() {}
+    pac::RCC.bdcr().modify(|w| {
+        w.set_rtcsel(Rtcsel::LSI);
+        w.set_rtcen(true);
+    });
+    // divide the 40kHz LSI down to about 1Hz
+    unlocked(|| {
+        pac::RTC.isr().modify(|w| w.set_init(true));
+        while !pac::RTC.isr().read().initf() {}
+        pac::RTC.prer().write(|w| {
+            w.set_prediv_a(LSI_PREDIV_A);
+            w.set_prediv_s(LSI_PREDIV_S);
+        });
+        pac::RTC.isr().modify(|w| w.set_init(false));
+    });
+    NO_CALENDAR.store(true, Ordering::Relaxed);
+}
+
+/// run `f` with the RTC registers unlocked
+fn unlocked<R>(f: impl FnOnce() -> R) -> R {
+    pac::RTC.wpr().write(|w| w.set_key(0xca));
+    pac::RTC.wpr().write(|w| w.set_key(0x53));
+    let r = f();
+    pac::RTC.wpr().write(|w| w.set_key(0xff));
+    r
+}
+
+/// Start the wakeup timer, to generate an event on EXTI line 20 after
+/// `seconds`
+pub fn start_wakeup(seconds: u16) {
+    unlocked(|| {
+        pac::RTC.cr().modify(|w| w.set_wute(false));
+        while !pac::RTC.isr().read().wutwf() {}
+        // the 1Hz ck_spre clock, with the reset prescaler values
+        pac::RTC
+            .wutr()
+            .write(|w| w.set_wut(seconds.saturating_sub(1)));
+        pac::RTC
+            .cr()
+            .modify(|w| w.set_wucksel(pac::rtc::vals::Wucksel::CLOCKSPARE));
+        pac::RTC.isr().modify(|w| w.set_wutf(false));
+        pac::RTC.cr().modify(|w| {
+            w.set_wutie(true);
+            w.set_wute(true);
+        });
+    });
+    // the wakeup is an event, not an interrupt, so no handler is needed
+    pac::EXTI
+        .pr(0)
+        .write(|w| w.set_line(RTC_WAKEUP_EXTI_LINE, true));
+    pac::EXTI
+        .rtsr(0)
+        .modify(|w| w.set_line(RTC_WAKEUP_EXTI_LINE, true));
+    pac::EXTI
+        .emr(0)
+        .modify(|w| w.set_line(RTC_WAKEUP_EXTI_LINE, true));
+}
+
+/// Stop the wakeup timer and clear its flags
+pub fn stop_wakeup() {
+    unlocked(|| {
+        pac::RTC.cr().modify(|w| {
+            w.set_wutie(false);
+            w.set_wute(false);
+        });
+        pac::RTC.isr().modify(|w| w.set_wutf(false));
+    });
+    pac::EXTI
+        .pr(0)
+        .write(|w| w.set_line(RTC_WAKEUP_EXTI_LINE, true));
+}
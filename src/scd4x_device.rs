@@ -0,0 +1,338 @@
+//! Reading the SCD40/SCD41 CO2 sensor
+//!
+//! The sensor shares I2C1 with the BME680, and is enabled with the `scd4x`
+//! cargo feature. In periodic mode the sensor measures every 5 seconds, and
+//! keeps running between cycles, as automatic self calibration needs. In
+//! single shot mode, SCD41 only, it's powered down between cycles. The
+//! ambient pressure from the BME680 compensates the CO2 reading.
+
+use crate::sensor::{Quantity, QuantitySpec, Readings, SensorId, SensorInfo, Unit};
+#[cfg(feature = "scd4x")]
+use core::fmt;
+use defmt::Format;
+#[cfg(feature = "scd4x")]
+use defmt::{debug, error, Debug2Format};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+#[cfg(feature = "scd4x")]
+use embassy_time::{Duration, Timer};
+#[cfg(feature = "scd4x")]
+use embedded_hal::blocking::i2c::{Read, Write};
+
+/// I2C address of the sensor
+#[cfg(feature = "scd4x")]
+const ADDRESS: u8 = 0x62;
+
+/// Sensor commands
+#[cfg(feature = "scd4x")]
+mod cmd {
+    pub const START_PERIODIC_MEASUREMENT: u16 = 0x21B1;
+    pub const READ_MEASUREMENT: u16 = 0xEC05;
+    pub const STOP_PERIODIC_MEASUREMENT: u16 = 0x3F86;
+    pub const SET_AMBIENT_PRESSURE: u16 = 0xE000;
+    pub const PERFORM_FORCED_RECALIBRATION: u16 = 0x362F;
+    pub const SET_AUTOMATIC_SELF_CALIBRATION_ENABLED: u16 = 0x2416;
+    pub const GET_DATA_READY_STATUS: u16 = 0xE4B8;
+    pub const MEASURE_SINGLE_SHOT: u16 = 0x219D;
+    pub const POWER_DOWN: u16 = 0x36E0;
+    pub const WAKE_UP: u16 = 0x36F6;
+}
+
+/// Data sensed by the SCD4x device
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct Co2Data {
+    /// CO2 concentration in ppm
+    pub co2: u16,
+    pub temperature: f32,
+    pub humidity: f32,
+}
+
+/// The quantities the SCD4x measures
+///
+/// The sensor's own temperature and humidity readings are skewed by its
+/// self heating, so only CO2 is used.
+pub static SENSOR: SensorInfo = SensorInfo {
+    id: SensorId::Scd4x,
+    quantities: &[QuantitySpec {
+        kind: Quantity::Co2,
+        unit: Unit::PartsPerMillion,
+        precision: 0,
+        output_precision: 0,
+        label: "CO2",
+        on_screen: true,
+    }],
+};
+
+impl Co2Data {
+    pub fn readings(&self) -> Readings {
+        let mut r = Readings::new();
+        r.set(Quantity::Co2, self.co2 as f32);
+        r
+    }
+}
+
+/// Errors from the SCD4x device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Scd4xFault {
+    /// the I2C transfer failed
+    Bus,
+    /// a word failed its checksum
+    Crc,
+    /// no measurement was ready in time
+    NotReady,
+    /// the sensor rejected a forced recalibration
+    Calibration,
+}
+
+/// How the sensor takes measurements
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Scd4xMode {
+    /// measure every 5 seconds, continuously
+    Periodic,
+    /// measure once per cycle, SCD41 only
+    SingleShot,
+}
+
+/// Control enum
+#[derive(Debug, Clone, Copy, Format)]
+pub enum Scd4xCommand {
+    On,
+    Off,
+}
+
+pub static SCD4X_SIGNAL: Signal<CriticalSectionRawMutex, Scd4xCommand> = Signal::new();
+
+/// Calibration requests, from the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Calibration {
+    /// enable or disable automatic self calibration
+    AutoSelfCalibration(bool),
+    /// recalibrate to a known CO2 concentration in ppm, after at least 3
+    /// minutes of operation in that concentration
+    Forced(u16),
+}
+
+pub static SCD4X_CALIBRATION: Signal<CriticalSectionRawMutex, Calibration> = Signal::new();
+
+/// CRC-8 of a data word, polynomial 0x31, initial value 0xff
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xffu8;
+    for &b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// decode words, each followed by its CRC
+pub fn decode_words(buf: &[u8], words: &mut [u16]) -> Result<(), Scd4xFault> {
+    for (chunk, word) in buf.chunks_exact(3).zip(words.iter_mut()) {
+        if crc8(&chunk[..2]) != chunk[2] {
+            return Err(Scd4xFault::Crc);
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Ok(())
+}
+
+/// convert the words of a measurement
+pub fn convert_measurement(words: &[u16; 3]) -> Co2Data {
+    Co2Data {
+        co2: words[0],
+        temperature: -45.0 + 175.0 * words[1] as f32 / 65535.0,
+        humidity: 100.0 * words[2] as f32 / 65535.0,
+    }
+}
+
+/// A SCD4x device
+#[cfg(feature = "scd4x")]
+pub struct Scd4x<I2C> {
+    i2c: I2C,
+    mode: Scd4xMode,
+    /// true while periodic measurement is running
+    measuring: bool,
+    /// ambient pressure in hPa, applied again after waking up
+    pressure_hpa: Option<u16>,
+}
+
+#[cfg(feature = "scd4x")]
+impl<I2C, E> Scd4x<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+    E: fmt::Debug,
+{
+    pub fn new(i2c: I2C, mode: Scd4xMode) -> Self {
+        Scd4x {
+            i2c,
+            mode,
+            measuring: false,
+            pressure_hpa: None,
+        }
+    }
+
+    /// put the sensor in its idle state, and set automatic self calibration
+    pub async fn init(&mut self, asc: bool) -> Result<(), Scd4xFault> {
+        if self.mode == Scd4xMode::SingleShot {
+            self.wake_up().await;
+        }
+        // in case it was left measuring before a reset
+        self.send(cmd::STOP_PERIODIC_MEASUREMENT)?;
+        Timer::after(Duration::from_millis(500)).await;
+        self.send_word(cmd::SET_AUTOMATIC_SELF_CALIBRATION_ENABLED, asc as u16)?;
+        Timer::after(Duration::from_millis(1)).await;
+        match self.mode {
+            Scd4xMode::Periodic => self.start_periodic(),
+            Scd4xMode::SingleShot => self.send(cmd::POWER_DOWN),
+        }
+    }
+
+    /// take a measurement
+    pub async fn measure(&mut self) -> Result<Co2Data, Scd4xFault> {
+        match self.mode {
+            Scd4xMode::Periodic => {
+                if !self.measuring {
+                    self.start_periodic()?;
+                }
+                // a new measurement every 5 seconds
+                for _ in 0..12 {
+                    if self.data_ready().await? {
+                        return self.read_measurement().await;
+                    }
+                    Timer::after(Duration::from_millis(500)).await;
+                }
+                Err(Scd4xFault::NotReady)
+            }
+            Scd4xMode::SingleShot => {
+                self.wake_up().await;
+                if let Some(hpa) = self.pressure_hpa {
+                    self.send_word(cmd::SET_AMBIENT_PRESSURE, hpa)?;
+                    Timer::after(Duration::from_millis(1)).await;
+                }
+                self.send(cmd::MEASURE_SINGLE_SHOT)?;
+                Timer::after(Duration::from_millis(5000)).await;
+                let data = self.read_measurement().await;
+                self.send(cmd::POWER_DOWN)?;
+                data
+            }
+        }
+    }
+
+    /// set the ambient pressure in hPa, for pressure compensation
+    pub fn set_ambient_pressure(&mut self, hpa: u16) -> Result<(), Scd4xFault> {
+        self.pressure_hpa = Some(hpa);
+        if self.measuring {
+            self.send_word(cmd::SET_AMBIENT_PRESSURE, hpa)?;
+        }
+        Ok(())
+    }
+
+    /// carry out a calibration, returning the forced recalibration
+    /// correction in ppm
+    ///
+    /// Periodic measurement is stopped during the calibration.
+    pub async fn calibrate(&mut self, calibration: Calibration) -> Result<i16, Scd4xFault> {
+        let was_measuring = self.measuring;
+        if was_measuring {
+            self.send(cmd::STOP_PERIODIC_MEASUREMENT)?;
+            self.measuring = false;
+            Timer::after(Duration::from_millis(500)).await;
+        } else {
+            self.wake_up().await;
+        }
+        let result = match calibration {
+            Calibration::AutoSelfCalibration(enabled) => {
+                self.send_word(cmd::SET_AUTOMATIC_SELF_CALIBRATION_ENABLED, enabled as u16)?;
+                Timer::after(Duration::from_millis(1)).await;
+                Ok(0)
+            }
+            Calibration::Forced(ppm) => {
+                self.send_word(cmd::PERFORM_FORCED_RECALIBRATION, ppm)?;
+                Timer::after(Duration::from_millis(400)).await;
+                let mut words = [0u16; 1];
+                self.read_words(cmd::PERFORM_FORCED_RECALIBRATION, &mut words)?;
+                match words[0] {
+                    0xffff => Err(Scd4xFault::Calibration),
+                    w => Ok((w as i32 - 0x8000) as i16),
+                }
+            }
+        };
+        if was_measuring {
+            self.start_periodic()?;
+        } else {
+            self.send(cmd::POWER_DOWN)?;
+        }
+        result
+    }
+
+    fn start_periodic(&mut self) -> Result<(), Scd4xFault> {
+        if let Some(hpa) = self.pressure_hpa {
+            self.send_word(cmd::SET_AMBIENT_PRESSURE, hpa)?;
+        }
+        self.send(cmd::START_PERIODIC_MEASUREMENT)?;
+        self.measuring = true;
+        debug!("scd4x periodic measurement started");
+        Ok(())
+    }
+
+    async fn data_ready(&mut self) -> Result<bool, Scd4xFault> {
+        self.send(cmd::GET_DATA_READY_STATUS)?;
+        Timer::after(Duration::from_millis(1)).await;
+        let mut words = [0u16; 1];
+        self.read_words(cmd::GET_DATA_READY_STATUS, &mut words)?;
+        Ok(words[0] & 0x07ff != 0)
+    }
+
+    async fn read_measurement(&mut self) -> Result<Co2Data, Scd4xFault> {
+        self.send(cmd::READ_MEASUREMENT)?;
+        Timer::after(Duration::from_millis(1)).await;
+        let mut words = [0u16; 3];
+        self.read_words(cmd::READ_MEASUREMENT, &mut words)?;
+        let data = convert_measurement(&words);
+        debug!("scd4x: {}", data);
+        Ok(data)
+    }
+
+    /// wake the sensor from power down, the sensor doesn't acknowledge the
+    /// command
+    async fn wake_up(&mut self) {
+        self.send(cmd::WAKE_UP).ok();
+        Timer::after(Duration::from_millis(30)).await;
+    }
+
+    fn send(&mut self, command: u16) -> Result<(), Scd4xFault> {
+        self.i2c
+            .write(ADDRESS, &command.to_be_bytes())
+            .map_err(|e| bus_error(e, command))
+    }
+
+    fn send_word(&mut self, command: u16, word: u16) -> Result<(), Scd4xFault> {
+        let [c0, c1] = command.to_be_bytes();
+        let [w0, w1] = word.to_be_bytes();
+        let crc = crc8(&[w0, w1]);
+        self.i2c
+            .write(ADDRESS, &[c0, c1, w0, w1, crc])
+            .map_err(|e| bus_error(e, command))
+    }
+
+    /// read the reply to `command`
+    fn read_words(&mut self, command: u16, words: &mut [u16]) -> Result<(), Scd4xFault> {
+        let mut buf = [0u8; 9];
+        let buf = &mut buf[..words.len() * 3];
+        self.i2c
+            .read(ADDRESS, buf)
+            .map_err(|e| bus_error(e, command))?;
+        decode_words(buf, words)
+    }
+}
+
+#[cfg(feature = "scd4x")]
+fn bus_error<E: fmt::Debug>(e: E, command: u16) -> Scd4xFault {
+    error!("scd4x command {=u16:#x}: {}", command, Debug2Format(&e));
+    Scd4xFault::Bus
+}
@@ -0,0 +1,81 @@
+//! Adaptive sampling interval
+//!
+//! Samples at the minimum interval while PM2.5 or IAQ is above its
+//! threshold, or rising, and doubles the interval up to the maximum while
+//! the air is clean and stable.
+
+use crate::parameter::Parameters;
+use defmt::{debug, Format};
+
+/// Chooses the time until the next measurement cycle
+#[derive(Debug, Clone, Copy, Format)]
+pub struct AdaptiveScheduler {
+    interval_sec: u32,
+    last_pm2_5: Option<u16>,
+    last_iaq: Option<u16>,
+}
+
+impl AdaptiveScheduler {
+    /// Create a scheduler starting at the minimum interval
+    pub fn new(params: &Parameters) -> Self {
+        Self {
+            interval_sec: params.screen_display_min_refresh_sec,
+            last_pm2_5: None,
+            last_iaq: None,
+        }
+    }
+
+    /// the current interval in seconds
+    pub fn interval_sec(&self) -> u32 {
+        self.interval_sec
+    }
+
+    /// update with the latest readings, returns the interval in seconds until
+    /// the next cycle
+    ///
+    /// A missing reading is ignored, and doesn't count as clean air.
+    pub fn next_interval(
+        &mut self,
+        params: &Parameters,
+        pm2_5: Option<u16>,
+        iaq: Option<u16>,
+    ) -> u32 {
+        let min = params.screen_display_min_refresh_sec;
+        let max = params.screen_display_max_refresh_sec.max(min);
+        if !params.adaptive_sampling {
+            self.interval_sec = min;
+            return min;
+        }
+        let pm_alert = pm2_5.map_or(false, |pm| {
+            pm >= params.adaptive_pm25_threshold
+                || self.last_pm2_5.map_or(false, |last| {
+                    pm >= last.saturating_add(params.adaptive_pm25_rise)
+                })
+        });
+        let iaq_alert = iaq.map_or(false, |iaq| {
+            iaq >= params.adaptive_iaq_threshold
+                || self.last_iaq.map_or(false, |last| {
+                    iaq >= last.saturating_add(params.adaptive_iaq_rise)
+                })
+        });
+        self.interval_sec = if pm_alert || iaq_alert {
+            min
+        } else if pm2_5.is_none() && iaq.is_none() {
+            self.interval_sec
+        } else {
+            self.interval_sec.saturating_mul(2)
+        }
+        .clamp(min, max);
+        if pm2_5.is_some() {
+            self.last_pm2_5 = pm2_5;
+        }
+        if iaq.is_some() {
+            self.last_iaq = iaq;
+        }
+        debug!(
+            "next interval {}s, pm alert: {} iaq alert: {}",
+            self.interval_sec, pm_alert, iaq_alert
+        );
+        self.interval_sec
+    }
+}
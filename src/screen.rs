@@ -1,10 +1,18 @@
-use crate::{bme680_device::Bme680Data, pms7003_device::PmSensorData};
+use crate::{
+    alert::AlertStatus,
+    battery::BatteryStatus,
+    crash::CrashRecord,
+    pms7003_device::{PmState, COUNTS},
+    rtc::DateTime,
+    sensor::{self, Quantity, Readings},
+};
 use core::fmt::Write;
 use defmt::debug;
 use embassy_stm32::{gpio::*, peripherals, spi::Spi};
 use embassy_time::Delay;
 use embedded_graphics::mono_font::MonoTextStyle;
 use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle, Triangle};
 use embedded_graphics::text::Text;
 use heapless::String;
 use il0373::{Color, GraphicDisplay, Interface};
@@ -59,7 +67,22 @@ impl Screen {
     }
 
     /// Update data on the display
-    pub fn update(&mut self, sensor_data: &Bme680Data, sensor_pmdata: &PmSensorData) {
+    ///
+    /// Temperature and pm2.5 are shown large, with the AQI and 24 hour
+    /// average above pm2.5, and the quantities the sensors list for the
+    /// display as lines of text, truncated to their precision. A sensor that
+    /// didn't return data is shown with "--" placeholders and a fault icon,
+    /// a value missing from a sensor that did return data, as the gas
+    /// resistance is when the heater isn't stable, is shown in red as
+    /// invalid.
+    pub fn update(
+        &mut self,
+        readings: &Readings,
+        pm_state: PmState,
+        battery: Option<&BatteryStatus>,
+        time: Option<&DateTime>,
+        alerts: &AlertStatus,
+    ) {
         debug!("display update");
 
         let mut delay = Delay;
@@ -77,87 +100,321 @@ impl Screen {
         let x_start: i32 = self.margin.into();
         let y_start: i32 = self.margin as i32 + 10;
 
-        Text::new(
-            "Atmo Monitor v0.1.0",
-            Point::new(x_start + 30, y_start),
-            med_char_rd_style,
-        )
-        .draw(&mut self.hdwr)
-        .unwrap();
-
         let mut buf: String<32> = String::new();
-        write!(&mut buf, "Humidity: {}\u{25}", sensor_data.humidity.trunc()).unwrap();
-        Text::new(
-            buf.as_str(),
-            Point::new(x_start, y_start + 14),
-            char_blk_style,
-        )
-        .draw(&mut self.hdwr)
-        .unwrap();
+        // active alerts replace the title
+        if alerts.active.is_empty() {
+            Text::new(
+                "Atmo Monitor v0.1.0",
+                Point::new(x_start + 30, y_start),
+                med_char_rd_style,
+            )
+            .draw(&mut self.hdwr)
+            .unwrap();
+        } else {
+            write!(&mut buf, "ALERT").unwrap();
+            for kind in alerts.active.iter() {
+                write!(&mut buf, " {}", kind.label()).ok();
+            }
+            Text::new(
+                buf.as_str(),
+                Point::new(x_start, y_start),
+                med_char_rd_style,
+            )
+            .draw(&mut self.hdwr)
+            .unwrap();
+            buf.clear();
+        }
+
+        let y = self.display_width - self.margin - 10;
+        // the listed quantities, three lines on the left, then one in the
+        // middle, above the time
+        for (i, spec) in sensor::on_screen().take(4).enumerate() {
+            buf.clear();
+            let value = readings.get(spec.kind);
+            if i < 3 {
+                let style = match value {
+                    Some(v) => {
+                        write!(&mut buf, "{}: ", spec.label).unwrap();
+                        spec.write_value(&mut buf, truncate(v, spec.precision))
+                            .unwrap();
+                        char_blk_style
+                    }
+                    None if is_invalid(readings, spec.kind) => {
+                        write!(&mut buf, "{} invalid", spec.label).unwrap();
+                        char_rd_style
+                    }
+                    None => {
+                        write!(&mut buf, "{}: --", spec.label).unwrap();
+                        char_blk_style
+                    }
+                };
+                Text::new(
+                    buf.as_str(),
+                    Point::new(x_start, y_start + 14 * (i as i32 + 1)),
+                    style,
+                )
+                .draw(&mut self.hdwr)
+                .unwrap();
+            } else {
+                Text::new(
+                    spec.label,
+                    Point::new(x_start + 90, (y - 28).into()),
+                    char_blk_style,
+                )
+                .draw(&mut self.hdwr)
+                .unwrap();
+                match value {
+                    Some(v) => spec
+                        .write_value(&mut buf, truncate(v, spec.precision))
+                        .unwrap(),
+                    None => write!(&mut buf, "--").unwrap(),
+                }
+                Text::new(
+                    buf.as_str(),
+                    Point::new(x_start + 90, (y - 14).into()),
+                    char_blk_style,
+                )
+                .draw(&mut self.hdwr)
+                .unwrap();
+            }
+        }
         buf.clear();
-        write!(&mut buf, "Pressure: {} hPa", sensor_data.pressure.trunc()).unwrap();
+        let mut x = self.display_height - self.margin - 10;
+        let pm2_5 = readings.get(Quantity::Pm2_5);
+        let char_width = match pm2_5 {
+            Some(v) => {
+                write!(&mut buf, "{}", v.trunc()).unwrap();
+                buf.len() as u16
+            }
+            None => {
+                write!(&mut buf, "--").unwrap();
+                2
+            }
+        };
+        x -= 18 * char_width;
         Text::new(
             buf.as_str(),
-            Point::new(x_start, y_start + 14 + 14),
-            char_blk_style,
+            Point::new(x.into(), y.into()),
+            lg_char_blk_style,
         )
         .draw(&mut self.hdwr)
         .unwrap();
         buf.clear();
-        let style = if sensor_data.gas_valid && sensor_data.heat_stable {
-            write!(&mut buf, "Gas: {} ohms", sensor_data.gas_resistance).unwrap();
-            char_blk_style
-        } else {
-            write!(&mut buf, "Gas invalid").unwrap();
+        // flag readings taken before the sensor had settled
+        let style = if pm_state == PmState::Unstable {
+            write!(&mut buf, "PM2.5?").unwrap();
             char_rd_style
+        } else {
+            write!(&mut buf, "PM2.5").unwrap();
+            char_blk_style
         };
+        Text::new(buf.as_str(), Point::new(x.into(), (y - 28).into()), style)
+            .draw(&mut self.hdwr)
+            .unwrap();
+        buf.clear();
+        // the AQI, from the NowCast once there is one, and the 24 hour
+        // average, on one line right aligned between the size distribution
+        // chart and pm2.5
+        let aqi = readings
+            .get(Quantity::AqiNowCast)
+            .or_else(|| readings.get(Quantity::Aqi));
+        if let Some(v) = aqi {
+            write!(&mut buf, "AQI {:.0}", v).unwrap();
+        }
+        if let Some(v) = readings.get(Quantity::Pm2_5Avg24h) {
+            if !buf.is_empty() {
+                buf.push(' ').unwrap();
+            }
+            write!(&mut buf, "24h {:.0}", v).unwrap();
+        }
+        if !buf.is_empty() {
+            let x_right = self.display_height - self.margin - 10 - 6 * buf.len() as u16;
+            Text::new(
+                buf.as_str(),
+                Point::new(x_right.into(), (y - 42).into()),
+                char_blk_style,
+            )
+            .draw(&mut self.hdwr)
+            .unwrap();
+            buf.clear();
+        }
+        match readings.get(Quantity::Temperature) {
+            Some(t) => write!(&mut buf, "{}\u{B0}C", t.trunc()).unwrap(),
+            None => {
+                write!(&mut buf, "--\u{B0}C").unwrap();
+                self.draw_fault_icon(Point::new(x_start, y.into()));
+            }
+        }
         Text::new(
             buf.as_str(),
-            Point::new(x_start, y_start + 14 + 14 + 14),
-            style,
+            Point::new(x_start + 10, y.into()),
+            lg_char_blk_style,
         )
         .draw(&mut self.hdwr)
         .unwrap();
-        buf.clear();
-        let mut x = self.display_height - self.margin - 10;
-        let y = self.display_width - self.margin - 10;
-        let char_width = if sensor_pmdata.pm2_5_atm < 10 {
-            1
-        } else if sensor_pmdata.pm2_5_atm < 100 {
-            2
-        } else if sensor_pmdata.pm2_5_atm < 1000 {
-            3
+        if let Some(b) = battery {
+            self.draw_battery_icon(b);
+        }
+        // local time of the measurement
+        if let Some(t) = time {
+            buf.clear();
+            write!(&mut buf, "{:02}:{:02}", t.hour, t.minute).unwrap();
+            Text::new(
+                buf.as_str(),
+                Point::new(x_start + 90, y.into()),
+                char_blk_style,
+            )
+            .draw(&mut self.hdwr)
+            .unwrap();
+        }
+        match pm2_5 {
+            Some(_) => self.draw_size_distribution(readings),
+            None => self.draw_fault_icon(Point::new((x - 12).into(), y.into())),
+        }
+        self.hdwr.update().ok();
+        self.hdwr.deep_sleep().ok();
+    }
+
+    /// Show the record of a crash before the last reset
+    pub fn show_crash(&mut self, record: &CrashRecord) {
+        debug!("display crash record");
+
+        let mut delay = Delay;
+        self.hdwr.reset(&mut delay).ok();
+        self.hdwr.clear(Color::White).ok();
+
+        let char_blk_style = MonoTextStyle::new(&PROFONT_10_POINT, Color::Black);
+        let med_char_rd_style = MonoTextStyle::new(&PROFONT_12_POINT, Color::Red);
+
+        let x_start: i32 = self.margin.into();
+        let mut y: i32 = self.margin as i32 + 10;
+
+        Text::new("Crashed", Point::new(x_start, y), med_char_rd_style)
+            .draw(&mut self.hdwr)
+            .unwrap();
+        let mut buf: String<32> = String::new();
+        match record {
+            CrashRecord::Panic(message) => {
+                // wrap the message at the screen width
+                let chars_per_line = ((self.display_height - 2 * self.margin) / 6) as usize;
+                for line in message.as_bytes().chunks(chars_per_line.min(32)).take(5) {
+                    y += 14;
+                    buf.clear();
+                    buf.push_str(core::str::from_utf8(line).unwrap_or("?")).ok();
+                    Text::new(buf.as_str(), Point::new(x_start, y), char_blk_style)
+                        .draw(&mut self.hdwr)
+                        .unwrap();
+                }
+            }
+            CrashRecord::HardFault { pc, lr, cfsr } => {
+                for (name, value) in [("PC", pc), ("LR", lr), ("CFSR", cfsr)] {
+                    y += 14;
+                    buf.clear();
+                    write!(&mut buf, "{}: {:#010x}", name, value).unwrap();
+                    Text::new(buf.as_str(), Point::new(x_start, y), char_blk_style)
+                        .draw(&mut self.hdwr)
+                        .unwrap();
+                }
+            }
+        }
+        self.hdwr.update().ok();
+        self.hdwr.deep_sleep().ok();
+    }
+
+    /// Draw a battery icon in the top right corner, filled to the state of
+    /// charge, red when the battery is low
+    fn draw_battery_icon(&mut self, battery: &BatteryStatus) {
+        const WIDTH: u32 = 18;
+        const HEIGHT: u32 = 9;
+        let color = if battery.low {
+            Color::Red
         } else {
-            4
+            Color::Black
         };
-        x -= 18 * char_width;
-        write!(&mut buf, "{}", sensor_pmdata.pm2_5_atm).unwrap();
-        Text::new(
-            buf.as_str(),
-            Point::new(x.into(), y.into()),
-            lg_char_blk_style,
+        let corner = Point::new(
+            (self.display_height - self.margin) as i32 - WIDTH as i32 - 2,
+            self.margin as i32,
+        );
+        Rectangle::new(corner, Size::new(WIDTH, HEIGHT))
+            .into_styled(PrimitiveStyle::with_stroke(color, 1))
+            .draw(&mut self.hdwr)
+            .unwrap();
+        // the terminal
+        Rectangle::new(
+            corner + Point::new(WIDTH as i32, 2),
+            Size::new(2, HEIGHT - 4),
         )
+        .into_styled(PrimitiveStyle::with_fill(color))
         .draw(&mut self.hdwr)
         .unwrap();
-        buf.clear();
-        write!(&mut buf, "PM2.5").unwrap();
-        Text::new(
-            buf.as_str(),
-            Point::new(x.into(), (y - 28).into()),
-            char_blk_style,
+        let fill = (WIDTH - 4) * battery.percent.min(100) as u32 / 100;
+        Rectangle::new(corner + Point::new(2, 2), Size::new(fill, HEIGHT - 4))
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(&mut self.hdwr)
+            .unwrap();
+    }
+
+    /// Draw a small red warning triangle, bottom left corner at `corner`
+    fn draw_fault_icon(&mut self, corner: Point) {
+        Triangle::new(
+            corner,
+            corner + Point::new(10, 0),
+            corner + Point::new(5, -10),
         )
+        .into_styled(PrimitiveStyle::with_fill(Color::Red))
         .draw(&mut self.hdwr)
         .unwrap();
-        buf.clear();
-        write!(&mut buf, "{}\u{B0}C", sensor_data.temperature.trunc()).unwrap();
-        Text::new(
-            buf.as_str(),
-            Point::new(x_start + 10, y.into()),
-            lg_char_blk_style,
+    }
+
+    /// Draw the particle size distribution as a bar chart
+    ///
+    /// One bar per PMS7003 count bin (>0.3, >0.5, >1.0, >2.5, >5.0, >10um),
+    /// smallest size on the left. Counts span several orders of magnitude,
+    /// so the bar height is log scaled. The chart sits in the top right
+    /// corner, between the battery icon and the AQI line.
+    fn draw_size_distribution(&mut self, readings: &Readings) {
+        const BAR_WIDTH: u32 = 10;
+        const BAR_SPACING: u32 = 2;
+        const CHART_HEIGHT: u32 = 18;
+        let x_start =
+            (self.display_height - self.margin) as i32 - 6 * (BAR_WIDTH + BAR_SPACING) as i32;
+        let y_base = self.margin as i32 + 30;
+        let fill = PrimitiveStyle::with_fill(Color::Black);
+        // full scale is the largest count the sensor can report
+        let full_scale = 65536f32.log10();
+        for (i, kind) in COUNTS.iter().enumerate() {
+            let count = readings.get(*kind).unwrap_or(0.0);
+            let height = ((count + 1.0).log10() / full_scale * CHART_HEIGHT as f32).round() as u32;
+            let x = x_start + (i as u32 * (BAR_WIDTH + BAR_SPACING)) as i32;
+            Rectangle::new(
+                Point::new(x, y_base - height as i32),
+                Size::new(BAR_WIDTH, height),
+            )
+            .into_styled(fill)
+            .draw(&mut self.hdwr)
+            .unwrap();
+        }
+        Line::new(
+            Point::new(x_start, y_base),
+            Point::new(x_start + 6 * (BAR_WIDTH + BAR_SPACING) as i32, y_base),
         )
+        .into_styled(PrimitiveStyle::with_stroke(Color::Black, 1))
         .draw(&mut self.hdwr)
         .unwrap();
-        self.hdwr.update().ok();
-        self.hdwr.deep_sleep().ok();
     }
 }
+
+/// truncate a value to `precision` decimal places
+fn truncate(value: f32, precision: u8) -> f32 {
+    let scale = 10f32.powi(precision.into());
+    (value * scale).trunc() / scale
+}
+
+/// true if a value of `kind` is missing, but the sensor that produces it
+/// returned other readings, so the value wasn't valid
+fn is_invalid(readings: &Readings, kind: Quantity) -> bool {
+    sensor::SENSORS
+        .iter()
+        .find(|s| s.quantities.iter().any(|q| q.kind == kind))
+        .is_some_and(|s| s.quantities.iter().any(|q| readings.get(q.kind).is_some()))
+}
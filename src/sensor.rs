@@ -0,0 +1,271 @@
+//! Sensor registry
+//!
+//! Each driver declares the quantities it produces, with their units and
+//! display and output precision, in a [`SensorInfo`]. Drivers send their
+//! data as a set of [`Readings`], which the coordinator, display, logging
+//! and outputs consume without knowing which sensor produced them. Drivers
+//! are included with cargo features, only the included drivers are in
+//! [`SENSORS`].
+
+use crate::parameter::Parameters;
+use core::fmt::Write;
+use defmt::Format;
+use heapless::Vec;
+
+/// The sensor drivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SensorId {
+    /// the environmental sensor, a BME680 or a model in `env_sensor`
+    Env,
+    /// the particulate matter sensor, a PMS7003 or a model in `pm_sensor`
+    Pm,
+    Scd4x,
+}
+
+/// Number of sensor drivers, included or not
+pub const SENSOR_COUNT: usize = 3;
+
+impl SensorId {
+    pub fn name(self) -> &'static str {
+        match self {
+            SensorId::Env => crate::env_sensor::MODEL_NAME,
+            SensorId::Pm => crate::pm_sensor::MODEL_NAME,
+            SensorId::Scd4x => "SCD4x",
+        }
+    }
+
+    /// time allowed for the sensor to return data in a cycle
+    pub fn timeout_sec(self, params: &Parameters) -> u32 {
+        match self {
+            SensorId::Env => params.bme680_timeout_sec,
+            SensorId::Pm => params.pm25_timeout_sec,
+            SensorId::Scd4x => params.scd4x_timeout_sec,
+        }
+    }
+}
+
+/// The quantities sensors measure
+///
+/// New quantities are added at the end, outputs identify a quantity by its
+/// position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+    Pressure,
+    GasResistance,
+    /// indoor air quality index, 0 to 500
+    Iaq,
+    Pm1_0,
+    Pm2_5,
+    Pm10,
+    /// particles beyond 0.3um
+    Count0_3,
+    /// particles beyond 0.5um
+    Count0_5,
+    /// particles beyond 1.0um
+    Count1_0,
+    /// particles beyond 2.5um
+    Count2_5,
+    /// particles beyond 5.0um
+    Count5_0,
+    /// particles beyond 10um
+    Count10,
+    Co2,
+    /// PM2.5 at the standard particle calibration, CF=1
+    Pm2_5Cf1,
+    /// PM2.5 before the humidity correction
+    Pm2_5Raw,
+    /// US EPA air quality index from PM2.5, 0 to 500
+    Aqi,
+    /// EPA NowCast of PM2.5, over the last 12 hours
+    Pm2_5NowCast,
+    /// average PM2.5 over the last 24 hours
+    Pm2_5Avg24h,
+    /// air quality index of the NowCast
+    AqiNowCast,
+    /// percentage of the PM frames averaged, the others were rejected as
+    /// outliers
+    PmQuality,
+}
+
+impl Quantity {
+    /// name for machine readable outputs
+    pub fn key(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temperature",
+            Quantity::Humidity => "humidity",
+            Quantity::Pressure => "pressure",
+            Quantity::GasResistance => "gas_resistance",
+            Quantity::Iaq => "iaq",
+            Quantity::Pm1_0 => "pm1_0",
+            Quantity::Pm2_5 => "pm2_5",
+            Quantity::Pm10 => "pm10",
+            Quantity::Count0_3 => "count0_3",
+            Quantity::Count0_5 => "count0_5",
+            Quantity::Count1_0 => "count1_0",
+            Quantity::Count2_5 => "count2_5",
+            Quantity::Count5_0 => "count5_0",
+            Quantity::Count10 => "count10",
+            Quantity::Co2 => "co2",
+            Quantity::Pm2_5Cf1 => "pm2_5_cf1",
+            Quantity::Pm2_5Raw => "pm2_5_raw",
+            Quantity::Aqi => "aqi",
+            Quantity::Pm2_5NowCast => "pm2_5_nowcast",
+            Quantity::Pm2_5Avg24h => "pm2_5_24h",
+            Quantity::AqiNowCast => "aqi_nowcast",
+            Quantity::PmQuality => "pm_quality",
+        }
+    }
+}
+
+/// Units of measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Unit {
+    Celsius,
+    Percent,
+    HectoPascal,
+    Ohm,
+    /// dimensionless index
+    Index,
+    MicrogramsPerCubicMetre,
+    /// particles in 0.1L of air
+    PerDeciLitre,
+    PartsPerMillion,
+}
+
+impl Unit {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Unit::Celsius => "\u{B0}C",
+            Unit::Percent => "%",
+            Unit::HectoPascal => "hPa",
+            Unit::Ohm => "ohms",
+            Unit::Index => "",
+            Unit::MicrogramsPerCubicMetre => "ug/m3",
+            Unit::PerDeciLitre => "/0.1L",
+            Unit::PartsPerMillion => "ppm",
+        }
+    }
+}
+
+/// A quantity a driver produces
+#[derive(Debug, Clone, Copy, Format)]
+pub struct QuantitySpec {
+    pub kind: Quantity,
+    pub unit: Unit,
+    /// decimal places shown
+    pub precision: u8,
+    /// decimal places in the machine readable outputs
+    pub output_precision: u8,
+    /// short name for the display and logs
+    pub label: &'static str,
+    /// listed on the display
+    pub on_screen: bool,
+}
+
+impl QuantitySpec {
+    /// write the value at the quantity's precision, with its unit
+    pub fn write_value<W: Write>(&self, out: &mut W, value: f32) -> core::fmt::Result {
+        write!(out, "{:.*}", self.precision as usize, value)?;
+        match self.unit {
+            Unit::Index => Ok(()),
+            // no space before the degree or percent signs
+            Unit::Celsius | Unit::Percent => out.write_str(self.unit.symbol()),
+            _ => write!(out, " {}", self.unit.symbol()),
+        }
+    }
+}
+
+/// A driver's declaration of what it measures
+#[derive(Debug, Format)]
+pub struct SensorInfo {
+    pub id: SensorId,
+    pub quantities: &'static [QuantitySpec],
+}
+
+/// The included drivers
+pub static SENSORS: &[&SensorInfo] = &[
+    #[cfg(feature = "env-sensor")]
+    &crate::env_sensor::SENSOR,
+    #[cfg(feature = "pm-sensor")]
+    &crate::pms7003_device::SENSOR,
+    #[cfg(feature = "scd4x")]
+    &crate::scd4x_device::SENSOR,
+];
+
+/// true if the driver is included
+pub fn is_included(id: SensorId) -> bool {
+    SENSORS.iter().any(|s| s.id == id)
+}
+
+/// find how a quantity is produced, None if no included driver produces it
+pub fn spec(kind: Quantity) -> Option<&'static QuantitySpec> {
+    quantities().find(|q| q.kind == kind)
+}
+
+/// the quantities of the included drivers, in registry order
+pub fn quantities() -> impl Iterator<Item = &'static QuantitySpec> {
+    SENSORS.iter().flat_map(|s| s.quantities.iter())
+}
+
+/// the quantities listed on the display, in registry order
+pub fn on_screen() -> impl Iterator<Item = &'static QuantitySpec> {
+    quantities().filter(|q| q.on_screen)
+}
+
+/// A value of a quantity
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Reading {
+    pub kind: Quantity,
+    pub value: f32,
+}
+
+/// Most readings in a set
+pub const MAX_READINGS: usize = 24;
+
+/// A set of readings, at most one of each quantity
+#[derive(Debug, Default, Clone)]
+pub struct Readings(Vec<Reading, MAX_READINGS>);
+
+impl Readings {
+    pub fn new() -> Self {
+        Readings(Vec::new())
+    }
+
+    /// set the value of a quantity, replacing any earlier value
+    pub fn set(&mut self, kind: Quantity, value: f32) {
+        match self.0.iter_mut().find(|r| r.kind == kind) {
+            Some(r) => r.value = value,
+            None => {
+                self.0.push(Reading { kind, value }).ok();
+            }
+        }
+    }
+
+    /// the value of a quantity, None if it wasn't measured
+    pub fn get(&self, kind: Quantity) -> Option<f32> {
+        self.0.iter().find(|r| r.kind == kind).map(|r| r.value)
+    }
+
+    /// add all the readings from another set
+    pub fn merge(&mut self, other: &Readings) {
+        for r in other.iter() {
+            self.set(r.kind, r.value);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Reading> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Format for Readings {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", self.0.as_slice())
+    }
+}
@@ -0,0 +1,323 @@
+//! Machine readable output of measurements
+//!
+//! Each measurement cycle is written as a record in one of the
+//! [`OutputFormat`]s, to any `embedded_io_async::Write` sink, such as a
+//! USART, USB CDC, or a buffer in a test. The format is chosen at runtime,
+//! it starts as `output_format` in the parameters and is changed with the
+//! console `output` command.
+//!
+//! The columns are the quantities of the included drivers, in registry
+//! order, after the uptime, the Unix time and the battery voltage. Values
+//! are rounded to the quantity's output precision, the same in every
+//! format, and written without units. Quantities without a reading in the
+//! cycle are left empty in CSV, and left out of JSON and CBOR.
+//!
+//! A CBOR record is an array of the uptime in ms, the Unix time, and the
+//! battery voltage in mV, null if unknown, then a map from the quantity's
+//! position in [`Quantity`](crate::sensor::Quantity) to its value. Whole
+//! values are written as integers, others as single precision floats.
+
+use crate::{
+    measurement::Measurement,
+    sensor::{self, QuantitySpec},
+};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicU8, Ordering};
+use defmt::{debug, Format};
+use embedded_io_async::Write;
+use heapless::Vec;
+use micromath::F32Ext;
+
+/// Longest record, a CSV header or a JSON line with every quantity
+pub const MAX_RECORD: usize = 1024;
+
+/// A record ready to be written
+pub type Record = Vec<u8, MAX_RECORD>;
+
+/// How measurements are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum OutputFormat {
+    /// no output
+    Off,
+    /// comma separated values, with a header line before the first record
+    Csv,
+    /// a JSON object per line
+    JsonLines,
+    /// a CBOR array per measurement
+    Cbor,
+}
+
+impl OutputFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Off => "off",
+            OutputFormat::Csv => "csv",
+            OutputFormat::JsonLines => "json",
+            OutputFormat::Cbor => "cbor",
+        }
+    }
+
+    /// the format with a name, None if there isn't one
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            OutputFormat::Off,
+            OutputFormat::Csv,
+            OutputFormat::JsonLines,
+            OutputFormat::Cbor,
+        ]
+        .into_iter()
+        .find(|f| f.name() == name)
+    }
+
+    pub(crate) fn from_u8(v: u8) -> Self {
+        match v {
+            1 => OutputFormat::Csv,
+            2 => OutputFormat::JsonLines,
+            3 => OutputFormat::Cbor,
+            _ => OutputFormat::Off,
+        }
+    }
+}
+
+static OUTPUT_FORMAT: AtomicU8 = AtomicU8::new(OutputFormat::Off as u8);
+
+/// set the format of the measurement output
+pub fn set_format(format: OutputFormat) {
+    OUTPUT_FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+/// the format of the measurement output
+pub fn format() -> OutputFormat {
+    OutputFormat::from_u8(OUTPUT_FORMAT.load(Ordering::Relaxed))
+}
+
+/// Errors writing a measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum OutputError {
+    /// the record is longer than `MAX_RECORD`
+    Overflow,
+    /// the sink failed
+    Write,
+}
+
+/// Writes measurements in a format
+///
+/// Keeps the CSV header state, so the header is repeated when the output
+/// changes to CSV.
+#[derive(Debug, Default)]
+pub struct Serializer {
+    header_written: bool,
+}
+
+impl Serializer {
+    pub fn new() -> Self {
+        Serializer {
+            header_written: false,
+        }
+    }
+
+    /// encode a measurement in `format`, replacing the contents of `out`
+    ///
+    /// The first CSV record after a change of format includes the header
+    /// line, `Off` leaves `out` empty.
+    pub fn encode(
+        &mut self,
+        format: OutputFormat,
+        m: &Measurement,
+        out: &mut Record,
+    ) -> Result<(), OutputError> {
+        out.clear();
+        let result = match format {
+            OutputFormat::Off => Ok(()),
+            OutputFormat::Csv => {
+                let header = if self.header_written {
+                    Ok(())
+                } else {
+                    csv_header(out)
+                };
+                header
+                    .and_then(|_| csv_row(m, out))
+                    .map_err(|_| OutputError::Overflow)
+            }
+            OutputFormat::JsonLines => json(m, out).map_err(|_| OutputError::Overflow),
+            OutputFormat::Cbor => cbor(m, out),
+        };
+        self.header_written = format == OutputFormat::Csv && result.is_ok();
+        result
+    }
+
+    /// write a measurement in `format` to `sink`
+    pub async fn write<W: Write>(
+        &mut self,
+        format: OutputFormat,
+        m: &Measurement,
+        sink: &mut W,
+    ) -> Result<(), OutputError> {
+        let mut record = Record::new();
+        self.encode(format, m, &mut record)?;
+        if record.is_empty() {
+            return Ok(());
+        }
+        debug!("output {} record, {} bytes", format, record.len());
+        sink.write_all(&record)
+            .await
+            .map_err(|_| OutputError::Write)?;
+        sink.flush().await.map_err(|_| OutputError::Write)
+    }
+}
+
+/// Formats text into a record
+struct Text<'a>(&'a mut Record);
+
+impl core::fmt::Write for Text<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0
+            .extend_from_slice(s.as_bytes())
+            .map_err(|_| core::fmt::Error)
+    }
+}
+
+/// round a value to the quantity's output precision
+fn round(spec: &QuantitySpec, value: f32) -> f32 {
+    let scale = 10f32.powi(spec.output_precision.into());
+    (value * scale).round() / scale
+}
+
+/// write a value at the quantity's output precision, nothing if it isn't
+/// finite
+fn write_value(out: &mut Text, spec: &QuantitySpec, value: f32) -> core::fmt::Result {
+    if value.is_finite() {
+        write!(
+            out,
+            "{:.*}",
+            spec.output_precision as usize,
+            round(spec, value)
+        )
+    } else {
+        Ok(())
+    }
+}
+
+fn csv_header(out: &mut Record) -> core::fmt::Result {
+    let mut out = Text(out);
+    out.write_str("uptime_ms,unix_time,battery_mv")?;
+    for spec in sensor::quantities() {
+        write!(out, ",{}", spec.kind.key())?;
+    }
+    out.write_str("\r\n")
+}
+
+fn csv_row(m: &Measurement, out: &mut Record) -> core::fmt::Result {
+    let mut out = Text(out);
+    write!(out, "{},", m.uptime_ms)?;
+    if let Some(t) = m.unix_time {
+        write!(out, "{}", t)?;
+    }
+    out.write_char(',')?;
+    if let Some(b) = &m.battery {
+        write!(out, "{}", b.millivolts)?;
+    }
+    for spec in sensor::quantities() {
+        out.write_char(',')?;
+        if let Some(value) = m.readings.get(spec.kind) {
+            write_value(&mut out, spec, value)?;
+        }
+    }
+    out.write_str("\r\n")
+}
+
+fn json(m: &Measurement, out: &mut Record) -> core::fmt::Result {
+    let mut out = Text(out);
+    write!(out, "{{\"uptime_ms\":{}", m.uptime_ms)?;
+    if let Some(t) = m.unix_time {
+        write!(out, ",\"unix_time\":{}", t)?;
+    }
+    if let Some(b) = &m.battery {
+        write!(out, ",\"battery_mv\":{}", b.millivolts)?;
+    }
+    for spec in sensor::quantities() {
+        if let Some(value) = m.readings.get(spec.kind) {
+            write!(out, ",\"{}\":", spec.kind.key())?;
+            if value.is_finite() {
+                write_value(&mut out, spec, value)?;
+            } else {
+                out.write_str("null")?;
+            }
+        }
+    }
+    out.write_str("}\n")
+}
+
+/// CBOR major types and simple values
+const CBOR_UINT: u8 = 0;
+const CBOR_NEGATIVE: u8 = 1;
+const CBOR_ARRAY: u8 = 4;
+const CBOR_MAP: u8 = 5;
+const CBOR_NULL: u8 = 0xf6;
+const CBOR_FLOAT32: u8 = 0xfa;
+
+fn put(out: &mut Record, bytes: &[u8]) -> Result<(), OutputError> {
+    out.extend_from_slice(bytes)
+        .map_err(|_| OutputError::Overflow)
+}
+
+/// write a CBOR item head, in the shortest form
+fn cbor_head(out: &mut Record, major: u8, n: u64) -> Result<(), OutputError> {
+    let major = major << 5;
+    match n {
+        0..=23 => put(out, &[major | n as u8]),
+        24..=0xff => put(out, &[major | 24, n as u8]),
+        0x100..=0xffff => {
+            put(out, &[major | 25])?;
+            put(out, &(n as u16).to_be_bytes())
+        }
+        0x1_0000..=0xffff_ffff => {
+            put(out, &[major | 26])?;
+            put(out, &(n as u32).to_be_bytes())
+        }
+        _ => {
+            put(out, &[major | 27])?;
+            put(out, &n.to_be_bytes())
+        }
+    }
+}
+
+fn cbor_optional(out: &mut Record, n: Option<u64>) -> Result<(), OutputError> {
+    match n {
+        Some(n) => cbor_head(out, CBOR_UINT, n),
+        None => put(out, &[CBOR_NULL]),
+    }
+}
+
+/// write a value as an integer if it's whole, or a float
+fn cbor_value(out: &mut Record, value: f32) -> Result<(), OutputError> {
+    let whole = value.abs() < 1e9 && value as i32 as f32 == value;
+    if whole && value >= 0.0 {
+        cbor_head(out, CBOR_UINT, value as u64)
+    } else if whole {
+        cbor_head(out, CBOR_NEGATIVE, (-1 - value as i64) as u64)
+    } else {
+        put(out, &[CBOR_FLOAT32])?;
+        put(out, &value.to_be_bytes())
+    }
+}
+
+fn cbor(m: &Measurement, out: &mut Record) -> Result<(), OutputError> {
+    let readings = || {
+        sensor::quantities().filter_map(|spec| {
+            m.readings
+                .get(spec.kind)
+                .map(|v| (spec.kind, round(spec, v)))
+        })
+    };
+    cbor_head(out, CBOR_ARRAY, 4)?;
+    cbor_head(out, CBOR_UINT, m.uptime_ms)?;
+    cbor_optional(out, m.unix_time)?;
+    cbor_optional(out, m.battery.as_ref().map(|b| b.millivolts.into()))?;
+    cbor_head(out, CBOR_MAP, readings().count() as u64)?;
+    for (kind, value) in readings() {
+        cbor_head(out, CBOR_UINT, kind as u64)?;
+        cbor_value(out, value)?;
+    }
+    Ok(())
+}
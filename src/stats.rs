@@ -0,0 +1,76 @@
+//! Daily statistics, rolled over at local midnight
+
+use crate::rtc::DateTime;
+use defmt::Format;
+
+/// Minimum, maximum and mean of a quantity
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct Summary {
+    pub min: f32,
+    pub max: f32,
+    sum: f32,
+    pub count: u32,
+}
+
+impl Summary {
+    fn add(&mut self, value: f32) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// the mean, None if there were no values
+    pub fn mean(&self) -> Option<f32> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as f32)
+        }
+    }
+}
+
+/// Statistics for one local day
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct DailyStats {
+    /// local date as (year, month, day)
+    pub date: Option<(u16, u8, u8)>,
+    pub pm2_5: Summary,
+    pub temperature: Summary,
+}
+
+impl DailyStats {
+    /// add the readings from a cycle at local time `now`
+    ///
+    /// When `now` is on a different day to the previous readings, the
+    /// statistics for the finished day are returned, and a new day started.
+    pub fn update(
+        &mut self,
+        now: &DateTime,
+        pm2_5: Option<u16>,
+        temperature: Option<f32>,
+    ) -> Option<DailyStats> {
+        let today = (now.year, now.month, now.day);
+        let finished = match self.date {
+            Some(date) if date != today => {
+                let finished = *self;
+                *self = DailyStats::default();
+                Some(finished)
+            }
+            _ => None,
+        };
+        self.date = Some(today);
+        if let Some(pm) = pm2_5 {
+            self.pm2_5.add(pm as f32);
+        }
+        if let Some(t) = temperature {
+            self.temperature.add(t);
+        }
+        finished
+    }
+}
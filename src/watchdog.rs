@@ -0,0 +1,158 @@
+//! Independent watchdog supervisor with task liveness monitoring
+//!
+//! The supervisor only pets the IWDG while every monitored task has
+//! checked in within `watchdog_task_timeout_sec`. A task is monitored from
+//! its first check in, so a task that was never spawned doesn't cause a
+//! reset. When a task stalls, the supervisor records which one in RAM that
+//! isn't initialized at startup, and stops petting the watchdog. The record
+//! is read back on the next boot.
+
+use crate::parameter::Parameters;
+use core::cell::Cell;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::pin::pin;
+use defmt::{debug, error, Format};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{peripherals, wdg::IndependentWatchdog};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+/// The tasks that are monitored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum TaskId {
+    Env,
+    Pm25,
+    Display,
+    Scd4x,
+    /// drawing the measurement on the e-paper display
+    Screen,
+}
+
+const TASK_COUNT: usize = 5;
+
+impl TaskId {
+    fn from_index(i: u32) -> Option<TaskId> {
+        match i {
+            0 => Some(TaskId::Env),
+            1 => Some(TaskId::Pm25),
+            2 => Some(TaskId::Display),
+            3 => Some(TaskId::Scd4x),
+            4 => Some(TaskId::Screen),
+            _ => None,
+        }
+    }
+}
+
+/// Interval at which tasks check in while waiting
+pub const HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// How often the supervisor checks the tasks
+const SUPERVISOR_PERIOD: Duration = Duration::from_secs(1);
+
+/// time of the last check in of each task, None if it hasn't checked in yet
+static CHECK_INS: Mutex<CriticalSectionRawMutex, Cell<[Option<Instant>; TASK_COUNT]>> =
+    Mutex::new(Cell::new([None; TASK_COUNT]));
+
+/// Record of a stalled task, kept across a reset
+#[repr(C)]
+struct StallRecord {
+    magic: u32,
+    task: u32,
+}
+
+const STALL_MAGIC: u32 = 0x57A1_1ED0;
+
+#[link_section = ".uninit.STALL_RECORD"]
+static mut STALL_RECORD: MaybeUninit<StallRecord> = MaybeUninit::uninit();
+
+/// tell the supervisor the task is alive
+pub fn check_in(task: TaskId) {
+    CHECK_INS.lock(|c| {
+        let mut check_ins = c.get();
+        check_ins[task as usize] = Some(Instant::now());
+        c.set(check_ins);
+    });
+}
+
+/// wait for a future, checking in every `HEARTBEAT` while waiting
+///
+/// Use this for waits that can legitimately take longer than the task
+/// timeout, like waiting for a command signal.
+pub async fn supervised<F: Future>(task: TaskId, fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    loop {
+        check_in(task);
+        if let Either::First(r) = select(fut.as_mut(), Timer::after(HEARTBEAT)).await {
+            check_in(task);
+            return r;
+        }
+    }
+}
+
+/// sleep, checking in every `HEARTBEAT`
+pub async fn sleep(task: TaskId, duration: Duration) {
+    supervised(task, Timer::after(duration)).await
+}
+
+/// find a monitored task that hasn't checked in within the timeout
+fn stalled_task(now: Instant, timeout: Duration) -> Option<TaskId> {
+    let check_ins = CHECK_INS.lock(|c| c.get());
+    check_ins
+        .iter()
+        .enumerate()
+        .find_map(|(i, check_in)| match check_in {
+            Some(t) if now.checked_duration_since(*t).unwrap_or_default() > timeout => {
+                TaskId::from_index(i as u32)
+            }
+            _ => None,
+        })
+}
+
+/// read and clear the record of a task that stalled before the last reset
+pub fn take_stall_record() -> Option<TaskId> {
+    // SAFETY: only called once at startup, before the supervisor is running.
+    // The memory isn't initialized at reset, but every bit pattern is a valid
+    // u32, and the magic number guards against random contents
+    unsafe {
+        let record = STALL_RECORD.as_mut_ptr();
+        let magic = core::ptr::addr_of!((*record).magic).read_volatile();
+        let task = core::ptr::addr_of!((*record).task).read_volatile();
+        core::ptr::addr_of_mut!((*record).magic).write_volatile(0);
+        if magic == STALL_MAGIC {
+            TaskId::from_index(task)
+        } else {
+            None
+        }
+    }
+}
+
+fn write_stall_record(task: TaskId) {
+    // SAFETY: only the supervisor task writes the record
+    unsafe {
+        let record = STALL_RECORD.as_mut_ptr();
+        core::ptr::addr_of_mut!((*record).task).write_volatile(task as u32);
+        core::ptr::addr_of_mut!((*record).magic).write_volatile(STALL_MAGIC);
+    }
+}
+
+/// task to pet the watchdog while all the monitored tasks are alive
+#[embassy_executor::task]
+pub async fn supervisor(
+    mut wdg: IndependentWatchdog<'static, peripherals::IWDG>,
+    params: Parameters,
+) {
+    let timeout = Duration::from_secs(params.watchdog_task_timeout_sec.into());
+    wdg.unleash();
+    debug!("watchdog started");
+    loop {
+        if let Some(task) = stalled_task(Instant::now(), timeout) {
+            error!("task {} stalled, waiting for watchdog reset", task);
+            write_stall_record(task);
+            // stop petting the watchdog
+            core::future::pending::<()>().await;
+        }
+        wdg.pet();
+        Timer::after(SUPERVISOR_PERIOD).await;
+    }
+}
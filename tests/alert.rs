@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::{
+        alert::{AlertInputs, AlertKind, AlertMonitor},
+        parameter::Parameters,
+    };
+    use defmt::assert;
+
+    fn pm(value: f32) -> AlertInputs {
+        AlertInputs {
+            pm2_5: Some(value),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hysteresis() {
+        let params = Parameters::new(104, 212);
+        let mut monitor = AlertMonitor::default();
+        let status = monitor.evaluate(&params, &pm(40.0), 0);
+        assert!(status.active.contains(AlertKind::Pm2_5));
+        assert!(status.triggered.contains(AlertKind::Pm2_5));
+        // below the limit, but within the hysteresis
+        let status = monitor.evaluate(&params, &pm(32.0), 10);
+        assert!(status.active.contains(AlertKind::Pm2_5));
+        assert!(status.triggered.is_empty());
+        let status = monitor.evaluate(&params, &pm(20.0), 20);
+        assert!(status.active.is_empty());
+    }
+
+    #[test]
+    fn holdoff() {
+        let params = Parameters::new(104, 212);
+        let mut monitor = AlertMonitor::default();
+        monitor.evaluate(&params, &pm(40.0), 0);
+        monitor.evaluate(&params, &pm(20.0), 10);
+        // active again, but within the holdoff, so doesn't sound
+        let status = monitor.evaluate(&params, &pm(40.0), 20);
+        assert!(status.active.contains(AlertKind::Pm2_5));
+        assert!(status.triggered.is_empty());
+        monitor.evaluate(&params, &pm(20.0), 30);
+        let status = monitor.evaluate(&params, &pm(40.0), 3600);
+        assert!(status.triggered.contains(AlertKind::Pm2_5));
+    }
+
+    #[test]
+    fn missing_reading_keeps_state() {
+        let params = Parameters::new(104, 212);
+        let mut monitor = AlertMonitor::default();
+        monitor.evaluate(&params, &pm(40.0), 0);
+        let status = monitor.evaluate(&params, &AlertInputs::default(), 10);
+        assert!(status.active.contains(AlertKind::Pm2_5));
+    }
+}
@@ -0,0 +1,29 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::battery::{divided_millivolts, is_low_with_hysteresis};
+    use defmt::{assert, assert_eq};
+
+    #[test]
+    fn fractional_divider() {
+        assert_eq!(divided_millivolts(1200, 2000), 2400);
+        assert_eq!(divided_millivolts(1200, 1500), 1800);
+        assert_eq!(divided_millivolts(1000, 3300), 3300);
+        // saturates rather than wrapping
+        assert_eq!(divided_millivolts(30_000, 3300), u16::MAX);
+    }
+
+    #[test]
+    fn low_flag_hysteresis() {
+        assert!(!is_low_with_hysteresis(16, false, 15, 5));
+        assert!(is_low_with_hysteresis(15, false, 15, 5));
+        // stays low until above 20%
+        assert!(is_low_with_hysteresis(16, true, 15, 5));
+        assert!(is_low_with_hysteresis(20, true, 15, 5));
+        assert!(!is_low_with_hysteresis(21, true, 15, 5));
+    }
+}
@@ -0,0 +1,256 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::{
+        coordinator::{Action, Coordinator, Event, State},
+        diagnostics::SensorFault,
+        env_sensor::BmeFault,
+        parameter::Parameters,
+        pm_correction,
+        pms7003_device::PmState,
+        sensor::{Quantity, Readings, SensorId},
+        DisplayInfo,
+    };
+    use defmt::{assert, assert_eq};
+
+    fn env_data() -> DisplayInfo {
+        let mut r = Readings::new();
+        r.set(Quantity::Temperature, 21.0);
+        r.set(Quantity::Humidity, 40.0);
+        DisplayInfo::Data(SensorId::Env, r)
+    }
+
+    fn pm_data(pm2_5: f32) -> DisplayInfo {
+        let mut r = Readings::new();
+        r.set(Quantity::Pm2_5, pm2_5);
+        DisplayInfo::Data(SensorId::Pm, r)
+    }
+
+    #[test]
+    fn full_cycle() {
+        let params = Parameters::new(104, 212);
+        let mut c = Coordinator::new(params);
+        assert_eq!(c.state(), State::Idle);
+
+        let a = c.handle(
+            Event::Start {
+                battery_low: false,
+                unix_time: None,
+            },
+            0,
+        );
+        assert!(matches!(
+            a[..],
+            [
+                Action::StartCycle,
+                Action::StartSensor(SensorId::Env),
+                Action::StartSensor(SensorId::Pm)
+            ]
+        ));
+        assert_eq!(c.state(), State::Warming);
+        assert_eq!(c.deadline(), Some(20_000));
+
+        let a = c.handle(Event::Sensor(env_data()), 1_000);
+        assert!(matches!(a[..], [Action::StopSensor(SensorId::Env)]));
+        assert_eq!(c.state(), State::Warming);
+        assert_eq!(c.deadline(), Some(110_000));
+
+        let a = c.handle(
+            Event::Sensor(DisplayInfo::PmState(PmState::Sampling)),
+            30_000,
+        );
+        assert!(a.is_empty());
+        assert_eq!(c.state(), State::Sampling);
+
+        let a = c.handle(Event::Sensor(pm_data(3.0)), 40_000);
+        match &a[..] {
+            [Action::StopSensor(SensorId::Pm), Action::Render(snapshot)] => {
+                assert_eq!(snapshot.readings.get(Quantity::Temperature), Some(21.0));
+                // corrected for humidity, with the raw value kept
+                assert_eq!(snapshot.readings.get(Quantity::Pm2_5Raw), Some(3.0));
+                assert_eq!(
+                    snapshot.readings.get(Quantity::Pm2_5),
+                    Some(pm_correction::epa(3.0, 40.0))
+                );
+                assert!(snapshot.readings.get(Quantity::Aqi).is_some());
+                assert_eq!(snapshot.pm_state, PmState::Sampling);
+            }
+            _ => defmt::panic!("unexpected actions {}", a),
+        }
+        assert_eq!(c.state(), State::Rendering);
+        assert_eq!(c.deadline(), None);
+
+        let a = c.handle(Event::RenderDone, 45_000);
+        assert!(matches!(a[..], [Action::Sleep { seconds: 360 }]));
+        assert_eq!(c.state(), State::Sleeping);
+    }
+
+    #[test]
+    fn sensor_timeout() {
+        let params = Parameters::new(104, 212);
+        let mut c = Coordinator::new(params);
+        c.handle(
+            Event::Start {
+                battery_low: false,
+                unix_time: None,
+            },
+            5_000,
+        );
+
+        // before the deadline, nothing happens
+        assert!(c.handle(Event::Tick, 24_999).is_empty());
+        let a = c.handle(Event::Tick, 25_000);
+        assert!(matches!(
+            a[..],
+            [
+                Action::RecordFault(SensorFault::Timeout(SensorId::Env)),
+                Action::StopSensor(SensorId::Env)
+            ]
+        ));
+
+        // late data from the sensor that timed out is ignored
+        assert!(c.handle(Event::Sensor(env_data()), 26_000).is_empty());
+
+        let a = c.handle(Event::Sensor(pm_data(7.0)), 60_000);
+        match &a[..] {
+            [Action::StopSensor(SensorId::Pm), Action::Render(snapshot)] => {
+                assert_eq!(snapshot.readings.get(Quantity::Temperature), None);
+                assert_eq!(snapshot.readings.get(Quantity::Pm2_5), Some(7.0));
+            }
+            _ => defmt::panic!("unexpected actions {}", a),
+        }
+        assert_eq!(c.state(), State::Rendering);
+    }
+
+    #[test]
+    fn fault_when_no_data() {
+        let params = Parameters::new(104, 212);
+        let mut c = Coordinator::new(params);
+        c.handle(
+            Event::Start {
+                battery_low: false,
+                unix_time: None,
+            },
+            0,
+        );
+        let a = c.handle(
+            Event::Sensor(DisplayInfo::Fault(SensorFault::Env(BmeFault::Read))),
+            1_000,
+        );
+        assert!(matches!(
+            a[..],
+            [
+                Action::RecordFault(SensorFault::Env(BmeFault::Read)),
+                Action::StopSensor(SensorId::Env)
+            ]
+        ));
+        let a = c.handle(Event::Tick, 115_000);
+        assert!(matches!(
+            a[..],
+            [
+                Action::RecordFault(SensorFault::Timeout(SensorId::Pm)),
+                Action::StopSensor(SensorId::Pm),
+                Action::Render(_)
+            ]
+        ));
+        assert_eq!(c.state(), State::Fault);
+
+        // retry at the minimum interval
+        let a = c.handle(Event::RenderDone, 120_000);
+        assert!(matches!(a[..], [Action::Sleep { seconds: 180 }]));
+
+        // the next cycle starts from sleep
+        let a = c.handle(
+            Event::Start {
+                battery_low: false,
+                unix_time: None,
+            },
+            300_000,
+        );
+        assert!(matches!(a[0], Action::StartCycle));
+        assert_eq!(c.deadline(), Some(320_000));
+    }
+
+    #[test]
+    fn low_battery_skips_pm() {
+        let params = Parameters::new(104, 212);
+        let mut c = Coordinator::new(params);
+        let a = c.handle(
+            Event::Start {
+                battery_low: true,
+                unix_time: None,
+            },
+            0,
+        );
+        assert!(matches!(
+            a[..],
+            [Action::StartCycle, Action::StartSensor(SensorId::Env)]
+        ));
+        assert_eq!(c.state(), State::Sampling);
+
+        let a = c.handle(Event::Sensor(env_data()), 1_000);
+        match &a[..] {
+            [Action::StopSensor(SensorId::Env), Action::Render(snapshot)] => {
+                assert_eq!(snapshot.readings.get(Quantity::Pm2_5), None)
+            }
+            _ => defmt::panic!("unexpected actions {}", a),
+        }
+        let a = c.handle(Event::RenderDone, 2_000);
+        assert!(matches!(a[..], [Action::Sleep { seconds: 720 }]));
+    }
+
+    /// run a cycle with both sensors, returning the rendered readings
+    fn cycle(c: &mut Coordinator, now_ms: u64, unix_time: Option<u64>) -> Readings {
+        c.handle(
+            Event::Start {
+                battery_low: false,
+                unix_time,
+            },
+            now_ms,
+        );
+        c.handle(Event::Sensor(env_data()), now_ms + 1_000);
+        c.handle(
+            Event::Sensor(DisplayInfo::PmState(PmState::Sampling)),
+            now_ms + 30_000,
+        );
+        let a = c.handle(Event::Sensor(pm_data(10.0)), now_ms + 40_000);
+        c.handle(Event::RenderDone, now_ms + 45_000);
+        match &a[..] {
+            [Action::StopSensor(SensorId::Pm), Action::Render(snapshot)] => {
+                snapshot.readings.clone()
+            }
+            _ => defmt::panic!("unexpected actions {}", a),
+        }
+    }
+
+    #[test]
+    fn averages_follow_the_rtc() {
+        let params = Parameters::new(104, 212);
+        let mut c = Coordinator::new(params);
+        // nothing is kept while the clock isn't set
+        for cycle_ms in [0, 3_600_000, 7_200_000] {
+            let r = cycle(&mut c, cycle_ms, None);
+            assert_eq!(r.get(Quantity::Pm2_5NowCast), None);
+        }
+        // cycles an hour apart on the RTC, but only minutes apart in
+        // uptime, as the uptime stops in STOP mode
+        let t = 1_700_000_000;
+        let r = cycle(&mut c, 8_000_000, Some(t));
+        assert_eq!(r.get(Quantity::Pm2_5NowCast), None);
+        let r = cycle(&mut c, 8_100_000, Some(t + 3600));
+        assert!(r.get(Quantity::Pm2_5NowCast).is_some());
+    }
+
+    #[test]
+    fn ignores_out_of_order_events() {
+        let params = Parameters::new(104, 212);
+        let mut c = Coordinator::new(params);
+        assert!(c.handle(Event::RenderDone, 0).is_empty());
+        assert!(c.handle(Event::Tick, 0).is_empty());
+        assert_eq!(c.state(), State::Idle);
+    }
+}
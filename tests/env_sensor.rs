@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::{
+        env_sensor::{bme688_gas_resistance, sht4x_convert, Bme280Calibration, EnvData},
+        sensor::Quantity,
+    };
+    use defmt::{assert, assert_eq};
+
+    #[test]
+    fn bme280_compensation() {
+        // the temperature and pressure calibration from the datasheet
+        // example, with typical humidity calibration
+        let calib = [
+            112, 107, 67, 103, 24, 252, 125, 142, 67, 214, 208, 11, 39, 11, 140, 0, 249, 255, 140,
+            60, 248, 198, 112, 23, 0, 75,
+        ];
+        let calib_h = [106, 1, 0, 19, 41, 3, 30];
+        let calibration = Bme280Calibration::from_registers(&calib, &calib_h);
+        let (temperature, pressure, humidity) = calibration.compensate(519888, 415148, 30000);
+        // 25.08 C and 1006.53 hPa in the datasheet
+        assert!(temperature > 25.07 && temperature < 25.09);
+        assert!(pressure > 1006.52 && pressure < 1006.54);
+        assert!(humidity > 54.9 && humidity < 55.1);
+    }
+
+    #[test]
+    fn sht4x_conversion() {
+        let (temperature, humidity) = sht4x_convert(&[0x6666, 0x8000]);
+        assert!(temperature > 24.99 && temperature < 25.01);
+        assert!(humidity > 56.49 && humidity < 56.51);
+        // humidity is clamped
+        let (_, humidity) = sht4x_convert(&[0x6666, 0x0000]);
+        assert_eq!(humidity, 0.0);
+    }
+
+    #[test]
+    fn bme688_gas_decode() {
+        // gas_r_h 512, range 10, valid and stable
+        assert_eq!(bme688_gas_resistance(&[0x80, 0x3a]), Some(62_500));
+        // gas_r_h 625, range 5, valid and stable, 1847125 ohms
+        let gas = bme688_gas_resistance(&[0x9c, 0x75]).unwrap();
+        assert!(gas > 1_847_000 && gas < 1_847_250);
+        // not valid
+        assert_eq!(bme688_gas_resistance(&[0x80, 0x1a]), None);
+        // heater not stable
+        assert_eq!(bme688_gas_resistance(&[0x80, 0x2a]), None);
+    }
+
+    #[test]
+    fn absent_values_left_out() {
+        let data = EnvData {
+            temperature: 21.0,
+            humidity: 40.0,
+            pressure: None,
+            gas_resistance: None,
+        };
+        let readings = data.readings(250_000);
+        assert_eq!(readings.get(Quantity::Temperature), Some(21.0));
+        assert_eq!(readings.get(Quantity::Pressure), None);
+        assert_eq!(readings.get(Quantity::GasResistance), None);
+        assert_eq!(readings.get(Quantity::Iaq), None);
+    }
+
+    #[test]
+    fn iaq_from_gas() {
+        let data = EnvData {
+            temperature: 21.0,
+            humidity: 40.0,
+            pressure: Some(1013.0),
+            gas_resistance: Some(250_000),
+        };
+        // ideal humidity and clean air
+        assert_eq!(data.iaq(250_000), Some(0));
+        assert_eq!(data.iaq(0), None);
+        let readings = data.readings(250_000);
+        assert_eq!(readings.get(Quantity::Iaq), Some(0.0));
+    }
+}
@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::{
+        battery::BatteryStatus,
+        measurement::Measurement,
+        modbus::{crc16, Frame, ModbusSlave, STATUS_CLOCK_SET, STATUS_MEASURED},
+        parameter::Parameters,
+        pms7003_device::PmState,
+        rtc,
+        sensor::{Quantity, Readings},
+        serializer::{self, OutputFormat},
+    };
+    use defmt::{assert, assert_eq};
+
+    /// a frame with its CRC
+    fn frame(bytes: &[u8]) -> Frame {
+        let mut frame = Frame::new();
+        frame.extend_from_slice(bytes).unwrap();
+        let crc = crc16(bytes);
+        frame.extend_from_slice(&crc.to_le_bytes()).unwrap();
+        frame
+    }
+
+    fn slave() -> ModbusSlave {
+        ModbusSlave::new(Parameters::new(104, 212))
+    }
+
+    fn measurement() -> Measurement {
+        let mut readings = Readings::new();
+        readings.set(Quantity::Temperature, -2.5);
+        readings.set(Quantity::Humidity, 45.0);
+        readings.set(Quantity::GasResistance, 250_000.0);
+        readings.set(Quantity::Pm2_5, 12.3);
+        Measurement {
+            uptime_ms: 60_000,
+            unix_time: Some(1_700_000_000),
+            readings,
+            pm_state: PmState::Sampling,
+            battery: Some(BatteryStatus {
+                millivolts: 3900,
+                percent: 80,
+                low: false,
+            }),
+        }
+    }
+
+    #[test]
+    fn crc() {
+        // the example in the Modbus serial line specification
+        assert_eq!(crc16(&[0x11, 0x03, 0x00, 0x6b, 0x00, 0x03]), 0x8776);
+    }
+
+    #[test]
+    fn reads_input_registers() {
+        let mut slave = slave();
+        slave.update(&measurement());
+        let response = slave.handle(&frame(&[0x01, 0x04, 0x00, 0x00, 0x00, 0x06]));
+        assert_eq!(
+            response,
+            Some(frame(&[
+                0x01, 0x04, 12, // 6 registers
+                0x00, 0x27, // status
+                0x00, 0x01, // count
+                0x00, 0x00, 0x00, 0x3c, // uptime 60s
+                0xff, 0x06, // -2.50 C
+                0x11, 0x94, // 45.00 %
+            ]))
+        );
+        assert_eq!(slave.input(7), Some(0x0003));
+        assert_eq!(slave.input(8), Some(0xd090));
+        assert_eq!(slave.input(11), Some(123));
+        assert_eq!(slave.input(19), Some(3900));
+    }
+
+    #[test]
+    fn absent_readings() {
+        let mut slave = slave();
+        assert_eq!(slave.input(0), Some(0));
+        assert_eq!(slave.input(4), Some(0x8000));
+        let mut m = measurement();
+        m.readings = Readings::new();
+        m.unix_time = None;
+        slave.update(&m);
+        assert_eq!(slave.input(0), Some(STATUS_MEASURED));
+        assert_eq!(slave.input(4), Some(0x8000));
+        assert_eq!(slave.input(7), Some(0xffff));
+        assert_eq!(slave.input(8), Some(0xffff));
+        assert_eq!(slave.input(11), Some(0xffff));
+        m.unix_time = Some(0);
+        slave.update(&m);
+        assert_eq!(slave.input(0), Some(STATUS_MEASURED | STATUS_CLOCK_SET));
+        assert_eq!(slave.input(1), Some(2));
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut slave = slave();
+        // past the last input register
+        assert_eq!(
+            slave.handle(&frame(&[0x01, 0x04, 0x00, 0x14, 0x00, 0x02])),
+            Some(frame(&[0x01, 0x84, 0x02]))
+        );
+        // read only holding register
+        assert_eq!(
+            slave.handle(&frame(&[0x01, 0x06, 0x00, 0x00, 0x00, 0x3c])),
+            Some(frame(&[0x01, 0x86, 0x02]))
+        );
+        // unknown output format
+        assert_eq!(
+            slave.handle(&frame(&[0x01, 0x06, 0x00, 0x0d, 0x00, 0x09])),
+            Some(frame(&[0x01, 0x86, 0x03]))
+        );
+        // read coils
+        assert_eq!(
+            slave.handle(&frame(&[0x01, 0x01, 0x00, 0x00, 0x00, 0x01])),
+            Some(frame(&[0x01, 0x81, 0x01]))
+        );
+    }
+
+    #[test]
+    fn writes_holding_registers() {
+        let mut slave = slave();
+        let request = frame(&[0x01, 0x06, 0x00, 0x0c, 0x00, 0x3c]);
+        assert_eq!(slave.handle(&request), Some(request.clone()));
+        assert_eq!(rtc::utc_offset(), 60);
+        // UTC-1:00 and JSON lines
+        assert_eq!(
+            slave.handle(&frame(&[
+                0x01, 0x10, 0x00, 0x0c, 0x00, 0x02, 0x04, 0xff, 0xc4, 0x00, 0x02
+            ])),
+            Some(frame(&[0x01, 0x10, 0x00, 0x0c, 0x00, 0x02]))
+        );
+        assert_eq!(rtc::utc_offset(), -60);
+        assert_eq!(serializer::format(), OutputFormat::JsonLines);
+        assert_eq!(slave.holding(12), Some(0xffc4));
+        // nothing is written if a register can't be
+        assert!(slave
+            .handle(&frame(&[
+                0x01, 0x10, 0x00, 0x0c, 0x00, 0x02, 0x04, 0x00, 0x00, 0x00, 0x09
+            ]))
+            .is_some());
+        assert_eq!(rtc::utc_offset(), -60);
+        rtc::set_utc_offset(0);
+        serializer::set_format(OutputFormat::Off);
+    }
+
+    #[test]
+    fn ignored_requests() {
+        let mut slave = slave();
+        let mut request = frame(&[0x01, 0x04, 0x00, 0x00, 0x00, 0x01]);
+        request[2] ^= 1;
+        assert_eq!(slave.handle(&request), None);
+        assert_eq!(
+            slave.handle(&frame(&[0x02, 0x04, 0x00, 0x00, 0x00, 0x01])),
+            None
+        );
+        // broadcasts are carried out without a reply
+        assert_eq!(
+            slave.handle(&frame(&[0x00, 0x06, 0x00, 0x0d, 0x00, 0x01])),
+            None
+        );
+        assert_eq!(serializer::format(), OutputFormat::Csv);
+        serializer::set_format(OutputFormat::Off);
+    }
+}
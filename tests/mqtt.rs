@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(async_fn_in_trait)]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+/// A transport that replays the coprocessor's responses
+struct MockUart<'a> {
+    rx: &'a [u8],
+    tx: heapless::Vec<u8, 512>,
+}
+
+impl<'a> MockUart<'a> {
+    fn new(rx: &'a [u8]) -> Self {
+        MockUart {
+            rx,
+            tx: heapless::Vec::new(),
+        }
+    }
+}
+
+impl embedded_io_async::ErrorType for MockUart<'_> {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io_async::Read for MockUart<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = buf.len().min(self.rx.len());
+        buf[..n].copy_from_slice(&self.rx[..n]);
+        self.rx = &self.rx[n..];
+        Ok(n)
+    }
+}
+
+impl embedded_io_async::Write for MockUart<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.tx.extend_from_slice(buf).ok();
+        Ok(buf.len())
+    }
+}
+
+#[defmt_test::tests]
+mod tests {
+    use super::MockUart;
+    use atmo_monitor_stm32::{
+        esp_at::{write_quoted, AtError, Command, EspAt},
+        mqtt::{discovery_config, discovery_topic, state_topic, Config},
+        parameter::Parameters,
+        sensor::{self, Quantity},
+    };
+    use defmt::{assert, assert_eq};
+    use embassy_futures::block_on;
+
+    #[test]
+    fn command_ok() {
+        let mut uart = MockUart::new(b"\r\nOK\r\n");
+        let mut at = EspAt::new(&mut uart);
+        assert_eq!(block_on(at.command("ATE0")), Ok(()));
+        assert_eq!(&uart.tx[..], b"ATE0\r\n");
+    }
+
+    #[test]
+    fn skips_reports() {
+        let mut uart = MockUart::new(b"WIFI CONNECTED\r\nWIFI GOT IP\r\n\r\nOK\r\n");
+        let mut at = EspAt::new(&mut uart);
+        assert_eq!(block_on(at.command("AT+CWJAP=\"net\",\"pass\"")), Ok(()));
+    }
+
+    #[test]
+    fn command_fails() {
+        let mut uart = MockUart::new(b"+CWJAP:1\r\n\r\nFAIL\r\n");
+        let mut at = EspAt::new(&mut uart);
+        assert_eq!(
+            block_on(at.command("AT+CWJAP=\"net\",\"pass\"")),
+            Err(AtError::Failed)
+        );
+        let mut uart = MockUart::new(b"\r\nERROR\r\n");
+        let mut at = EspAt::new(&mut uart);
+        assert_eq!(block_on(at.command("AT+MQTTCONN?")), Err(AtError::Failed));
+        // the transport ends before the response
+        let mut uart = MockUart::new(b"\r\nO");
+        let mut at = EspAt::new(&mut uart);
+        assert_eq!(block_on(at.command("AT")), Err(AtError::Io));
+    }
+
+    #[test]
+    fn publishes_raw() {
+        let mut uart = MockUart::new(b"\r\nOK\r\n\r\n>+MQTTPUB:OK\r\n");
+        let mut at = EspAt::new(&mut uart);
+        assert_eq!(
+            block_on(at.publish("atmo/atmo_monitor/state", b"{\"a\":1}", false)),
+            Ok(())
+        );
+        assert_eq!(
+            &uart.tx[..],
+            &b"AT+MQTTPUBRAW=0,\"atmo/atmo_monitor/state\",7,0,0\r\n{\"a\":1}"[..]
+        );
+        let mut uart = MockUart::new(b"\r\nOK\r\n\r\n>+MQTTPUB:FAIL\r\n");
+        let mut at = EspAt::new(&mut uart);
+        assert_eq!(
+            block_on(at.publish("atmo/atmo_monitor/status", b"online", true)),
+            Err(AtError::Failed)
+        );
+    }
+
+    #[test]
+    fn quotes_arguments() {
+        let mut command = Command::new();
+        write_quoted(&mut command, "my,\"net\\").unwrap();
+        assert_eq!(command.as_str(), "\"my\\,\\\"net\\\\\"");
+    }
+
+    #[test]
+    fn topics() {
+        let params = Parameters::new(104, 212);
+        assert_eq!(state_topic(&params).as_str(), "atmo/atmo_monitor/state");
+        assert_eq!(
+            discovery_topic(&params, Quantity::Pm2_5).as_str(),
+            "homeassistant/sensor/atmo_monitor/pm2_5/config"
+        );
+    }
+
+    #[test]
+    fn discovery() {
+        let params = Parameters::new(104, 212);
+        let mut config = Config::new();
+        let spec = sensor::spec(Quantity::Pm2_5).unwrap();
+        discovery_config(&params, spec, &mut config).unwrap();
+        for part in [
+            "\"unique_id\":\"atmo_monitor_pm2_5\"",
+            "\"state_topic\":\"atmo/atmo_monitor/state\"",
+            "\"value_template\":\"{{ value_json.pm2_5 }}\"",
+            "\"availability_topic\":\"atmo/atmo_monitor/status\"",
+            "\"device_class\":\"pm25\"",
+            "\"unit_of_measurement\":\"\u{B5}g/m\u{B3}\"",
+        ] {
+            assert!(config.contains(part));
+        }
+        assert!(config.ends_with("}}"));
+        // every quantity's config fits
+        for spec in sensor::quantities() {
+            discovery_config(&params, spec, &mut config).unwrap();
+        }
+    }
+}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::nowcast::{PmHistory, HOUR_SEC};
+    use defmt::assert_eq;
+
+    #[test]
+    fn constant_concentration() {
+        let mut history = PmHistory::new();
+        for hour in 0..30 {
+            history.add(hour * HOUR_SEC, 10.0);
+        }
+        assert_eq!(history.nowcast(29 * HOUR_SEC), Some(10.0));
+        assert_eq!(history.average_24h(29 * HOUR_SEC), Some(10.0));
+    }
+
+    #[test]
+    fn needs_two_recent_hours() {
+        let mut history = PmHistory::new();
+        history.add(0, 10.0);
+        history.add(10 * 60, 30.0);
+        assert_eq!(history.hourly(20 * 60, 0), Some(20.0));
+        assert_eq!(history.nowcast(20 * 60), None);
+    }
+
+    #[test]
+    fn weights_recent_hours() {
+        let mut history = PmHistory::new();
+        history.add(0, 20.0);
+        history.add(HOUR_SEC, 40.0);
+        // weight factor 0.5: (40 + 0.5 * 20) / 1.5
+        assert_eq!(history.nowcast(HOUR_SEC), Some(33.3));
+    }
+
+    #[test]
+    fn old_hours_left_out() {
+        let mut history = PmHistory::new();
+        history.add(0, 50.0);
+        history.add(HOUR_SEC, 50.0);
+        // the slots now belong to hours a day later
+        assert_eq!(history.hourly(24 * HOUR_SEC, 0), None);
+        assert_eq!(history.nowcast(24 * HOUR_SEC), None);
+    }
+
+    #[test]
+    fn day_average_needs_18_hours() {
+        let mut history = PmHistory::new();
+        for hour in 0..17 {
+            history.add(hour * HOUR_SEC, 10.0);
+        }
+        assert_eq!(history.average_24h(16 * HOUR_SEC), None);
+        history.add(17 * HOUR_SEC, 28.0);
+        assert_eq!(history.average_24h(17 * HOUR_SEC), Some(11.0));
+    }
+
+    #[test]
+    fn gaps_between_cycles() {
+        let mut history = PmHistory::new();
+        // a cycle every 2 hours, from an RTC time, the hours between have
+        // no data
+        let start = 1_700_000_000 / HOUR_SEC * HOUR_SEC;
+        for cycle in 0..12 {
+            history.add(start + cycle * 2 * HOUR_SEC + 600, 10.0);
+        }
+        let now = start + 22 * HOUR_SEC + 900;
+        assert_eq!(history.hourly(now, 0), Some(10.0));
+        assert_eq!(history.hourly(now, 1), None);
+        // only one of the last three hours has data
+        assert_eq!(history.nowcast(now + HOUR_SEC), None);
+        // two of the last three hours
+        assert_eq!(history.nowcast(now), Some(10.0));
+        // 12 hours with data, too few for a day average
+        assert_eq!(history.average_24h(now), None);
+        // a long gap, as after a reset or while the PM sensor was skipped
+        history.add(now + 30 * HOUR_SEC, 40.0);
+        assert_eq!(history.hourly(now + 30 * HOUR_SEC, 0), Some(40.0));
+        assert_eq!(history.nowcast(now + 30 * HOUR_SEC), None);
+    }
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::{
+        pms7003_device::{AveragingStrategy, OutlierLimit, PmSensorData},
+        sensor::Quantity,
+    };
+    use defmt::{assert, assert_eq};
+
+    fn frame(pm2_5: u16) -> PmSensorData {
+        PmSensorData {
+            pm2_5_atm: pm2_5,
+            count_0_3: pm2_5,
+            ..Default::default()
+        }
+    }
+
+    const LIMIT: OutlierLimit = OutlierLimit {
+        min_deviation: 10,
+        percent: 50,
+    };
+
+    #[test]
+    fn empty_has_no_average() {
+        assert!(PmSensorData::average(&[], AveragingStrategy::Mean, None).is_none());
+        assert!(PmSensorData::average(&[], AveragingStrategy::Median, Some(LIMIT)).is_none());
+    }
+
+    #[test]
+    fn strategies() {
+        let data = [frame(10), frame(12), frame(11), frame(500), frame(9)];
+        let avg = PmSensorData::average(&data, AveragingStrategy::Mean, None).unwrap();
+        assert_eq!(avg.data.pm2_5_atm, 108);
+        assert_eq!(avg.data.count_0_3, 108);
+        let avg = PmSensorData::average(&data, AveragingStrategy::Median, None).unwrap();
+        assert_eq!(avg.data.pm2_5_atm, 11);
+        let avg = PmSensorData::average(&data, AveragingStrategy::TrimmedMean, None).unwrap();
+        assert_eq!(avg.data.pm2_5_atm, 11);
+        assert_eq!(avg.samples, 5);
+        assert_eq!(avg.quality(), 100);
+    }
+
+    #[test]
+    fn median_of_even_count() {
+        let data = [frame(4), frame(6)];
+        let avg = PmSensorData::average(&data, AveragingStrategy::Median, None).unwrap();
+        assert_eq!(avg.data.pm2_5_atm, 5);
+    }
+
+    #[test]
+    fn outlier_rejected() {
+        let data = [frame(10), frame(12), frame(11), frame(500), frame(9)];
+        let avg = PmSensorData::average(&data, AveragingStrategy::Mean, Some(LIMIT)).unwrap();
+        assert_eq!(avg.data.pm2_5_atm, 10);
+        assert_eq!(avg.samples, 4);
+        assert_eq!(avg.rejected, 1);
+        assert_eq!(avg.quality(), 80);
+        // the quality is passed on with the readings
+        assert_eq!(avg.readings().get(Quantity::PmQuality), Some(80.0));
+    }
+
+    #[test]
+    fn all_rejected_keeps_all() {
+        // the median is 50, both frames are further than the limit from it
+        let data = [frame(0), frame(100)];
+        for strategy in [
+            AveragingStrategy::Mean,
+            AveragingStrategy::Median,
+            AveragingStrategy::TrimmedMean,
+        ] {
+            let avg = PmSensorData::average(&data, strategy, Some(LIMIT)).unwrap();
+            assert_eq!(avg.data.pm2_5_atm, 50);
+            assert_eq!(avg.samples, 2);
+            assert_eq!(avg.rejected, 0);
+        }
+    }
+
+    #[test]
+    fn no_overflow() {
+        let data = [frame(u16::MAX); 20];
+        let avg = PmSensorData::average(&data, AveragingStrategy::Mean, None).unwrap();
+        assert_eq!(avg.data.pm2_5_atm, u16::MAX);
+        assert_eq!(avg.samples, 16);
+    }
+}
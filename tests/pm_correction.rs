@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::{
+        parameter::Parameters,
+        pm_correction::{self, aqi, epa, kohler, HumidityCorrection},
+        sensor::{Quantity, Readings},
+    };
+    use defmt::{assert, assert_eq};
+
+    fn near(a: f32, b: f32) -> bool {
+        a > b - 0.01 && a < b + 0.01
+    }
+
+    #[test]
+    fn epa_correction() {
+        assert!(near(epa(3.0, 40.0), 3.874));
+        assert!(near(epa(100.0, 50.0), 80.04));
+        assert!(near(epa(300.0, 50.0), 289.526));
+        // the pieces join at the breakpoints
+        assert!(near(epa(29.999, 60.0), epa(30.0, 60.0)));
+        assert!(near(epa(260.0, 60.0), epa(259.999, 60.0)));
+        // never negative in clean humid air
+        assert_eq!(epa(0.0, 95.0), 0.0);
+    }
+
+    #[test]
+    fn kohler_correction() {
+        assert!(near(kohler(20.0, 50.0, 0.4), 14.286));
+        assert_eq!(kohler(20.0, 0.0, 0.4), 20.0);
+        // the humidity is limited near saturation
+        assert_eq!(kohler(20.0, 99.0, 0.4), kohler(20.0, 95.0, 0.4));
+    }
+
+    #[test]
+    fn aqi_breakpoints() {
+        assert_eq!(aqi(0.0), 0);
+        assert_eq!(aqi(9.0), 50);
+        assert_eq!(aqi(9.05), 50);
+        assert_eq!(aqi(12.0), 56);
+        assert_eq!(aqi(35.4), 100);
+        assert_eq!(aqi(35.5), 101);
+        assert_eq!(aqi(325.4), 500);
+        assert_eq!(aqi(600.0), 500);
+    }
+
+    #[test]
+    fn keeps_raw_value() {
+        let mut params = Parameters::new(104, 212);
+        params.pm25_humidity_correction = HumidityCorrection::Kohler { kappa: 0.4 };
+        let mut r = Readings::new();
+        r.set(Quantity::Pm2_5, 20.0);
+        r.set(Quantity::Humidity, 50.0);
+        pm_correction::apply(&mut r, &params);
+        assert_eq!(r.get(Quantity::Pm2_5Raw), Some(20.0));
+        assert!(near(r.get(Quantity::Pm2_5).unwrap(), 14.286));
+        assert_eq!(r.get(Quantity::Aqi), Some(aqi(14.286) as f32));
+    }
+
+    #[test]
+    fn uncorrected_without_humidity() {
+        let params = Parameters::new(104, 212);
+        let mut r = Readings::new();
+        r.set(Quantity::Pm2_5, 20.0);
+        pm_correction::apply(&mut r, &params);
+        assert_eq!(r.get(Quantity::Pm2_5), Some(20.0));
+        assert_eq!(r.get(Quantity::Pm2_5Raw), Some(20.0));
+        assert_eq!(r.get(Quantity::Aqi), Some(71.0));
+    }
+}
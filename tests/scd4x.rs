@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::scd4x_device::{convert_measurement, crc8, decode_words, Scd4xFault};
+    use defmt::{assert, assert_eq};
+
+    #[test]
+    fn crc() {
+        // example from the datasheet
+        assert_eq!(crc8(&[0xbe, 0xef]), 0x92);
+    }
+
+    #[test]
+    fn decodes_measurement() {
+        // 500 ppm, 25 C, 37 %RH, from the datasheet
+        let buf = [
+            0x01,
+            0xf4,
+            crc8(&[0x01, 0xf4]),
+            0x66,
+            0x67,
+            crc8(&[0x66, 0x67]),
+            0x5e,
+            0xb9,
+            crc8(&[0x5e, 0xb9]),
+        ];
+        let mut words = [0u16; 3];
+        decode_words(&buf, &mut words).unwrap();
+        let data = convert_measurement(&words);
+        assert_eq!(data.co2, 500);
+        assert!(data.temperature > 24.99 && data.temperature < 25.01);
+        assert!(data.humidity > 36.99 && data.humidity < 37.01);
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let buf = [0x01, 0xf4, 0x00];
+        let mut words = [0u16; 1];
+        assert_eq!(decode_words(&buf, &mut words), Err(Scd4xFault::Crc));
+    }
+}
@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::{parameter::Parameters, scheduler::AdaptiveScheduler};
+    use defmt::assert_eq;
+
+    #[test]
+    fn backs_off_when_clean() {
+        let params = Parameters::new(104, 212);
+        let mut s = AdaptiveScheduler::new(&params);
+        assert_eq!(s.next_interval(&params, Some(3), Some(20)), 360);
+        assert_eq!(s.next_interval(&params, Some(3), Some(20)), 720);
+        assert_eq!(s.next_interval(&params, Some(3), Some(20)), 1440);
+        assert_eq!(s.next_interval(&params, Some(3), Some(20)), 1440);
+    }
+
+    #[test]
+    fn fast_when_elevated_or_rising() {
+        let params = Parameters::new(104, 212);
+        let mut s = AdaptiveScheduler::new(&params);
+        s.next_interval(&params, Some(3), None);
+        s.next_interval(&params, Some(3), None);
+        // rising, but below the threshold
+        assert_eq!(s.next_interval(&params, Some(9), None), 180);
+        assert_eq!(s.next_interval(&params, Some(9), None), 360);
+        // above the threshold
+        assert_eq!(s.next_interval(&params, Some(30), None), 180);
+        assert_eq!(s.next_interval(&params, None, Some(150)), 180);
+    }
+}
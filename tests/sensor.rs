@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::sensor::{self, Quantity, Readings, SensorId};
+    use defmt::{assert, assert_eq};
+    use heapless::String;
+
+    #[test]
+    fn readings_set_and_merge() {
+        let mut a = Readings::new();
+        assert!(a.is_empty());
+        a.set(Quantity::Temperature, 20.0);
+        a.set(Quantity::Temperature, 21.0);
+        assert_eq!(a.iter().count(), 1);
+        assert_eq!(a.get(Quantity::Temperature), Some(21.0));
+        assert_eq!(a.get(Quantity::Pm2_5), None);
+
+        let mut b = Readings::new();
+        b.set(Quantity::Pm2_5, 8.0);
+        b.set(Quantity::Temperature, 22.0);
+        a.merge(&b);
+        assert_eq!(a.get(Quantity::Pm2_5), Some(8.0));
+        assert_eq!(a.get(Quantity::Temperature), Some(22.0));
+    }
+
+    #[test]
+    fn default_registry() {
+        assert!(sensor::is_included(SensorId::Env));
+        assert!(sensor::is_included(SensorId::Pm));
+        assert!(sensor::spec(Quantity::Pm2_5).is_some());
+    }
+
+    #[test]
+    fn formats_value() {
+        let spec = sensor::spec(Quantity::Pressure).unwrap();
+        let mut s: String<16> = String::new();
+        spec.write_value(&mut s, 1013.4).unwrap();
+        assert_eq!(s.as_str(), "1013 hPa");
+        let spec = sensor::spec(Quantity::Humidity).unwrap();
+        s.clear();
+        spec.write_value(&mut s, 45.6).unwrap();
+        assert_eq!(s.as_str(), "46%");
+    }
+}
@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::{
+        battery::BatteryStatus,
+        console::{parse, Command},
+        measurement::Measurement,
+        pms7003_device::PmState,
+        sensor::{Quantity, Readings},
+        serializer::{OutputFormat, Record, Serializer},
+    };
+    use defmt::{assert, assert_eq};
+
+    fn measurement() -> Measurement {
+        let mut readings = Readings::new();
+        readings.set(Quantity::Temperature, 21.53);
+        readings.set(Quantity::Humidity, 45.0);
+        readings.set(Quantity::Pm2_5, 12.0);
+        Measurement {
+            uptime_ms: 60_000,
+            unix_time: Some(1_700_000_000),
+            readings,
+            pm_state: PmState::Sampling,
+            battery: Some(BatteryStatus {
+                millivolts: 3900,
+                percent: 80,
+                low: false,
+            }),
+        }
+    }
+
+    #[test]
+    fn csv_header_then_rows() {
+        let mut serializer = Serializer::new();
+        let mut out = Record::new();
+        serializer
+            .encode(OutputFormat::Csv, &measurement(), &mut out)
+            .unwrap();
+        assert!(out.starts_with(b"uptime_ms,unix_time,battery_mv,temperature,humidity,"));
+        // values at their output precision, quantities without a reading
+        // are left empty
+        let row = b"60000,1700000000,3900,21.5,45.0,,,,,12.0,,,,,,,,,,,,,,\r\n";
+        assert!(out.ends_with(row));
+        serializer
+            .encode(OutputFormat::Csv, &measurement(), &mut out)
+            .unwrap();
+        assert_eq!(&out[..], &row[..]);
+        // the header is repeated after a change of format
+        serializer
+            .encode(OutputFormat::JsonLines, &measurement(), &mut out)
+            .unwrap();
+        serializer
+            .encode(OutputFormat::Csv, &measurement(), &mut out)
+            .unwrap();
+        assert!(out.starts_with(b"uptime_ms,"));
+    }
+
+    #[test]
+    fn json_line() {
+        let mut serializer = Serializer::new();
+        let mut out = Record::new();
+        let mut m = measurement();
+        m.unix_time = None;
+        serializer
+            .encode(OutputFormat::JsonLines, &m, &mut out)
+            .unwrap();
+        assert_eq!(
+            &out[..],
+            &b"{\"uptime_ms\":60000,\"battery_mv\":3900,\"temperature\":21.5,\"humidity\":45.0,\"pm2_5\":12.0}\n"[..]
+        );
+    }
+
+    #[test]
+    fn cbor_record() {
+        let mut serializer = Serializer::new();
+        let mut out = Record::new();
+        let mut m = measurement();
+        m.battery = None;
+        serializer.encode(OutputFormat::Cbor, &m, &mut out).unwrap();
+        assert_eq!(
+            &out[..],
+            &[
+                0x84, // array of 4
+                0x19, 0xea, 0x60, // uptime 60000
+                0x1a, 0x65, 0x53, 0xf1, 0x00, // Unix time
+                0xf6, // no battery
+                0xa3, // map of 3 readings
+                0x00, 0xfa, 0x41, 0xac, 0x00, 0x00, // temperature rounded to 21.5
+                0x01, 0x18, 0x2d, // humidity 45
+                0x06, 0x0c, // pm2.5 12
+            ]
+        );
+    }
+
+    #[test]
+    fn off_writes_nothing() {
+        let mut serializer = Serializer::new();
+        let mut out = Record::new();
+        serializer
+            .encode(OutputFormat::Off, &measurement(), &mut out)
+            .unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn writes_to_sink() {
+        let mut serializer = Serializer::new();
+        let mut buf = [0u8; 128];
+        let mut sink = &mut buf[..];
+        embassy_futures::block_on(serializer.write(
+            OutputFormat::JsonLines,
+            &measurement(),
+            &mut sink,
+        ))
+        .unwrap();
+        let written = 128 - sink.len();
+        assert!(buf[..written].starts_with(b"{\"uptime_ms\":60000,"));
+        assert_eq!(buf[written - 1], b'\n');
+    }
+
+    #[test]
+    fn output_command() {
+        assert_eq!(parse("output"), Ok(Command::ShowOutput));
+        assert_eq!(
+            parse("output cbor"),
+            Ok(Command::SetOutput(OutputFormat::Cbor))
+        );
+        assert!(parse("output xml").is_err());
+    }
+}
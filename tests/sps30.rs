@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+use atmo_monitor_stm32 as _; // memory layout + panic handler
+
+#[defmt_test::tests]
+mod tests {
+    use atmo_monitor_stm32::pm_sensor::{shdlc_decode, shdlc_encode, sps30_data, PmError};
+    use defmt::{assert, assert_eq};
+
+    #[test]
+    fn encodes_request() {
+        // start measurement and read measured values, from the datasheet
+        assert_eq!(
+            &shdlc_encode(0x00, &[0x01, 0x03])[..],
+            &[0x7e, 0x00, 0x00, 0x02, 0x01, 0x03, 0xf9, 0x7e]
+        );
+        assert_eq!(
+            &shdlc_encode(0x03, &[])[..],
+            &[0x7e, 0x00, 0x03, 0x00, 0xfc, 0x7e]
+        );
+    }
+
+    #[test]
+    fn stuffs_reserved_bytes() {
+        // the wake up command id is 0x11
+        assert_eq!(
+            &shdlc_encode(0x11, &[])[..],
+            &[0x7e, 0x00, 0x7d, 0x31, 0x00, 0xee, 0x7e]
+        );
+    }
+
+    #[test]
+    fn decodes_response() {
+        let response = shdlc_decode(&[0x00, 0x00, 0x00, 0x00, 0xff]).unwrap();
+        assert_eq!(response.command, 0x00);
+        assert!(response.data.is_empty());
+        // data bytes 0x11 and 0x7e, stuffed
+        let response =
+            shdlc_decode(&[0x00, 0x03, 0x00, 0x02, 0x7d, 0x31, 0x7d, 0x5e, 0x6b]).unwrap();
+        assert_eq!(&response.data[..], &[0x11, 0x7e]);
+    }
+
+    #[test]
+    fn rejects_bad_response() {
+        assert_eq!(
+            shdlc_decode(&[0x00, 0x00, 0x00, 0x00, 0xfe]),
+            Err(PmError::Checksum)
+        );
+        assert_eq!(
+            shdlc_decode(&[0x00, 0x00, 0x43, 0x00, 0xbc]),
+            Err(PmError::Device(0x43))
+        );
+        assert_eq!(
+            shdlc_decode(&[0x00, 0x00, 0x00, 0x02, 0xfd]),
+            Err(PmError::IncorrectResponse)
+        );
+    }
+
+    #[test]
+    fn converts_measurement() {
+        let data = sps30_data(&[10, 12, 13, 14, 50, 60, 70, 75, 80, 600]);
+        assert_eq!(data.pm2_5, 12);
+        assert_eq!(data.pm2_5_atm, 12);
+        assert_eq!(data.pm10_atm, 14);
+        assert_eq!(data.counts(), [8000, 3000, 2000, 1000, 500, 0]);
+    }
+}
@@ -0,0 +1,182 @@
+#!/usr/bin/env python3
+"""Mock ESP-AT WiFi coprocessor, bridging the monitor's MQTT to a broker.
+
+Answers the AT commands the monitor's mqtt task sends, on a serial port
+wired to UART5 in place of the ESP8266/ESP32, and forwards the MQTT
+connection and messages to a real broker, such as a local Mosquitto:
+
+    pip install pyserial paho-mqtt
+    mosquitto -v &
+    mosquitto_sub -v -t 'atmo/#' -t 'homeassistant/#' &
+    tools/mock_esp_at.py /dev/ttyUSB1 --broker localhost
+
+The broker from the monitor's AT+MQTTCONN command is replaced by --broker.
+--fail-join and --fail-publish make the matching commands fail, to check
+the monitor reconnects.
+"""
+
+import argparse
+import sys
+import time
+
+import paho.mqtt.client as mqtt
+import serial
+
+
+def parse_args(text):
+    """split AT command arguments, unquoting and unescaping strings"""
+    args, current, quoted, escaped, was_quoted = [], "", False, False, False
+    for c in text:
+        if escaped:
+            current += c
+            escaped = False
+        elif c == "\\" and quoted:
+            escaped = True
+        elif c == '"':
+            quoted = not quoted
+            was_quoted = True
+        elif c == "," and not quoted:
+            args.append(current if was_quoted else int(current))
+            current, was_quoted = "", False
+        else:
+            current += c
+    if text:
+        args.append(current if was_quoted else int(current))
+    return args
+
+
+class MockEsp:
+    def __init__(self, port, broker, fail_join, fail_publish, verbose):
+        self.serial = serial.Serial(port, 115200, timeout=None)
+        self.broker = broker
+        self.fail_join = fail_join
+        self.fail_publish = fail_publish
+        self.verbose = verbose
+        self.echo = True
+        self.user = None
+        self.will = None
+        self.keep_alive = 120
+        self.client = None
+
+    def send(self, text):
+        if self.verbose:
+            print(f"<- {text!r}")
+        self.serial.write(text.encode())
+
+    def reply(self, *lines):
+        self.send("".join(f"{line}\r\n" for line in lines))
+
+    def read_line(self):
+        line = self.serial.read_until(b"\r\n").decode(errors="replace").strip()
+        if self.verbose:
+            print(f"-> {line!r}")
+        return line
+
+    def run(self):
+        print(f"mock ESP-AT on {self.serial.port}, broker {self.broker}")
+        while True:
+            line = self.read_line()
+            if not line:
+                continue
+            if self.echo:
+                self.send(line + "\r\n")
+            try:
+                self.command(line)
+            except (ValueError, IndexError, TypeError, OSError) as e:
+                print(f"{line}: {e}")
+                self.reply("", "ERROR")
+
+    def command(self, line):
+        name, _, rest = line.partition("=")
+        args = parse_args(rest)
+        if name in ("AT", "AT+CWMODE"):
+            self.reply("", "OK")
+        elif name in ("ATE0", "ATE1"):
+            self.echo = name == "ATE1"
+            self.reply("", "OK")
+        elif name == "AT+RST":
+            self.disconnect()
+            self.reply("", "OK")
+            time.sleep(0.5)
+            self.echo = True
+            self.reply("", "ready")
+        elif name == "AT+CWJAP":
+            if self.fail_join:
+                self.reply("+CWJAP:1", "", "FAIL")
+            else:
+                print(f"joined {args[0]}")
+                self.reply("WIFI CONNECTED", "WIFI GOT IP", "", "OK")
+        elif name == "AT+MQTTUSERCFG":
+            self.user = args
+            self.reply("", "OK")
+        elif name == "AT+MQTTCONNCFG":
+            self.keep_alive = args[1]
+            self.will = args[3:7]
+            self.reply("", "OK")
+        elif name == "AT+MQTTCONN":
+            self.connect(args)
+        elif name == "AT+MQTTPUBRAW":
+            self.publish_raw(args)
+        elif name == "AT+MQTTCLEAN":
+            self.disconnect()
+            self.reply("", "OK")
+        else:
+            print(f"unknown command {line}")
+            self.reply("", "ERROR")
+
+    def connect(self, args):
+        _, client_id, username, password = self.user[:4]
+        if hasattr(mqtt, "CallbackAPIVersion"):
+            client = mqtt.Client(mqtt.CallbackAPIVersion.VERSION2, client_id=client_id)
+        else:
+            client = mqtt.Client(client_id=client_id)
+        if username:
+            client.username_pw_set(username, password)
+        if self.will:
+            topic, payload, qos, retain = self.will
+            client.will_set(topic, payload, qos, bool(retain))
+        client.connect(self.broker, args[2], self.keep_alive)
+        client.loop_start()
+        self.client = client
+        print(f"connected {client_id} to {self.broker}:{args[2]}, asked for {args[1]}")
+        self.reply(f'+MQTTCONNECTED:0,1,"{args[1]}","{args[2]}","",0', "", "OK")
+
+    def publish_raw(self, args):
+        _, topic, length, qos, retain = args
+        if self.client is None:
+            self.reply("", "ERROR")
+            return
+        self.reply("", "OK")
+        self.send(">")
+        payload = self.serial.read(length)
+        if self.fail_publish:
+            self.reply("+MQTTPUB:FAIL")
+            return
+        self.client.publish(topic, payload, qos, bool(retain)).wait_for_publish()
+        print(f"{topic} {payload.decode(errors='replace')}")
+        self.reply("+MQTTPUB:OK")
+
+    def disconnect(self):
+        if self.client is not None:
+            # drop the connection without a disconnect, so the broker
+            # publishes the will
+            self.client.loop_stop()
+            self.client.socket().close()
+            self.client = None
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("port", help="serial port wired to UART5")
+    parser.add_argument("--broker", default="localhost")
+    parser.add_argument("--fail-join", action="store_true")
+    parser.add_argument("--fail-publish", action="store_true")
+    parser.add_argument("-v", "--verbose", action="store_true",
+                        help="show the AT exchange")
+    args = parser.parse_args()
+    MockEsp(args.port, args.broker, args.fail_join, args.fail_publish,
+            args.verbose).run()
+
+
+if __name__ == "__main__":
+    sys.exit(main())
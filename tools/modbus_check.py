@@ -0,0 +1,229 @@
+#!/usr/bin/env python3
+"""Check the monitor's Modbus RTU slave from a host, over an RS-485 adapter.
+
+Reads and decodes the input and holding registers, and checks the slave's
+responses to writes and to bad requests. Only needs pyserial:
+
+    pip install pyserial
+    tools/modbus_check.py /dev/ttyUSB0 --address 1 --baud 9600
+
+Exits with status 1 if a check fails. The register map is documented in
+the Modbus section of README.md.
+"""
+
+import argparse
+import struct
+import sys
+import time
+
+import serial
+
+INPUTS = [
+    # register, name, scale, unit, signed, words
+    (0, "status", None, "", False, 1),
+    (1, "count", 1, "", False, 1),
+    (2, "uptime", 1, "s", False, 2),
+    (4, "temperature", 0.01, "C", True, 1),
+    (5, "humidity", 0.01, "%", False, 1),
+    (6, "pressure", 0.1, "hPa", False, 1),
+    (7, "gas resistance", 1, "ohm", False, 2),
+    (9, "IAQ", 1, "", False, 1),
+    (10, "PM1.0", 0.1, "ug/m3", False, 1),
+    (11, "PM2.5", 0.1, "ug/m3", False, 1),
+    (12, "PM10", 0.1, "ug/m3", False, 1),
+    (13, "PM2.5 raw", 0.1, "ug/m3", False, 1),
+    (14, "AQI", 1, "", False, 1),
+    (15, "PM2.5 NowCast", 0.1, "ug/m3", False, 1),
+    (16, "AQI NowCast", 1, "", False, 1),
+    (17, "PM2.5 24h", 0.1, "ug/m3", False, 1),
+    (18, "CO2", 1, "ppm", False, 1),
+    (19, "battery", 1, "mV", False, 1),
+    (20, "battery charge", 1, "%", False, 1),
+]
+INPUT_REGISTERS = 21
+
+STATUS_FLAGS = ["measured", "env sensor", "pm sensor", "co2 sensor",
+                "battery low", "clock set"]
+
+HOLDING = [
+    "screen_display_min_refresh_sec",
+    "screen_display_max_refresh_sec",
+    "pm25_warmup_sec",
+    "pm25_sample_count",
+    "battery_low_percent",
+    "alerts_enabled",
+    "alert_pm25 x10",
+    "alert_pm10 x10",
+    "alert_iaq",
+    "alert_humidity x10",
+    "alert_temperature x10",
+    "pm25_humidity_correction",
+    "utc offset minutes",
+    "output format",
+    "modbus_address",
+]
+UTC_OFFSET_REGISTER = 12
+
+ILLEGAL_DATA_ADDRESS = 2
+ILLEGAL_DATA_VALUE = 3
+
+
+def crc16(data):
+    crc = 0xFFFF
+    for b in data:
+        crc ^= b
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0xA001 if crc & 1 else crc >> 1
+    return crc
+
+
+class ModbusError(Exception):
+    def __init__(self, code):
+        super().__init__(f"exception {code}")
+        self.code = code
+
+
+class Client:
+    def __init__(self, port, address, baud):
+        self.address = address
+        self.serial = serial.Serial(port, baud, parity=serial.PARITY_EVEN,
+                                    timeout=0.5)
+        # the silent interval between frames
+        self.gap = max(3.5 * 11 / baud, 0.00175)
+
+    def request(self, pdu, expected_len):
+        frame = bytes([self.address]) + pdu
+        frame += struct.pack("<H", crc16(frame))
+        self.serial.reset_input_buffer()
+        self.serial.write(frame)
+        self.serial.flush()
+        header = self.serial.read(2)
+        if len(header) < 2:
+            raise TimeoutError("no response")
+        if header[1] & 0x80:
+            rest = self.serial.read(3)
+            response = header + rest
+        else:
+            response = header + self.serial.read(expected_len - 2 + 2)
+        time.sleep(self.gap)
+        if crc16(response[:-2]) != struct.unpack("<H", response[-2:])[0]:
+            raise ValueError(f"bad crc in {response.hex()}")
+        if response[0] != self.address:
+            raise ValueError(f"response from address {response[0]}")
+        if response[1] & 0x80:
+            raise ModbusError(response[2])
+        return response[2:-2]
+
+    def read(self, function, start, count):
+        data = self.request(struct.pack(">BHH", function, start, count),
+                            3 + 2 * count)
+        if data[0] != 2 * count:
+            raise ValueError(f"byte count {data[0]}")
+        return list(struct.unpack(f">{count}H", data[1:]))
+
+    def read_inputs(self, start, count):
+        return self.read(0x04, start, count)
+
+    def read_holding(self, start, count):
+        return self.read(0x03, start, count)
+
+    def write(self, register, value):
+        pdu = struct.pack(">BHH", 0x06, register, value & 0xFFFF)
+        data = self.request(pdu, 6)
+        if data != pdu[1:]:
+            raise ValueError(f"write echoed {data.hex()}")
+
+
+def decode_inputs(regs):
+    lines = []
+    for register, name, scale, unit, is_signed, words in INPUTS:
+        raw = regs[register]
+        if words == 2:
+            raw = (raw << 16) | regs[register + 1]
+        if scale is None:
+            flags = [f for i, f in enumerate(STATUS_FLAGS) if raw & (1 << i)]
+            lines.append(f"{name:>16}: 0x{raw:04x} {', '.join(flags)}")
+            continue
+        missing = {1: 0x8000 if is_signed else 0xFFFF, 2: 0xFFFFFFFF}[words]
+        if raw == missing and register > 3:
+            lines.append(f"{name:>16}: --")
+            continue
+        if is_signed and raw & 0x8000:
+            raw -= 0x10000
+        value = raw * scale
+        text = f"{value:.2f}".rstrip("0").rstrip(".") if scale < 1 else str(raw)
+        lines.append(f"{name:>16}: {text} {unit}".rstrip())
+    return lines
+
+
+class Checks:
+    def __init__(self):
+        self.failed = 0
+
+    def check(self, name, f):
+        try:
+            f()
+            print(f"PASS {name}")
+        except Exception as e:  # report every failure, not just the first
+            self.failed += 1
+            print(f"FAIL {name}: {e}")
+
+
+def expect_exception(code, f):
+    try:
+        f()
+    except ModbusError as e:
+        if e.code != code:
+            raise ValueError(f"exception {e.code}, expected {code}")
+        return
+    raise ValueError(f"no exception, expected {code}")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("port", help="serial port of the RS-485 adapter")
+    parser.add_argument("--address", type=int, default=1)
+    parser.add_argument("--baud", type=int, default=9600)
+    parser.add_argument("--no-write", action="store_true",
+                        help="skip the checks that write a register")
+    args = parser.parse_args()
+
+    client = Client(args.port, args.address, args.baud)
+    checks = Checks()
+
+    def inputs():
+        for line in decode_inputs(client.read_inputs(0, INPUT_REGISTERS)):
+            print(line)
+
+    def holding():
+        regs = client.read_holding(0, len(HOLDING))
+        for name, value in zip(HOLDING, regs):
+            print(f"{name:>32}: {value}")
+        if regs[14] != args.address:
+            raise ValueError(f"modbus_address {regs[14]}")
+
+    def write_offset():
+        (original,) = client.read_holding(UTC_OFFSET_REGISTER, 1)
+        try:
+            client.write(UTC_OFFSET_REGISTER, -90)
+            (value,) = client.read_holding(UTC_OFFSET_REGISTER, 1)
+            if value != (-90 & 0xFFFF):
+                raise ValueError(f"read back {value}")
+        finally:
+            client.write(UTC_OFFSET_REGISTER, original)
+
+    checks.check("read input registers", inputs)
+    checks.check("read holding registers", holding)
+    checks.check("read past the last input register", lambda: expect_exception(
+        ILLEGAL_DATA_ADDRESS, lambda: client.read_inputs(INPUT_REGISTERS - 1, 2)))
+    checks.check("write a read only register", lambda: expect_exception(
+        ILLEGAL_DATA_ADDRESS, lambda: client.write(0, 60)))
+    checks.check("write an out of range value", lambda: expect_exception(
+        ILLEGAL_DATA_VALUE, lambda: client.write(UTC_OFFSET_REGISTER, 2000)))
+    if not args.no_write:
+        checks.check("write the UTC offset", write_offset)
+    return 1 if checks.failed else 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())